@@ -1,4 +1,5 @@
 use std::any::Any;
+use std::ffi::{CStr, CString};
 use std::ptr;
 use std::rc::Rc;
 
@@ -6,7 +7,7 @@ use super::decoder::Decoder;
 use super::encoder::Encoder;
 use super::{threading, Compliance, Debug, Flags, Id, Parameters};
 use ffi::*;
-use libc::c_int;
+use libc::{c_int, c_void, ENOMEM};
 use media;
 use {Codec, Error};
 
@@ -81,6 +82,37 @@ impl Context {
         }
     }
 
+    /// Hands the statistics gathered by a first pass to an encoder about to run the second.
+    /// Must be set before the encoder is opened.
+    pub fn set_stats_in(&mut self, value: &str) -> Result<(), Error> {
+        let value = CString::new(value).map_err(|_| Error::InvalidData)?;
+
+        unsafe {
+            let stats = av_strdup(value.as_ptr());
+            if stats.is_null() {
+                return Err(Error::from(AVERROR(ENOMEM)));
+            }
+
+            av_freep(&mut (*self.as_mut_ptr()).stats_in as *mut _ as *mut c_void);
+            (*self.as_mut_ptr()).stats_in = stats;
+        }
+
+        Ok(())
+    }
+
+    /// The statistics a first-pass encoder has gathered so far, if it has made any available.
+    /// Some encoders, such as libvpx, only make them available once flushed.
+    pub fn stats_out(&self) -> Option<&str> {
+        unsafe {
+            let stats = (*self.as_ptr()).stats_out;
+            if stats.is_null() {
+                None
+            } else {
+                CStr::from_ptr(stats).to_str().ok()
+            }
+        }
+    }
+
     pub fn id(&self) -> Id {
         unsafe { Id::from((*self.as_ptr()).codec_id) }
     }
@@ -141,6 +173,8 @@ impl Drop for Context {
     fn drop(&mut self) {
         unsafe {
             if self.owner.is_none() {
+                // libavcodec leaves `stats_in` to whoever set it.
+                av_freep(&mut (*self.as_mut_ptr()).stats_in as *mut _ as *mut c_void);
                 avcodec_free_context(&mut self.as_mut_ptr());
             }
         }
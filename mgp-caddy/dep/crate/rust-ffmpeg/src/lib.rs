@@ -9,6 +9,8 @@ pub extern crate ffmpeg_sys_next as sys;
 #[cfg(feature = "image")]
 extern crate image;
 extern crate libc;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 pub use sys as ffi;
 
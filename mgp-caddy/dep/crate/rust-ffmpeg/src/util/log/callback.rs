@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::panic;
+use std::process;
+use std::ptr;
+use std::sync::RwLock;
+
+use super::Level;
+use ffi::*;
+use libc::{c_char, c_int, c_void};
+
+// The type a `va_list` is passed to C functions as. On x86_64 System V it is an array,
+// which decays to a pointer to its first element.
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+type VaList = *mut __va_list_tag;
+#[cfg(not(all(target_arch = "x86_64", not(windows))))]
+type VaList = va_list;
+
+// The size of the buffer messages are formatted into, the same as FFmpeg's default callback.
+const LINE_SIZE: usize = 1024;
+
+type Callback = Box<dyn Fn(&Message) + Send + Sync>;
+
+static CALLBACK: RwLock<Option<Callback>> = RwLock::new(None);
+
+thread_local! {
+    static JOB: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A message logged by FFmpeg, already formatted.
+#[derive(Debug)]
+pub struct Message<'a> {
+    /// The level the message was logged at.
+    pub level: Level,
+    /// The name of the component which logged the message, such as `libvpx-vp9` or `matroska`.
+    pub component: Option<&'a str>,
+    /// The job the logging thread was working on, as set by [`enter_job`].
+    pub job: Option<&'a str>,
+    /// The message, without its trailing newline.
+    pub text: &'a str,
+}
+
+/// Sends everything FFmpeg logs at or above the current log level to `callback`
+/// instead of standard error. Replaces any callback set before.
+pub fn set_callback<F>(callback: F)
+where
+    F: Fn(&Message) + Send + Sync + 'static,
+{
+    *CALLBACK.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(callback));
+    unsafe { av_log_set_callback(Some(trampoline)) }
+}
+
+/// Restores FFmpeg's default logging to standard error.
+pub fn reset_callback() {
+    unsafe { av_log_set_callback(Some(av_log_default_callback)) }
+    *CALLBACK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Marks the current thread as working on the given job until the guard is dropped,
+/// so messages it logs can be tied back to it.
+///
+/// Only messages logged from the current thread carry the job. Those logged from
+/// FFmpeg's own worker threads, such as during frame-threaded decoding, do not.
+pub fn enter_job<S: Into<String>>(job: S) -> JobGuard {
+    let previous = JOB.with(|current| current.replace(Some(job.into())));
+    JobGuard { previous }
+}
+
+/// Restores the job the thread was working on before [`enter_job`] when dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct JobGuard {
+    previous: Option<String>,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        JOB.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Forwards everything FFmpeg logs to `tracing` events with the `ffmpeg` target.
+///
+/// The level of each event follows the level of the message, and the component
+/// and job are recorded as the `component` and `job` fields.
+#[cfg(feature = "tracing")]
+pub fn forward_to_tracing() {
+    use tracing::Level as T;
+
+    macro_rules! forward {
+        ($level:expr, $message:expr) => {
+            ::tracing::event!(
+                target: "ffmpeg",
+                $level,
+                component = $message.component,
+                job = $message.job,
+                "{}",
+                $message.text
+            )
+        };
+    }
+
+    set_callback(|message| match message.level {
+        Level::Quiet => (),
+        Level::Panic | Level::Fatal | Level::Error => forward!(T::ERROR, message),
+        Level::Warning => forward!(T::WARN, message),
+        Level::Info => forward!(T::INFO, message),
+        Level::Verbose | Level::Debug => forward!(T::DEBUG, message),
+        Level::Trace => forward!(T::TRACE, message),
+    });
+}
+
+// Levels FFmpeg logs at are not always one of the named ones,
+// so round them down to the nearest named level as FFmpeg does.
+fn level(value: c_int) -> Level {
+    match value {
+        v if v < AV_LOG_PANIC => Level::Quiet,
+        v if v < AV_LOG_FATAL => Level::Panic,
+        v if v < AV_LOG_ERROR => Level::Fatal,
+        v if v < AV_LOG_WARNING => Level::Error,
+        v if v < AV_LOG_INFO => Level::Warning,
+        v if v < AV_LOG_VERBOSE => Level::Info,
+        v if v < AV_LOG_DEBUG => Level::Verbose,
+        v if v < AV_LOG_TRACE => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+unsafe fn component<'a>(avcl: *mut c_void) -> Option<&'a str> {
+    if avcl.is_null() {
+        return None;
+    }
+
+    let class = *(avcl as *const *const AVClass);
+    if class.is_null() {
+        return None;
+    }
+
+    let name = match (*class).item_name {
+        Some(item_name) => item_name(avcl),
+        None => (*class).class_name,
+    };
+    if name.is_null() {
+        return None;
+    }
+
+    CStr::from_ptr(name).to_str().ok()
+}
+
+unsafe extern "C" fn trampoline(
+    avcl: *mut c_void,
+    level_value: c_int,
+    fmt: *const c_char,
+    args: VaList,
+) {
+    if level_value > av_log_get_level() {
+        return;
+    }
+
+    // The component is left out here and passed separately, so no `[name @ address]`
+    // prefix is written into the line.
+    let mut line = [0 as c_char; LINE_SIZE];
+    let mut print_prefix = 1;
+    let written = av_log_format_line2(
+        ptr::null_mut(),
+        level_value,
+        fmt,
+        args,
+        line.as_mut_ptr(),
+        LINE_SIZE as c_int,
+        &mut print_prefix,
+    );
+    if written < 0 {
+        return;
+    }
+
+    let text = CStr::from_ptr(line.as_ptr()).to_string_lossy();
+    let text = text.trim_end_matches(&['\r', '\n'][..]);
+    if text.is_empty() {
+        return;
+    }
+
+    let component = component(avcl);
+    let result = panic::catch_unwind(|| {
+        let callback = CALLBACK.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(callback) = callback.as_ref() {
+            JOB.with(|job| {
+                callback(&Message {
+                    level: level(level_value),
+                    component,
+                    job: job.borrow().as_deref(),
+                    text,
+                })
+            });
+        }
+    });
+    if result.is_err() {
+        process::abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_level_rounding() {
+        assert_eq!(level(AV_LOG_QUIET), Level::Quiet);
+        assert_eq!(level(AV_LOG_ERROR), Level::Error);
+        assert_eq!(level(AV_LOG_WARNING + 1), Level::Warning);
+        assert_eq!(level(AV_LOG_TRACE + 8), Level::Trace);
+    }
+
+    #[test]
+    fn test_callback_formats_message() {
+        static LOGGED: Mutex<Vec<(Level, Option<String>, String)>> = Mutex::new(Vec::new());
+
+        set_callback(|message| {
+            LOGGED.lock().unwrap().push((
+                message.level,
+                message.job.map(String::from),
+                message.text.to_owned(),
+            ))
+        });
+        {
+            let _job = enter_job("job-1");
+            unsafe {
+                av_log(
+                    ptr::null_mut(),
+                    AV_LOG_ERROR,
+                    b"%s %d\n\0".as_ptr() as *const c_char,
+                    b"answer\0".as_ptr() as *const c_char,
+                    42 as c_int,
+                );
+            }
+        }
+        reset_callback();
+
+        assert_eq!(
+            LOGGED.lock().unwrap().as_slice(),
+            &[(
+                Level::Error,
+                Some("job-1".to_owned()),
+                "answer 42".to_owned()
+            )]
+        );
+    }
+}
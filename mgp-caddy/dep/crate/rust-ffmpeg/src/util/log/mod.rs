@@ -4,6 +4,11 @@ pub use self::level::Level;
 pub mod flag;
 pub use self::flag::Flags;
 
+pub mod callback;
+#[cfg(feature = "tracing")]
+pub use self::callback::forward_to_tracing;
+pub use self::callback::{enter_job, reset_callback, set_callback, JobGuard, Message};
+
 use ffi::*;
 use std::convert::TryInto;
 
@@ -0,0 +1,23 @@
+//! Running tasks in the background, such as the collector and the scrubber.
+
+use std::{future::Future, time::Duration};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+/// Runs a task in the background, once right away and then every `interval` seconds.
+/// A run is never started while the one before is still going.
+pub fn every<F, Fut>(interval: u64, mut task: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut interval = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    // A slow run shouldn't be followed by a burst of catching up.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    tokio::spawn(async move {
+        loop {
+            _ = interval.tick().await;
+            task().await;
+        }
+    })
+}
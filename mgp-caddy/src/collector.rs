@@ -0,0 +1,39 @@
+//! The background collector, which removes deleted files from storage.
+//!
+//! Deleting a file only marks it with a tombstone, so the collector does the
+//! actual removal some time later.
+
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::background;
+use crate::fs::FileStore;
+
+/// Settings for the background collector.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CollectorConfig {
+    /// Seconds between collection runs.
+    pub interval: u64,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        CollectorConfig { interval: 300 }
+    }
+}
+
+/// Starts collecting in the background, once right away and then every interval.
+pub fn spawn(store: Arc<FileStore>, config: &CollectorConfig) -> JoinHandle<()> {
+    background::every(config.interval, move || {
+        let store = store.clone();
+        async move {
+            match store.collect().await {
+                Ok(0) => (),
+                Ok(removed) => tracing::info!(removed, "Collected deleted files"),
+                Err(e) => tracing::error!("Collecting deleted files failed: {e:?}"),
+            }
+        }
+    })
+}
@@ -0,0 +1,70 @@
+//! Runtime configuration for the media caddy.
+//!
+//! The configuration is read from the JSON file named by the `MGP_CADDY_CONFIG`
+//! environment variable. Every field has a default, so the variable may be left
+//! unset and the file only needs to mention the settings it changes.
+
+use eyre::Result;
+use serde::Deserialize;
+
+use crate::collector::CollectorConfig;
+use crate::convert::TranscodeProfile;
+use crate::encryption::EncryptionConfig;
+use crate::profile_image::ProfileImageConfig;
+use crate::quota::QuotaConfig;
+use crate::rebalancer::RebalancerConfig;
+use crate::scrubber::ScrubberConfig;
+use crate::signing::SigningConfig;
+use crate::storage::StorageConfig;
+use crate::storyboard::StoryboardConfig;
+use crate::sweeper::StagingConfig;
+use crate::transcode::RegistryConfig;
+
+/// The environment variable holding the path to the configuration file.
+pub const CONFIG_PATH_VAR: &str = "MGP_CADDY_CONFIG";
+
+/// Top-level configuration for the media caddy.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Encoder settings used when normalizing uploaded media.
+    pub transcode: TranscodeProfile,
+    /// Which transcoders and input formats are in use.
+    pub transcoders: RegistryConfig,
+    /// Storyboards generated for uploaded videos.
+    pub storyboard: StoryboardConfig,
+    /// Cropping and sizes of avatars and cover banners.
+    pub profile_images: ProfileImageConfig,
+    /// Whether the original of each upload is kept alongside its converted file,
+    /// so it can be converted afresh by `mgp-caddy reprocess`.
+    pub retain_originals: bool,
+    /// How often deleted files are removed from storage.
+    pub collector: CollectorConfig,
+    /// Where files are stored.
+    pub storage: StorageConfig,
+    /// How often files are moved between volumes of a sharded store.
+    pub rebalancer: RebalancerConfig,
+    /// How often stored files are checked against their checksums.
+    pub scrubber: ScrubberConfig,
+    /// Whether files are also checked against their checksums every time they are read.
+    pub verify_reads: bool,
+    /// How much each uploader may store.
+    pub quotas: QuotaConfig,
+    /// How long new files stay staged, and how often expired ones are deleted.
+    pub staging: StagingConfig,
+    /// The keys reads are signed with, and whether they must be.
+    pub signing: SigningConfig,
+    /// The master keys stored files are encrypted with.
+    pub encryption: EncryptionConfig,
+}
+
+impl Config {
+    /// Loads the configuration from the file named by [`CONFIG_PATH_VAR`],
+    /// or returns the defaults if it is not set.
+    pub fn load() -> Result<Config> {
+        match std::env::var_os(CONFIG_PATH_VAR) {
+            None => Ok(Config::default()),
+            Some(path) => Ok(serde_json::from_slice(&std::fs::read(path)?)?),
+        }
+    }
+}
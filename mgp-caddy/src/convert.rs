@@ -3,152 +3,643 @@
 //! For optimization purposes, all images are stored in webp format
 //! and all videos are stored in webm format. (The GIF analog is an animated webp, not a webm.)
 
-use eyre::{bail, Result};
-use ffmpeg_next::{ffi::*, format::context::Input, encoder::{Audio, Video}};
+use crate::profile_image::Purpose;
+use crate::quality::{self, Metric, QualityTuning, Tuning, PSNR_IDENTICAL};
+use eyre::{bail, ensure, eyre, Result};
+use ffmpeg_next::{
+    codec, decoder, encoder,
+    ffi::*,
+    filter, format,
+    format::context::{Input, Output},
+    frame, media, packet, picture, ChannelLayout, Dictionary, DictionaryRef, Packet, Rational,
+    Rescale, Stream,
+};
 use file_format::FileFormat;
-use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::{
+    codecs::webp::{WebPEncoder, WebPQuality},
+    ImageFormat, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    ffi::c_void,
-    io::{BufRead, Read, Seek, Write},
-    ops::{Deref, DerefMut},
+    ffi::{c_void, CString},
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut, RangeInclusive},
     ptr,
-    sync::atomic::AtomicUsize,
 };
 
-/// Returns a FileFormat is the given data's format is an accepted media type,
-/// and an error otherwise.
-///
-/// If the data format was successfully determined but it
-/// was not of an acceptable type, then the returned error will be what format it was
-/// determined to be.
-///
-/// The following formats are supported:
-///
-/// **Images**
-///
-/// - JPEG
-/// - PNG
-/// - GIF
-/// - SVG
-/// - WEBP
-///
-/// **Videos**
-///
-/// - WEBM
-/// - MKV
-/// - MP4
-/// - MOV
+/// Opus only supports a handful of sample rates, and 48kHz is the one every player expects.
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+/// `whence` flags for AVIO seek callbacks, from `libavformat/avio.h`.
+const AVIO_SEEK_SIZE: i32 = 0x10000;
+const AVIO_SEEK_FORCE: i32 = 0x20000;
+
+/// The WebP qualities automatic quality tuning chooses between.
+const WEBP_QUALITY_RANGE: RangeInclusive<u8> = 30..=95;
+/// The VP9 CRFs automatic quality tuning chooses between.
+const VP9_CRF_RANGE: RangeInclusive<u8> = 15..=50;
+/// The highest, and lowest quality, CRF VP9 accepts.
+const VP9_MAX_CRF: u8 = 63;
+
+/// Encoder settings used when normalizing uploaded media.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TranscodeProfile {
+    /// Quality of WebP images, from 0 to 100.
+    pub image_quality: u8,
+    /// Constant quality factor for VP9 video, from 0 (lossless) to 63.
+    pub video_crf: u8,
+    /// Target bit rate for Opus audio, in bits per second.
+    pub audio_bit_rate: usize,
+    /// Loudness normalization applied to audio tracks.
+    pub loudness: LoudnessTarget,
+    /// Automatic tuning of the image quality and video CRF, in place of the fixed settings above.
+    pub tuning: QualityTuning,
+    /// Whether video is encoded in two passes, the first only gathering statistics which let
+    /// the second spend bits where they are needed most. Slower, but better quality per byte.
+    pub two_pass: bool,
+}
+
+impl Default for TranscodeProfile {
+    fn default() -> Self {
+        TranscodeProfile {
+            image_quality: 80,
+            video_crf: 31,
+            audio_bit_rate: 96_000,
+            loudness: LoudnessTarget::default(),
+            tuning: QualityTuning::default(),
+            two_pass: false,
+        }
+    }
+}
+
+/// EBU R128 loudness normalization settings, passed through to FFMPEG's `loudnorm` filter.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoudnessTarget {
+    /// Whether audio tracks are measured and normalized at all.
+    pub enabled: bool,
+    /// Target integrated loudness, in LUFS.
+    pub integrated: f64,
+    /// Maximum true peak, in dBTP.
+    pub true_peak: f64,
+    /// Target loudness range, in LU.
+    pub range: f64,
+}
+
+impl Default for LoudnessTarget {
+    fn default() -> Self {
+        LoudnessTarget {
+            enabled: true,
+            integrated: -16.0,
+            true_peak: -1.5,
+            range: 11.0,
+        }
+    }
+}
+
+/// Facts learned about an input while it was being transcoded.
+#[derive(Debug, Clone, Default)]
+pub struct TranscodeReport {
+    /// The loudness of the input's audio track before normalization, if it had one
+    /// and loudness normalization is enabled.
+    pub loudness: Option<Loudness>,
+    /// What the transcoded output turned out to be.
+    pub media: MediaInfo,
+}
+
+/// Facts about a converted media file, gathered while it was produced
+/// so they never have to be probed from the stored file again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    /// The MIME type of the converted file.
+    pub kind: String,
+    /// The MIME type of the file it was converted from.
+    pub source_format: String,
+    /// Width in pixels, if the file has a picture.
+    pub width: Option<u32>,
+    /// Height in pixels, if the file has a picture.
+    pub height: Option<u32>,
+    /// Length in seconds, for video and audio.
+    pub duration: Option<f64>,
+    /// Frames per second, for video.
+    pub frame_rate: Option<f64>,
+    /// Number of pictures in the file.
+    pub frame_count: Option<u64>,
+    /// Whether the file is a moving picture rather than a still.
+    pub animated: bool,
+    /// Whether the file is a silent video converted from an animated image,
+    /// which clients should autoplay and loop like a GIF.
+    pub gifv: bool,
+    /// Whether a storyboard of thumbnails was generated for the video.
+    pub storyboard: bool,
+    /// The quality setting chosen by automatic quality tuning, if it was used.
+    pub tuning: Option<Tuning>,
+    /// What the image was uploaded as, if it is an avatar or cover banner.
+    pub purpose: Option<Purpose>,
+    /// Widths in pixels the avatar or cover banner is stored at, widest first.
+    #[serde(default)]
+    pub renditions: Vec<u32>,
+    /// Name of the video or image codec, such as `vp9` or `webp`.
+    pub video_codec: Option<String>,
+    /// Name of the audio codec, such as `opus`.
+    pub audio_codec: Option<String>,
+    /// Average bit rate of the whole file, in bits per second.
+    pub bit_rate: Option<u64>,
+    /// Number of audio channels.
+    pub audio_channels: Option<u16>,
+    /// Audio sample rate, in Hz.
+    pub sample_rate: Option<u32>,
+    /// The loudness of the audio track before it was normalized, if it was measured.
+    #[serde(default)]
+    pub loudness: Option<Loudness>,
+}
+
+/// EBU R128 loudness statistics of an entire audio track.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS.
+    pub integrated: f64,
+    /// Loudness range, in LU.
+    pub range: f64,
+    /// Maximum true peak over all channels, in dBTP.
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// The integrated loudness `ebur128` reports for a track with no audible content.
+    const SILENCE: f64 = -70.0;
+
+    /// Reads the running totals that `ebur128=metadata=1` attaches to every frame it outputs.
+    fn update(&mut self, metadata: DictionaryRef) {
+        for (key, value) in metadata.iter() {
+            let value = match value.parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            match key {
+                "lavfi.r128.I" => self.integrated = value,
+                "lavfi.r128.LRA" => self.range = value,
+                // Peaks are reported as linear sample values, and as 0 for digital silence.
+                k if k.starts_with("lavfi.r128.true_peaks_ch") => {
+                    self.true_peak = self.true_peak.max(20.0 * value.log10())
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.integrated <= Self::SILENCE
+    }
+
+    /// The `loudnorm` filter spec which brings a track with these statistics to the given target.
+    ///
+    /// This is the second pass of a two-pass normalization: the statistics measured in the
+    /// first are handed to `loudnorm`, so it can apply a single linear gain to the whole
+    /// track rather than guessing as it goes. It only falls back to adjusting the gain
+    /// dynamically when a linear gain would push the true peak over the target.
+    fn filter_spec(&self, target: &LoudnessTarget) -> String {
+        if self.is_silent() {
+            // There's nothing to normalize, and trying to will only amplify the noise floor.
+            return "anull".to_owned();
+        }
+
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={:.2}:measured_TP={:.2}:measured_LRA={:.2}:measured_thresh={:.2}:linear=true",
+            target.integrated,
+            target.true_peak,
+            target.range,
+            self.integrated,
+            self.true_peak,
+            self.range,
+            self.threshold(),
+        )
+    }
+
+    /// The relative gating threshold of the integrated loudness, in LUFS. `ebur128` doesn't
+    /// report it, so it is estimated as 10 LU below the integrated loudness, where the gate sits.
+    fn threshold(&self) -> f64 {
+        self.integrated - 10.0
+    }
+
+    /// Container tags recording these statistics, for analytics.
+    fn tags<'a>(&self, target: &LoudnessTarget) -> Dictionary<'a> {
+        let mut tags = Dictionary::new();
+        tags.set("LOUDNESS_INTEGRATED", &format!("{:.2}", self.integrated));
+        tags.set("LOUDNESS_RANGE", &format!("{:.2}", self.range));
+        tags.set("LOUDNESS_TRUE_PEAK", &format!("{:.2}", self.true_peak));
+        tags.set("LOUDNESS_TARGET", &format!("{:.2}", target.integrated));
+        tags
+    }
+}
+
+impl Default for Loudness {
+    fn default() -> Self {
+        // Peaks of digital silence are floored to the same level as its loudness,
+        // so they are always finite, as `loudnorm` and JSON both need them to be.
+        Loudness {
+            integrated: Self::SILENCE,
+            range: 0.0,
+            true_peak: Self::SILENCE,
+        }
+    }
+}
+
+/// Converts an image to WebP. Animated images are reduced to their first frame.
 ///
-fn check_format<R: BufRead + Seek>(mut data: &mut R) -> Result<FileFormat> {
-    use FileFormat::*;
-    let t = FileFormat::from_reader(&mut data)?;
-    _ = data.seek(std::io::SeekFrom::Start(0))?;
-    match t {
-        // Static Images and Animated Images
-        JointPhotographicExpertsGroup |
-        PortableNetworkGraphics |
-        GraphicsInterchangeFormat |
-        Webp |
-        // Videos
-        Webm |
-        MatroskaVideo |
-        Mpeg4Part14Video |
-        AppleQuicktime => Ok(t),
-        _ => bail!(t),
-    }
-}
-
-fn convert_to_webp<R: BufRead + Seek, W: Write>(mut data: &mut R, out: &mut W) -> Result<()> {
-    use image::ImageFormat;
-    use FileFormat::*;
-    let t = check_format(&mut data)?;
-
-    let image = match t {
-        // Static Images and Animated Images
-        JointPhotographicExpertsGroup => image::load(data, ImageFormat::Jpeg),
-        PortableNetworkGraphics => image::load(data, ImageFormat::Png),
-        GraphicsInterchangeFormat => image::load(data, ImageFormat::Gif),
-        Webp => image::load(data, ImageFormat::WebP),
-        _ => bail!(t),
-    }?;
-
-    let encoder = WebPEncoder::new_with_quality(out, WebPQuality::lossy(80));
-    encoder.encode(
-        image.as_bytes(),
+/// If quality tuning is enabled, the image is encoded several times, and each encoding
+/// is decoded again to score it against the source.
+pub fn convert_to_webp<R: BufRead + Seek, W: Write>(
+    data: &mut R,
+    out: &mut W,
+    profile: &TranscodeProfile,
+) -> Result<MediaInfo> {
+    let image = image::io::Reader::new(data)
+        .with_guessed_format()?
+        .decode()?
+        .to_rgba8();
+
+    let (encoded, tuning) = match profile.tuning.enabled {
+        false => (encode_webp(&image, profile.image_quality)?, None),
+        true => {
+            let metric = profile.tuning.metric;
+            let (encoded, quality, score) =
+                quality::search(WEBP_QUALITY_RANGE, &profile.tuning, |quality| {
+                    let encoded = encode_webp(&image, quality)?;
+                    let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::WebP)?
+                        .to_rgba8();
+                    Ok((encoded, quality::score_images(metric, &image, &decoded)))
+                })?;
+            (
+                encoded,
+                Some(Tuning {
+                    metric,
+                    quality,
+                    score,
+                }),
+            )
+        }
+    };
+    out.write_all(&encoded)?;
+
+    Ok(MediaInfo {
+        kind: FileFormat::Webp.media_type().to_owned(),
+        width: Some(image.width()),
+        height: Some(image.height()),
+        frame_count: Some(1),
+        video_codec: Some("webp".to_owned()),
+        tuning,
+        ..Default::default()
+    })
+}
+
+/// Encodes an image as a lossy WebP at the given quality, from 0 to 100.
+pub(crate) fn encode_webp(image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    WebPEncoder::new_with_quality(&mut encoded, WebPQuality::lossy(quality)).encode(
+        image.as_raw(),
         image.width(),
         image.height(),
         image::ColorType::Rgba8,
     )?;
+    Ok(encoded)
+}
+
+/// Converts a given WebM, MKV, MP4, or MOV into a WebM using VP9 video codec and Opus audio codec.
+///
+/// Before transcoding, the input is briefly read to find out how its video should be
+/// rotated. If loudness normalization is enabled, it is also read once in full to measure
+/// the loudness of its audio track.
+///
+/// If two-pass encoding is enabled and the input has video, it is read once more in full
+/// for the first pass. If quality tuning is enabled, it is transcoded several times,
+/// and each transcode is decoded again to score it against the input.
+pub fn convert_to_webm<R: Read + Seek, W: Write + Seek>(
+    source: &mut R,
+    out: &mut W,
+    profile: &TranscodeProfile,
+) -> Result<TranscodeReport> {
+    let loudness = match profile.loudness.enabled {
+        true => measure_loudness(source)?,
+        false => None,
+    };
+    _ = source.seek(SeekFrom::Start(0))?;
+    let display_matrix = probe_display_matrix(source)?;
+    _ = source.seek(SeekFrom::Start(0))?;
+
+    // Frames are turned upright here, and the output stream gets no display matrix
+    // of its own, so players won't rotate them a second time.
+    let video_filter_spec = match &display_matrix {
+        Some(matrix) => matrix.filter_spec(),
+        None => "null".to_owned(),
+    };
+
+    let has_video = has_video(source)?;
+    _ = source.seek(SeekFrom::Start(0))?;
+
+    // First-pass statistics describe the input rather than any one encoding of it,
+    // so a single first pass serves every attempt made while tuning.
+    let stats = match profile.two_pass && has_video {
+        true => {
+            let stats = first_pass(source, profile, &video_filter_spec)?;
+            _ = source.seek(SeekFrom::Start(0))?;
+            Some(stats)
+        }
+        false => None,
+    };
+    let stats = stats.as_deref();
+
+    if !profile.tuning.enabled || !has_video {
+        return transcode_webm(source, out, profile, loudness, &video_filter_spec, stats);
+    }
+
+    let metric = profile.tuning.metric;
+    let levels = (VP9_MAX_CRF - VP9_CRF_RANGE.end())..=(VP9_MAX_CRF - VP9_CRF_RANGE.start());
+    let ((encoded, mut report), level, score) =
+        quality::search(levels, &profile.tuning, |level| {
+            let profile = TranscodeProfile {
+                video_crf: VP9_MAX_CRF - level,
+                ..profile.clone()
+            };
+
+            _ = source.seek(SeekFrom::Start(0))?;
+            let mut encoded = Cursor::new(Vec::new());
+            let report = transcode_webm(
+                source,
+                &mut encoded,
+                &profile,
+                loudness,
+                &video_filter_spec,
+                stats,
+            )?;
+
+            _ = source.seek(SeekFrom::Start(0))?;
+            encoded.set_position(0);
+            let score = measure_video_quality(source, &mut encoded, &video_filter_spec, metric)?;
+
+            Ok(((encoded.into_inner(), report), score))
+        })?;
+
+    out.write_all(&encoded)?;
+    report.media.tuning = Some(Tuning {
+        metric,
+        quality: VP9_MAX_CRF - level,
+        score,
+    });
 
-    Ok(())
+    Ok(report)
 }
 
-#[allow(unused)]
-/// Converts a given WebM, MKV, MP4, or MOV into a WebM using VP9 video codec and Opus audio codec
-pub fn convert_to_webm<R: Read>(mut source: std::io::BufReader<R>) -> Result<()> {
-    let input = StreamingInput::new(source)?;
+/// Transcodes the input to WebM, with the loudness of its audio already measured and its
+/// video turned upright by the given filter. The video is encoded in a single pass,
+/// or as the second of two passes if given the statistics gathered by [`first_pass`].
+fn transcode_webm<R: Read + Seek, W: Write + Seek>(
+    source: &mut R,
+    out: &mut W,
+    profile: &TranscodeProfile,
+    loudness: Option<Loudness>,
+    video_filter_spec: &str,
+    stats: Option<&str>,
+) -> Result<TranscodeReport> {
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+    let mut output = StreamingOutput::new(&mut *out, "webm")?;
 
-    println!(
-        "Format: {}, Duration {} us",
-        input.format().name(),
-        input.duration()
+    let pass = match stats {
+        Some(stats) => Pass::Second(stats),
+        None => Pass::Only,
+    };
+    let mut video = match input.streams().best(media::Type::Video) {
+        Some(stream) => Some(VideoTranscoder::new(
+            &stream,
+            &mut output,
+            profile,
+            video_filter_spec,
+            pass,
+        )?),
+        None => None,
+    };
+
+    let audio_filter_spec = match &loudness {
+        Some(loudness) => loudness.filter_spec(&profile.loudness),
+        None => "anull".to_owned(),
+    };
+    let mut audio = match input.streams().best(media::Type::Audio) {
+        Some(stream) => Some(AudioTranscoder::new(
+            &stream,
+            &mut output,
+            profile,
+            &audio_filter_spec,
+        )?),
+        None => None,
+    };
+
+    ensure!(
+        video.is_some() || audio.is_some(),
+        "Input contains neither a video nor an audio stream"
     );
 
-    Ok(())
+    if let (Some(audio), Some(loudness)) = (&audio, &loudness) {
+        if let Some(mut stream) = output.stream_mut(audio.ost_index) {
+            stream.set_metadata(loudness.tags(&profile.loudness));
+        }
+    }
+
+    output.write_header()?;
+
+    // The muxer is free to pick its own stream time bases when writing the header.
+    if let Some(video) = video.as_mut() {
+        video.ost_time_base = output_time_base(&output, video.ost_index)?;
+    }
+    if let Some(audio) = audio.as_mut() {
+        audio.ost_time_base = output_time_base(&output, audio.ost_index)?;
+    }
+
+    for (stream, packet) in input.packets() {
+        let index = stream.index();
+        if let Some(video) = video.as_mut().filter(|v| v.ist_index == index) {
+            video.send_packet(&packet, &mut output)?;
+        } else if let Some(audio) = audio.as_mut().filter(|a| a.ist_index == index) {
+            audio.send_packet(&packet, &mut output)?;
+        }
+    }
+
+    if let Some(video) = video.as_mut() {
+        video.finish(&mut output)?;
+    }
+    if let Some(audio) = audio.as_mut() {
+        audio.finish(&mut output)?;
+    }
+
+    output.write_trailer()?;
+    drop(output);
+
+    let mut media = MediaInfo {
+        kind: FileFormat::Webm.media_type().to_owned(),
+        loudness,
+        ..Default::default()
+    };
+    if let Some(video) = &video {
+        video.describe(&mut media);
+    }
+    if let Some(audio) = &audio {
+        audio.describe(&mut media);
+    }
+
+    let size = out.seek(SeekFrom::End(0))?;
+    if let Some(duration) = media.duration.filter(|d| *d > 0.0) {
+        media.bit_rate = Some((size as f64 * 8.0 / duration).round() as u64);
+    }
+
+    Ok(TranscodeReport { loudness, media })
 }
 
-struct StreamingInput<R> {
+/// Runs the first pass of a two-pass VP9 encode over the best video stream of the input,
+/// returning the statistics it gathered for the second pass.
+fn first_pass<R: Read + Seek>(
+    source: &mut R,
+    profile: &TranscodeProfile,
+    video_filter_spec: &str,
+) -> Result<String> {
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+    // The first pass produces no pictures, but the encoder still needs a stream to belong to.
+    let mut output = StreamingOutput::new(Cursor::new(Vec::new()), "webm")?;
+
+    let mut video = match input.streams().best(media::Type::Video) {
+        Some(stream) => VideoTranscoder::new(
+            &stream,
+            &mut output,
+            profile,
+            video_filter_spec,
+            Pass::First,
+        )?,
+        None => bail!("Input contains no video stream"),
+    };
+
+    output.write_header()?;
+    video.ost_time_base = output_time_base(&output, video.ost_index)?;
+
+    for (stream, packet) in input.packets() {
+        if stream.index() == video.ist_index {
+            video.send_packet(&packet, &mut output)?;
+        }
+    }
+    video.finish(&mut output)?;
+    output.write_trailer()?;
+
+    // libvpx only hands over its statistics once it has been flushed.
+    match video.encoder.stats_out() {
+        Some(stats) if !stats.is_empty() => Ok(stats.to_owned()),
+        _ => bail!("VP9 first pass gathered no statistics"),
+    }
+}
+
+/// Runs the best audio stream of the input through FFMPEG's `ebur128` filter and returns
+/// the loudness of the whole track, or `None` if the input has no audio.
+fn measure_loudness<R: Read + Seek>(source: &mut R) -> Result<Option<Loudness>> {
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+
+    let (index, time_base, mut decoder) = match input.streams().best(media::Type::Audio) {
+        Some(stream) => (
+            stream.index(),
+            stream.time_base(),
+            codec::context::Context::from_parameters(stream.parameters())?
+                .decoder()
+                .audio()?,
+        ),
+        None => return Ok(None),
+    };
+
+    let mut filter = audio_filter(&decoder, time_base, "ebur128=metadata=1:peak=true", None)?;
+
+    let mut loudness = Loudness::default();
+    let mut decoded = frame::Audio::empty();
+    let mut measured = frame::Audio::empty();
+
+    let mut eof = false;
+    let mut packets = input.packets();
+    while !eof {
+        match packets.next() {
+            Some((stream, packet)) if stream.index() == index => decoder.send_packet(&packet)?,
+            Some(_) => continue,
+            None => {
+                decoder.send_eof()?;
+                eof = true;
+            }
+        }
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            filter.get("in").unwrap().source().add(&decoded)?;
+        }
+
+        if eof {
+            filter.get("in").unwrap().source().flush()?;
+        }
+
+        while filter
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut measured)
+            .is_ok()
+        {
+            loudness.update(measured.metadata());
+        }
+    }
+
+    Ok(Some(loudness))
+}
+
+/// An FFMPEG input context which demuxes from an arbitrary seekable reader
+/// instead of a file.
+pub(crate) struct StreamingInput<R> {
     istream: *mut std::io::BufReader<R>,
 
     avio_ptr: *mut AVIOContext,
     format_ptr: *mut AVFormatContext,
 
-    inner: Input,
+    inner: ManuallyDrop<Input>,
 }
 
 impl<R> Drop for StreamingInput<R> {
     fn drop(&mut self) {
-        // the `self.inner` field (type `Input`) comes from `ffmpeg-next`,
-        // and isn't part of the `-sys` bindings. Therefore it comes with
-        // its own destructor; the library frees the memory properly for us.
-        // Therefore we don't need a custom `drop` function since all the
-        // resources we allocated will be properly freed, even our custom buffer.
-        //
-        // This drop implementation will be kept for reference, in case a
-        // future change means we will need to transition to dropping things
-        // ourselves again.
-        /*
+        // The `self.inner` field (type `Input`) comes from `ffmpeg-next`,
+        // and its destructor closes and frees the format context for us.
+        // However, FFMPEG never frees an IO context it did not open itself,
+        // so once the format context is gone we must free our AVIO context,
+        // its buffer, and the boxed reader ourselves.
         unsafe {
-            // Free the input stream by "returning it"
-            // to rust's borrow checker system
-            _ = Box::from_raw(self.istream);
-            println!("Freed IStream");
-
-            // Free the AVIO Context input buffer
-            av_free((*self.avio_ptr).buffer as *mut _);
-            println!("Freed Context Buffer");
+            ManuallyDrop::drop(&mut self.inner);
+            Self::free_io(self.avio_ptr, self.istream);
+        }
+    }
+}
 
-            // Free the AV contexts themselves
-            avio_context_free(&mut self.avio_ptr);
-            println!("Freed AVIO Context");
+impl<R> StreamingInput<R> {
+    /// Frees our AVIO context, its buffer, and the boxed reader,
+    /// once no format context uses them any more.
+    unsafe fn free_io(mut avio: *mut AVIOContext, istream: *mut std::io::BufReader<R>) {
+        // AVIO may have reallocated the buffer we originally gave it.
+        av_freep(ptr::addr_of_mut!((*avio).buffer).cast());
+        avio_context_free(&mut avio);
 
-            avformat_free_context(self.format_ptr);
-            println!("Freed AVFormat Context");
-        }
-        */
+        _ = Box::from_raw(istream);
     }
 }
 
-impl<R: Read> StreamingInput<R> {
+impl<R: Read + Seek> StreamingInput<R> {
     pub fn new(source: std::io::BufReader<R>) -> Result<Self> {
         const BUF_SIZE: usize = 8192;
         unsafe {
             // In order to stream from memory instead of a file we must
-            // construct our own AVIOContext with a custom reader function.
+            // construct our own AVIOContext with custom reader and seek functions.
             let bufptr = av_malloc(BUF_SIZE) as *mut u8;
 
             let datptr = Box::into_raw(Box::new(source));
-            // println!("DatPtr: {datptr:?}");
             let avio = avio_alloc_context(
                 bufptr,
                 BUF_SIZE as i32,
@@ -156,26 +647,25 @@ impl<R: Read> StreamingInput<R> {
                 datptr as *mut c_void,
                 Some(Self::read_function),
                 None,
-                None,
+                Some(Self::seek_function),
             );
 
             // Construct an AVFormatContext and then replace its pb field with out AVIOContext.
-            let format = avformat_alloc_context();
+            let mut format = avformat_alloc_context();
             (*format).pb = avio;
             (*format).flags |= AVFMT_FLAG_CUSTOM_IO;
 
-            let rv = avformat_open_input(
-                Box::into_raw(Box::new(format)),
-                ptr::null(),
-                ptr::null(),
-                ptr::null_mut(),
-            );
+            // On failure this frees the format context, but leaves our IO context alone.
+            let rv = avformat_open_input(&mut format, ptr::null(), ptr::null(), ptr::null_mut());
             if rv < 0 {
+                Self::free_io(avio, datptr);
                 bail!("FFMPEG Error in avformat_open_input: AVERROR(0x{rv:X})");
             }
 
             let rv = avformat_find_stream_info(format, ptr::null_mut());
             if rv < 0 {
+                avformat_close_input(&mut format);
+                Self::free_io(avio, datptr);
                 bail!("FFMPEG Error in avformat_find_stream_info: AVERROR(0x{rv:X})");
             }
 
@@ -183,36 +673,27 @@ impl<R: Read> StreamingInput<R> {
                 istream: datptr,
                 avio_ptr: avio,
                 format_ptr: format,
-                inner: Input::wrap(format),
+                inner: ManuallyDrop::new(Input::wrap(format)),
             })
         }
     }
 
     unsafe extern "C" fn read_function(opaque: *mut c_void, buf: *mut u8, buf_size: i32) -> i32 {
-        // println!("Converting Data (0x{opaque:?})");
         let data = &mut *(opaque as *mut std::io::BufReader<R>);
-        // println!("Converting buf (0x{buf:?}, size {buf_size})");
         let buf = std::slice::from_raw_parts_mut(buf, buf_size as usize);
 
-        static BYTES_READ: AtomicUsize = AtomicUsize::new(0);
-
-        // println!("Reading");
+        // Never panic here, since unwinding out of a callback from C aborts the process.
         match data.read(buf) {
-            Err(e) => todo!("{e}"),
-            Ok(0) => {
-                //println!(
-                //    "Sending AVERROR_EOF (Read {} bytes)",
-                //    BYTES_READ.load(std::sync::atomic::Ordering::Acquire)
-                //);
-                0
-            }
-            Ok(n) => {
-                _ = BYTES_READ.fetch_add(n, std::sync::atomic::Ordering::Release);
-                //println!("Read {n} bytes ({} total)", BYTES_READ.load(std::sync::atomic::Ordering::Acquire));
-                n as i32
-            }
+            Ok(0) => AVERROR_EOF,
+            Ok(n) => n as i32,
+            Err(e) => AVERROR(e.raw_os_error().unwrap_or(ffmpeg_next::util::error::EIO)),
         }
     }
+
+    unsafe extern "C" fn seek_function(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
+        let data = &mut *(opaque as *mut std::io::BufReader<R>);
+        avio_seek_with(data, offset, whence)
+    }
 }
 
 impl<R> Deref for StreamingInput<R> {
@@ -228,60 +709,973 @@ impl<R> DerefMut for StreamingInput<R> {
     }
 }
 
-struct AVTranscoder<R> {
-    istream: StreamingInput<R>,
-    audio_encoder: Audio,
-    video_encoder: Video,
+/// An FFMPEG output context which muxes into an arbitrary seekable writer
+/// instead of a file.
+struct StreamingOutput<W> {
+    ostream: *mut W,
+
+    avio_ptr: *mut AVIOContext,
+
+    inner: ManuallyDrop<Output>,
+}
+
+impl<W> Drop for StreamingOutput<W> {
+    fn drop(&mut self) {
+        // Unlike the input side, the `Output` destructor calls `avio_close`
+        // on whatever IO context is attached, which would try to close our
+        // writer as if it were one of FFMPEG's own protocol handles.
+        // Detach our AVIO context first, then free everything ourselves.
+        unsafe {
+            (*self.inner.as_mut_ptr()).pb = ptr::null_mut();
+            ManuallyDrop::drop(&mut self.inner);
+
+            av_freep(ptr::addr_of_mut!((*self.avio_ptr).buffer).cast());
+            avio_context_free(&mut self.avio_ptr);
+
+            _ = Box::from_raw(self.ostream);
+        }
+    }
+}
+
+impl<W: Write + Seek> StreamingOutput<W> {
+    /// Creates an output context for the muxer with the given short name, e.g. `webm`.
+    pub fn new(sink: W, format_name: &str) -> Result<Self> {
+        const BUF_SIZE: usize = 8192;
+        let format_name = CString::new(format_name)?;
+        unsafe {
+            let mut format: *mut AVFormatContext = ptr::null_mut();
+            let rv = avformat_alloc_output_context2(
+                &mut format,
+                ptr::null(),
+                format_name.as_ptr(),
+                ptr::null(),
+            );
+            if rv < 0 {
+                bail!("FFMPEG Error in avformat_alloc_output_context2: AVERROR(0x{rv:X})");
+            }
+
+            let bufptr = av_malloc(BUF_SIZE) as *mut u8;
+            let datptr = Box::into_raw(Box::new(sink));
+            let avio = avio_alloc_context(
+                bufptr,
+                BUF_SIZE as i32,
+                1,
+                datptr as *mut c_void,
+                None,
+                Some(Self::write_function),
+                Some(Self::seek_function),
+            );
+
+            (*format).pb = avio;
+            (*format).flags |= AVFMT_FLAG_CUSTOM_IO;
+
+            Ok(StreamingOutput {
+                ostream: datptr,
+                avio_ptr: avio,
+                inner: ManuallyDrop::new(Output::wrap(format)),
+            })
+        }
+    }
+
+    unsafe extern "C" fn write_function(opaque: *mut c_void, buf: *mut u8, buf_size: i32) -> i32 {
+        let data = &mut *(opaque as *mut W);
+        let buf = std::slice::from_raw_parts(buf, buf_size as usize);
+
+        match data.write_all(buf) {
+            Ok(()) => buf_size,
+            Err(_) => AVERROR(ffmpeg_next::util::error::EIO),
+        }
+    }
+
+    unsafe extern "C" fn seek_function(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
+        let data = &mut *(opaque as *mut W);
+        avio_seek_with(data, offset, whence)
+    }
+}
+
+impl<W> Deref for StreamingOutput<W> {
+    type Target = Output;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<W> DerefMut for StreamingOutput<W> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// Implements the semantics of an AVIO seek callback on top of [`Seek`].
+fn avio_seek_with<S: Seek>(stream: &mut S, offset: i64, whence: i32) -> i64 {
+    let result = match whence & !AVIO_SEEK_FORCE {
+        // FFMPEG wants the total stream size, without moving the cursor.
+        AVIO_SEEK_SIZE => stream.stream_position().and_then(|pos| {
+            let len = stream.seek(SeekFrom::End(0))?;
+            _ = stream.seek(SeekFrom::Start(pos))?;
+            Ok(len)
+        }),
+        0 => stream.seek(SeekFrom::Start(offset as u64)),
+        1 => stream.seek(SeekFrom::Current(offset)),
+        2 => stream.seek(SeekFrom::End(offset)),
+        _ => return i64::from(AVERROR(ffmpeg_next::util::error::EINVAL)),
+    };
+
+    match result {
+        Ok(pos) => pos as i64,
+        Err(_) => i64::from(AVERROR(ffmpeg_next::util::error::EIO)),
+    }
+}
+
+/// Finds the display matrix of the input's best video stream. Most containers store it
+/// in the stream's side data, but some only attach it to the stream's first packet.
+fn probe_display_matrix<R: Read + Seek>(source: &mut R) -> Result<Option<DisplayMatrix>> {
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+
+    let index = match input.streams().best(media::Type::Video) {
+        Some(stream) => match DisplayMatrix::find(stream.side_data()) {
+            Some(matrix) => return Ok(Some(matrix)),
+            None => stream.index(),
+        },
+        None => return Ok(None),
+    };
+
+    for (stream, packet) in input.packets() {
+        if stream.index() == index {
+            return Ok(DisplayMatrix::find(packet.side_data()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Whether the input has a video stream at all.
+fn has_video<R: Read + Seek>(source: &mut R) -> Result<bool> {
+    let input = StreamingInput::new(BufReader::new(source))?;
+    let has_video = input.streams().best(media::Type::Video).is_some();
+    Ok(has_video)
+}
+
+/// Scores how closely the video in `encoded` resembles the video in `source`, averaged over
+/// every frame, with FFMPEG's `ssim` or `psnr` filter. The source's frames are first run through
+/// `source_filter_spec` and scaled, so that they line up with the encoding's.
+fn measure_video_quality<R: Read + Seek, E: Read + Seek>(
+    source: &mut R,
+    encoded: &mut E,
+    source_filter_spec: &str,
+    metric: Metric,
+) -> Result<f64> {
+    let mut reference = FrameReader::new(source)?;
+    let mut main = FrameReader::new(encoded)?;
+
+    let (filter_name, key) = match metric {
+        Metric::Ssim => ("ssim", "lavfi.ssim.All"),
+        Metric::Psnr => ("psnr", "lavfi.psnr.psnr_avg"),
+    };
+
+    let mut graph = filter::Graph::new();
+    let main_args = buffer_args(&main.decoder, main.time_base);
+    let reference_args = buffer_args(&reference.decoder, reference.time_base);
+    _ = graph.add(&find_filter("buffer")?, "main", &main_args)?;
+    _ = graph.add(&find_filter("buffer")?, "ref", &reference_args)?;
+    _ = graph.add(&find_filter("buffersink")?, "out", "")?;
+
+    let spec = format!(
+        "[main]format=yuv420p[m];\
+         [ref]{source_filter_spec},scale={}:{},format=yuv420p[r];\
+         [m][r]{filter_name}[out]",
+        main.decoder.width(),
+        main.decoder.height(),
+    );
+    graph
+        .output("main", 0)?
+        .output("ref", 0)?
+        .input("out", 0)?
+        .parse(&spec)?;
+    graph.validate()?;
+
+    let mut total = 0.0;
+    let mut frames = 0;
+    let mut frame = frame::Video::empty();
+    let mut measured = frame::Video::empty();
+    loop {
+        // Feed whichever input is behind, so neither has to buffer much of the video
+        // while the filter waits for the matching frame from the other.
+        let main_turn = match (main.done, reference.done) {
+            (true, true) => break,
+            (false, true) => true,
+            (true, false) => false,
+            _ => main.position <= reference.position,
+        };
+        let (read, name) = match main_turn {
+            true => (main.next_frame(&mut frame)?, "main"),
+            false => (reference.next_frame(&mut frame)?, "ref"),
+        };
+
+        match read {
+            true => graph.get(name).unwrap().source().add(&frame)?,
+            false => graph.get(name).unwrap().source().flush()?,
+        }
+
+        while graph
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut measured)
+            .is_ok()
+        {
+            if let Some(score) = measured
+                .metadata()
+                .get(key)
+                .and_then(|v| v.parse::<f64>().ok())
+            {
+                // Identical frames have an infinite PSNR.
+                total += score.min(PSNR_IDENTICAL);
+                frames += 1;
+            }
+        }
+    }
+
+    ensure!(
+        frames > 0,
+        "No frames of the encoding could be compared to the source"
+    );
+    Ok(total / frames as f64)
+}
+
+/// Decodes the best video stream of an input one frame at a time.
+struct FrameReader<R> {
+    input: StreamingInput<R>,
+    index: usize,
+    decoder: decoder::Video,
+    time_base: Rational,
+    /// The timestamp of the last frame read, in seconds.
+    position: f64,
+    /// Whether the decoder has been sent the end of the stream.
+    eof: bool,
+    /// Whether every frame has been read.
+    done: bool,
+}
+
+impl<R: Read + Seek> FrameReader<R> {
+    fn new(source: R) -> Result<Self> {
+        let input = StreamingInput::new(BufReader::new(source))?;
+        let (index, time_base, decoder) = match input.streams().best(media::Type::Video) {
+            Some(stream) => (
+                stream.index(),
+                stream.time_base(),
+                codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .video()?,
+            ),
+            None => bail!("Input has no video stream"),
+        };
+
+        Ok(FrameReader {
+            input,
+            index,
+            decoder,
+            time_base,
+            position: 0.0,
+            eof: false,
+            done: false,
+        })
+    }
+
+    /// Reads the next frame, or returns `false` once there are none left.
+    fn next_frame(&mut self, frame: &mut frame::Video) -> Result<bool> {
+        loop {
+            if self.decoder.receive_frame(frame).is_ok() {
+                let timestamp = frame.timestamp();
+                frame.set_pts(timestamp);
+                if let Some(timestamp) = timestamp {
+                    self.position = timestamp as f64 * f64::from(self.time_base);
+                }
+                return Ok(true);
+            }
+
+            if self.eof {
+                self.done = true;
+                return Ok(false);
+            }
+
+            match self.input.packets().next() {
+                Some((stream, packet)) if stream.index() == self.index => {
+                    self.decoder.send_packet(&packet)?
+                }
+                Some(_) => (),
+                None => {
+                    self.decoder.send_eof()?;
+                    self.eof = true;
+                }
+            }
+        }
+    }
 }
 
-/*
-#[allow(unused)]
-impl<R: Read> AVTranscoder<R> {
-    pub fn new(
-        istream: std::io::BufReader<R>,
-        audio_encoder: Audio,
-        video_encoder: Video,
+/// Gathers the facts about a stored WebP image or WebM video from the file itself,
+/// for when those recorded while it was converted have been lost.
+///
+/// Only what the file shows can be found again. What it was converted from,
+/// how its quality was tuned, and whatever is stored alongside it are left unknown.
+pub fn probe(data: &[u8]) -> Result<MediaInfo> {
+    match FileFormat::from_bytes(data) {
+        FileFormat::Webp => {
+            let (width, height) =
+                image::io::Reader::with_format(Cursor::new(data), ImageFormat::WebP)
+                    .into_dimensions()?;
+            Ok(MediaInfo {
+                kind: FileFormat::Webp.media_type().to_owned(),
+                width: Some(width),
+                height: Some(height),
+                frame_count: Some(1),
+                video_codec: Some("webp".to_owned()),
+                ..Default::default()
+            })
+        }
+        FileFormat::Webm => {
+            let frames = count_frames(&mut Cursor::new(data))?;
+            let input = StreamingInput::new(BufReader::new(Cursor::new(data)))?;
+            let mut media = MediaInfo {
+                kind: FileFormat::Webm.media_type().to_owned(),
+                ..Default::default()
+            };
+
+            if let Some(stream) = input.streams().best(media::Type::Video) {
+                let decoder = codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .video()?;
+                media.width = Some(decoder.width());
+                media.height = Some(decoder.height());
+                media.video_codec = Some(decoder.id().name().to_owned());
+                media.frame_count = Some(frames);
+                media.animated = frames > 1;
+                let frame_rate = stream.avg_frame_rate();
+                if frame_rate.numerator() > 0 && frame_rate.denominator() > 0 {
+                    media.frame_rate = Some(f64::from(frame_rate));
+                }
+            }
+            if let Some(stream) = input.streams().best(media::Type::Audio) {
+                let decoder = codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .audio()?;
+                media.audio_codec = Some(decoder.id().name().to_owned());
+                media.audio_channels = Some(decoder.channels());
+                media.sample_rate = Some(decoder.rate());
+            }
+
+            if input.duration() > 0 {
+                media.duration = Some(input.duration() as f64 / f64::from(AV_TIME_BASE));
+            }
+            if input.bit_rate() > 0 {
+                media.bit_rate = Some(input.bit_rate() as u64);
+            }
+            Ok(media)
+        }
+        format => bail!("Stored files are never {}", format.media_type()),
+    }
+}
+
+/// Counts the frames in the input's best video stream without decoding them.
+pub fn count_frames<R: Read + Seek>(source: &mut R) -> Result<u64> {
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+
+    let index = match input.streams().best(media::Type::Video) {
+        Some(stream) => stream.index(),
+        None => return Ok(0),
+    };
+
+    Ok(input
+        .packets()
+        .filter(|(stream, _)| stream.index() == index)
+        .count() as u64)
+}
+
+/// The 3x3 transformation matrix describing how a video's frames should be
+/// displayed, as stored in `AV_PKT_DATA_DISPLAYMATRIX` side data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DisplayMatrix([i32; 9]);
+
+impl DisplayMatrix {
+    fn find<'a>(mut side_data: impl Iterator<Item = packet::SideData<'a>>) -> Option<Self> {
+        side_data
+            .find(|sd| sd.kind() == packet::side_data::Type::DisplayMatrix)
+            .and_then(|sd| Self::from_bytes(sd.data()))
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 9 * 4 {
+            return None;
+        }
+
+        let mut matrix = [0; 9];
+        for (value, bytes) in matrix.iter_mut().zip(data.chunks_exact(4)) {
+            *value = i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Some(DisplayMatrix(matrix))
+    }
+
+    /// The clockwise rotation the matrix applies, in degrees from 0 up to 360.
+    fn rotation(&self) -> f64 {
+        // FFMPEG reports the angle counter-clockwise.
+        let theta = -unsafe { av_display_rotation_get(self.0.as_ptr()) }.round();
+        theta - 360.0 * (theta / 360.0 + 0.9 / 360.0).floor()
+    }
+
+    /// The video filter spec which applies this matrix to decoded frames.
+    /// This follows what the `ffmpeg` command line tool does for `-autorotate`.
+    fn filter_spec(&self) -> String {
+        let theta = self.rotation();
+        let m = &self.0;
+
+        if (theta - 90.0).abs() < 1.0 {
+            match m[3] > 0 {
+                true => "transpose=cclock_flip",
+                false => "transpose=clock",
+            }
+            .to_owned()
+        } else if (theta - 180.0).abs() < 1.0 {
+            match (m[0] < 0, m[4] < 0) {
+                (true, true) => "hflip,vflip",
+                (true, false) => "hflip",
+                (false, true) => "vflip",
+                (false, false) => "null",
+            }
+            .to_owned()
+        } else if (theta - 270.0).abs() < 1.0 {
+            match m[3] < 0 {
+                true => "transpose=clock_flip",
+                false => "transpose=cclock",
+            }
+            .to_owned()
+        } else if theta.abs() > 1.0 {
+            format!("rotate={theta}*PI/180")
+        } else if m[4] < 0 {
+            "vflip".to_owned()
+        } else {
+            "null".to_owned()
+        }
+    }
+}
+
+/// Builds an audio filter graph which reads decoded frames from a source named `in`,
+/// applies `spec`, and delivers the result to a sink named `out`.
+///
+/// If an encoder is given, the sink converts frames into exactly what it expects.
+fn audio_filter(
+    decoder: &decoder::Audio,
+    time_base: Rational,
+    spec: &str,
+    encoder: Option<&encoder::Audio>,
+) -> Result<filter::Graph> {
+    let mut graph = filter::Graph::new();
+
+    // Some demuxers only know the channel count, not the layout.
+    let channel_layout = match decoder.channel_layout() {
+        layout if layout.is_empty() => ChannelLayout::default(i32::from(decoder.channels())),
+        layout => layout,
+    };
+
+    let args = format!(
+        "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+        time_base,
+        decoder.rate(),
+        decoder.format().name(),
+        channel_layout.bits()
+    );
+
+    _ = graph.add(&find_filter("abuffer")?, "in", &args)?;
+    _ = graph.add(&find_filter("abuffersink")?, "out", "")?;
+
+    if let Some(encoder) = encoder {
+        let mut out = graph.get("out").unwrap();
+        out.set_sample_format(encoder.format());
+        out.set_channel_layout(encoder.channel_layout());
+        out.set_sample_rate(encoder.rate());
+    }
+
+    graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+    graph.validate()?;
+
+    if let Some(encoder) = encoder {
+        let variable_frame_size = encoder.codec().map_or(true, |codec| {
+            codec
+                .capabilities()
+                .contains(codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+        });
+
+        if !variable_frame_size {
+            graph
+                .get("out")
+                .unwrap()
+                .sink()
+                .set_frame_size(encoder.frame_size());
+        }
+    }
+
+    Ok(graph)
+}
+
+/// Builds a video filter graph which reads decoded frames from a source named `in`,
+/// applies `spec`, and delivers YUV 4:2:0 frames to a sink named `out`.
+fn video_filter(
+    decoder: &decoder::Video,
+    time_base: Rational,
+    spec: &str,
+) -> Result<filter::Graph> {
+    let mut graph = filter::Graph::new();
+
+    _ = graph.add(
+        &find_filter("buffer")?,
+        "in",
+        &buffer_args(decoder, time_base),
+    )?;
+    _ = graph.add(&find_filter("buffersink")?, "out", "")?;
+
+    graph
+        .get("out")
+        .unwrap()
+        .set_pixel_format(format::Pixel::YUV420P);
+
+    graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+    graph.validate()?;
+
+    Ok(graph)
+}
+
+/// Arguments for a `buffer` filter fed frames from the given decoder.
+fn buffer_args(decoder: &decoder::Video, time_base: Rational) -> String {
+    let aspect_ratio = match decoder.aspect_ratio() {
+        r if r.numerator() == 0 => Rational(1, 1),
+        r => r,
+    };
+
+    format!(
+        "video_size={}x{}:pix_fmt={}:time_base={}:pixel_aspect={}",
+        decoder.width(),
+        decoder.height(),
+        AVPixelFormat::from(decoder.format()) as i32,
+        time_base,
+        aspect_ratio
+    )
+}
+
+fn find_filter(name: &str) -> Result<filter::Filter> {
+    filter::find(name).ok_or_else(|| eyre!("Failed to find FFMPEG filter `{name}`"))
+}
+
+/// The time base of frames leaving a graph's `out` sink.
+fn sink_time_base(graph: &mut filter::Graph) -> Rational {
+    unsafe {
+        Rational::from(av_buffersink_get_time_base(
+            graph.get("out").unwrap().as_ptr(),
+        ))
+    }
+}
+
+/// The dimensions of frames leaving a graph's `out` sink.
+fn sink_dimensions(graph: &mut filter::Graph) -> (u32, u32) {
+    unsafe {
+        let out = graph.get("out").unwrap();
+        (
+            av_buffersink_get_w(out.as_ptr()) as u32,
+            av_buffersink_get_h(out.as_ptr()) as u32,
+        )
+    }
+}
+
+/// The sample aspect ratio of frames leaving a graph's `out` sink.
+fn sink_aspect_ratio(graph: &mut filter::Graph) -> Rational {
+    unsafe {
+        Rational::from(av_buffersink_get_sample_aspect_ratio(
+            graph.get("out").unwrap().as_ptr(),
+        ))
+    }
+}
+
+fn output_time_base(output: &Output, index: usize) -> Result<Rational> {
+    output
+        .stream(index)
+        .map(|stream| stream.time_base())
+        .ok_or_else(|| eyre!("Output stream {index} does not exist"))
+}
+
+/// Converts a timestamp to seconds.
+fn seconds(timestamp: i64, time_base: Rational) -> f64 {
+    timestamp as f64 * f64::from(time_base)
+}
+
+/// Which pass of a VP9 encode a [`VideoTranscoder`] runs.
+#[derive(Debug, Clone, Copy)]
+enum Pass<'a> {
+    /// The only pass of a single-pass encode.
+    Only,
+    /// The first pass of a two-pass encode, which only gathers statistics.
+    First,
+    /// The second pass of a two-pass encode, given the statistics of the first.
+    Second(&'a str),
+}
+
+/// Decodes one stream of the input, re-encodes it as VP9, and writes it to the output.
+struct VideoTranscoder {
+    ist_index: usize,
+    ost_index: usize,
+    decoder: decoder::Video,
+    filter: filter::Graph,
+    encoder: encoder::Video,
+    time_base: Rational,
+    ost_time_base: Rational,
+    frame_rate: Rational,
+    /// The number of packets encoded so far.
+    frames: u64,
+    /// Where the last encoded packet ends, in `time_base` units.
+    end: i64,
+}
+
+impl VideoTranscoder {
+    fn new(
+        ist: &Stream,
+        octx: &mut Output,
+        profile: &TranscodeProfile,
+        filter_spec: &str,
+        pass: Pass,
     ) -> Result<Self> {
-        let input = StreamingInput::new(istream)?;
+        let decoder = codec::context::Context::from_parameters(ist.parameters())?
+            .decoder()
+            .video()?;
+        let codec = encoder::find_by_name("libvpx-vp9")
+            .ok_or_else(|| eyre!("Failed to find VP9 encoder"))?;
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
 
-        // Transcode audio
-        let opus_audio = {
-            let input = input
-                .streams()
-                .best(media::Type::Audio)
-                .ok_or_else(|| eyre!("Failed to find best audio stream"))?;
+        // Rotating the video may swap its dimensions, so the encoder
+        // takes them from the filter graph rather than the decoder.
+        let mut filter = video_filter(&decoder, ist.time_base(), filter_spec)?;
+        let time_base = sink_time_base(&mut filter);
+        let (width, height) = sink_dimensions(&mut filter);
+        let aspect_ratio = sink_aspect_ratio(&mut filter);
+        let frame_rate = decoder.frame_rate().unwrap_or_else(|| ist.avg_frame_rate());
 
-            let mut decoder = ffmpeg_next::codec::Context::from_parameters(input.parameters())?
-                .decoder()
-                .audio()?;
-            decoder.set_parameters(input.parameters());
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = encoder::new().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_aspect_ratio(aspect_ratio);
+        encoder.set_format(format::Pixel::YUV420P);
+        encoder.set_time_base(time_base);
+        encoder.set_frame_rate(Some(frame_rate));
+        // A zero bit rate puts libvpx into constant quality mode, driven by `crf`.
+        encoder.set_bit_rate(0);
+
+        let mut flags = codec::Flags::empty();
+        if global_header {
+            flags |= codec::Flags::GLOBAL_HEADER;
+        }
+        match pass {
+            Pass::Only => (),
+            Pass::First => flags |= codec::Flags::PASS1,
+            Pass::Second(stats) => {
+                flags |= codec::Flags::PASS2;
+                encoder.set_stats_in(stats)?;
+            }
+        }
+        encoder.set_flags(flags);
+
+        let mut options = Dictionary::new();
+        options.set("crf", &profile.video_crf.to_string());
+        options.set("deadline", "good");
+        options.set("cpu-used", "4");
+        options.set("row-mt", "1");
+
+        let encoder = encoder.open_as_with(codec, options)?;
+        ost.set_parameters(&encoder);
+
+        Ok(VideoTranscoder {
+            ist_index: ist.index(),
+            ost_index: ost.index(),
+            decoder,
+            filter,
+            encoder,
+            time_base,
+            ost_time_base: time_base,
+            frame_rate,
+            frames: 0,
+            end: 0,
+        })
+    }
+
+    fn send_packet(&mut self, packet: &Packet, octx: &mut Output) -> Result<()> {
+        self.decoder.send_packet(packet)?;
+        self.receive_decoded(octx)
+    }
+
+    /// Drains the decoder, filter and encoder once the input has run out of packets.
+    fn finish(&mut self, octx: &mut Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        self.receive_decoded(octx)?;
+        self.filter.get("in").unwrap().source().flush()?;
+        self.receive_filtered(octx)?;
+        self.encoder.send_eof()?;
+        self.receive_encoded(octx)
+    }
+
+    /// Records what was written to the output.
+    fn describe(&self, media: &mut MediaInfo) {
+        media.width = Some(self.encoder.width());
+        media.height = Some(self.encoder.height());
+        media.video_codec = Some(self.encoder.id().name().to_owned());
+        media.frame_count = Some(self.frames);
+        media.animated = self.frames > 1;
+        if self.frame_rate.numerator() > 0 && self.frame_rate.denominator() > 0 {
+            media.frame_rate = Some(f64::from(self.frame_rate));
+        }
+        let duration = seconds(self.end, self.time_base);
+        media.duration = Some(media.duration.map_or(duration, |d| d.max(duration)));
+    }
 
-            let encoder = ffmpeg_next::encoder::find(ffmpeg_next::codec::Id::OPUS)
-                .ok_or_else(|| eyre!("Failed to find Opus encoder"))?
-                .audio()?;
-            encoder.set_parameters(input.parameters());
+    fn receive_decoded(&mut self, octx: &mut Output) -> Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            // The filter graph already applies the rotation.
+            decoded.remove_side_data(frame::side_data::Type::DisplayMatrix);
+            self.filter.get("in").unwrap().source().add(&decoded)?;
+            self.receive_filtered(octx)?;
+        }
+        Ok(())
+    }
 
-            let channel_layout = encoder
-                .channel_layouts()
-                .map(|cls| cls.best(decoder.channel_layout().channels()))
-                .unwrap_or(ffmpeg::channel_layout::ChannelLayout::STEREO);
+    fn receive_filtered(&mut self, octx: &mut Output) -> Result<()> {
+        let mut filtered = frame::Video::empty();
+        while self
+            .filter
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            // Let the encoder place keyframes where it sees fit.
+            filtered.set_kind(picture::Type::None);
+            self.encoder.send_frame(&filtered)?;
+            self.receive_encoded(octx)?;
+        }
+        Ok(())
+    }
 
-            encoder.set_rate(decoder.rate() as i32);
+    fn receive_encoded(&mut self, octx: &mut Output) -> Result<()> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            self.frames += 1;
+            if let Some(pts) = encoded.pts() {
+                self.end = self.end.max(pts + encoded.duration());
+            }
+            encoded.set_stream(self.ost_index);
+            encoded.rescale_ts(self.time_base, self.ost_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
+}
 
-            0
+/// Decodes one stream of the input, runs it through a filter graph,
+/// re-encodes it as Opus, and writes it to the output.
+struct AudioTranscoder {
+    ist_index: usize,
+    ost_index: usize,
+    decoder: decoder::Audio,
+    filter: filter::Graph,
+    encoder: encoder::Audio,
+    filter_time_base: Rational,
+    time_base: Rational,
+    ost_time_base: Rational,
+    /// Where the last encoded packet ends, in `time_base` units.
+    end: i64,
+}
+
+impl AudioTranscoder {
+    fn new(
+        ist: &Stream,
+        octx: &mut Output,
+        profile: &TranscodeProfile,
+        filter_spec: &str,
+    ) -> Result<Self> {
+        let decoder = codec::context::Context::from_parameters(ist.parameters())?
+            .decoder()
+            .audio()?;
+        let codec = encoder::find_by_name("libopus")
+            .ok_or_else(|| eyre!("Failed to find Opus encoder"))?
+            .audio()?;
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+        // Surround sound is downmixed, since Opus needs extra channel mapping metadata for it.
+        let channel_layout = match decoder.channels() {
+            1 => ChannelLayout::MONO,
+            _ => ChannelLayout::STEREO,
         };
+        let time_base = Rational(1, OPUS_SAMPLE_RATE as i32);
+
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = encoder::new().audio()?;
+        encoder.set_rate(OPUS_SAMPLE_RATE as i32);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_channels(channel_layout.channels());
+        encoder.set_format(
+            codec
+                .formats()
+                .and_then(|mut formats| formats.next())
+                .ok_or_else(|| eyre!("Opus encoder does not report any sample formats"))?,
+        );
+        encoder.set_bit_rate(profile.audio_bit_rate);
+        encoder.set_time_base(time_base);
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
 
-        Ok(Self {
-            istream: input,
-            audio_encoder,
-            video_encoder,
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+
+        let mut filter = audio_filter(&decoder, ist.time_base(), filter_spec, Some(&encoder))?;
+        let filter_time_base = sink_time_base(&mut filter);
+
+        Ok(AudioTranscoder {
+            ist_index: ist.index(),
+            ost_index: ost.index(),
+            decoder,
+            filter,
+            encoder,
+            filter_time_base,
+            time_base,
+            ost_time_base: time_base,
+            end: 0,
         })
     }
+
+    fn send_packet(&mut self, packet: &Packet, octx: &mut Output) -> Result<()> {
+        self.decoder.send_packet(packet)?;
+        self.receive_decoded(octx)
+    }
+
+    /// Drains the decoder, filter and encoder once the input has run out of packets.
+    fn finish(&mut self, octx: &mut Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        self.receive_decoded(octx)?;
+        self.filter.get("in").unwrap().source().flush()?;
+        self.receive_filtered(octx)?;
+        self.encoder.send_eof()?;
+        self.receive_encoded(octx)
+    }
+
+    /// Records what was written to the output.
+    fn describe(&self, media: &mut MediaInfo) {
+        media.audio_codec = Some(self.encoder.id().name().to_owned());
+        media.audio_channels = Some(self.encoder.channels());
+        media.sample_rate = Some(self.encoder.rate());
+        let duration = seconds(self.end, self.time_base);
+        media.duration = Some(media.duration.map_or(duration, |d| d.max(duration)));
+    }
+
+    fn receive_decoded(&mut self, octx: &mut Output) -> Result<()> {
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            self.filter.get("in").unwrap().source().add(&decoded)?;
+            self.receive_filtered(octx)?;
+        }
+        Ok(())
+    }
+
+    fn receive_filtered(&mut self, octx: &mut Output) -> Result<()> {
+        let mut filtered = frame::Audio::empty();
+        while self
+            .filter
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            // `loudnorm` resamples internally, so the graph's output time base
+            // isn't necessarily the encoder's.
+            let pts = filtered
+                .pts()
+                .map(|pts| pts.rescale(self.filter_time_base, self.time_base));
+            filtered.set_pts(pts);
+            self.encoder.send_frame(&filtered)?;
+            self.receive_encoded(octx)?;
+        }
+        Ok(())
+    }
+
+    fn receive_encoded(&mut self, octx: &mut Output) -> Result<()> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            if let Some(pts) = encoded.pts() {
+                self.end = self.end.max(pts + encoded.duration());
+            }
+            encoded.set_stream(self.ost_index);
+            encoded.rescale_ts(self.time_base, self.ost_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
 }
 
-impl<R: Read> Read for AVTranscoder<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        todo!()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{codecs::gif::GifEncoder, Delay, Frame, Rgba};
+
+    /// A short animated GIF of a square sliding across a gradient.
+    fn sample() -> Vec<u8> {
+        const SIZE: u32 = 64;
+        const FRAMES: u32 = 12;
+
+        let frames = (0..FRAMES).map(|index| {
+            let image = RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+                let square = x / 16 == index % 4 && y / 16 == index / 4;
+                match square {
+                    true => Rgba([255, 255, 255, 255]),
+                    false => Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255]),
+                }
+            });
+            Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))
+        });
+
+        let mut data = Vec::new();
+        GifEncoder::new(&mut data).encode_frames(frames).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_first_pass_gathers_stats() {
+        let profile = TranscodeProfile::default();
+        let stats = first_pass(&mut Cursor::new(sample()), &profile, "null").unwrap();
+        assert!(!stats.is_empty());
+    }
+
+    #[test]
+    fn test_two_pass_encode() {
+        let sample = sample();
+        let profile = TranscodeProfile {
+            two_pass: true,
+            ..Default::default()
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        let report = convert_to_webm(&mut Cursor::new(&sample), &mut out, &profile).unwrap();
+        assert_eq!(report.media.video_codec.as_deref(), Some("vp9"));
+        assert_eq!(
+            (report.media.width, report.media.height),
+            (Some(64), Some(64))
+        );
+
+        // Every frame of the sample survives both passes.
+        out.set_position(0);
+        assert_eq!(
+            count_frames(&mut out).unwrap(),
+            count_frames(&mut Cursor::new(&sample)).unwrap()
+        );
     }
 }
-*/
\ No newline at end of file
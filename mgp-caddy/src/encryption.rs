@@ -0,0 +1,339 @@
+//! Envelope encryption of stored files.
+//!
+//! Every file encrypted at rest has a data key of its own, which its contents and attachments
+//! are encrypted with. The data key is kept next to the file, wrapped by one of the master keys
+//! from the configuration, so the master key can change by wrapping each data key afresh
+//! without touching the media.
+//!
+//! Encrypted objects are split into chunks of [`CHUNK_SIZE`] bytes, each sealed on its own with
+//! XChaCha20-Poly1305, so any range of an object can be read by decrypting only the chunks it
+//! spans. An object starts with a header holding a random nonce prefix, which the index of each
+//! chunk is appended to for its nonce. Each chunk is also bound to the name of the object and to
+//! whether it is the last, so chunks can't be moved between objects and an object can't be cut short.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use eyre::{ensure, eyre, Result};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use crate::fs::FileStore;
+
+/// How many bytes of an object each chunk holds, before it is sealed.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// What every encrypted object starts with, followed by the version of its format.
+const MAGIC: &[u8; 4] = b"MGPE";
+const FORMAT_VERSION: u8 = 1;
+
+/// The length of the random part of each chunk's nonce, which the chunk index follows.
+const NONCE_PREFIX_LEN: usize = 16;
+
+/// The length of the header an encrypted object starts with.
+pub const HEADER_LEN: u64 = (MAGIC.len() + 1 + NONCE_PREFIX_LEN) as u64;
+
+/// The length of the authentication tag each sealed chunk ends with.
+const TAG_LEN: u64 = 16;
+
+/// The length of a key, whether a master key or a data key.
+pub const KEY_LEN: usize = 32;
+
+/// Decodes a configured key, such as a master key or a URL signing key, which is always
+/// 32 random bytes in standard base64. `what` names the kind of key in errors.
+pub fn decode_key(what: &str, kid: &str, key: &str) -> Result<[u8; KEY_LEN]> {
+    let key = STANDARD
+        .decode(key)
+        .map_err(|e| eyre!("The {what} `{kid}` isn't valid base64: {e}"))?;
+    key.try_into()
+        .map_err(|_| eyre!("The {what} `{kid}` must be {KEY_LEN} bytes long"))
+}
+
+/// Settings for encrypting stored files.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// The master keys data keys may be wrapped with, by key ID, each 32 bytes in base64.
+    pub master_keys: HashMap<String, String>,
+    /// The ID of the master key new data keys are wrapped with.
+    /// New files are only encrypted when one is set.
+    pub current_key: Option<String>,
+}
+
+/// The master keys, which wrap the data key of each encrypted file.
+#[derive(Default)]
+pub struct Keyring {
+    master_keys: HashMap<String, XChaCha20Poly1305>,
+    current_key: Option<String>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("master_keys", &self.master_keys.keys())
+            .field("current_key", &self.current_key)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Makes sure the configured master keys are usable.
+    pub fn new(config: &EncryptionConfig) -> Result<Keyring> {
+        let mut master_keys = HashMap::new();
+        for (kid, key) in &config.master_keys {
+            let key = decode_key("master key", kid, key)?;
+            _ = master_keys.insert(kid.clone(), XChaCha20Poly1305::new_from_slice(&key)?);
+        }
+        if let Some(current_key) = &config.current_key {
+            ensure!(
+                master_keys.contains_key(current_key),
+                "The current master key `{current_key}` isn't one of the master keys"
+            );
+        }
+
+        Ok(Keyring {
+            master_keys,
+            current_key: config.current_key.clone(),
+        })
+    }
+
+    /// Generates a data key for a new file, along with the data key wrapped with the
+    /// current master key, to keep next to the file. Returns `None` if there is
+    /// no current master key, and so new files aren't encrypted.
+    pub fn generate(&self, file_id: &str) -> Result<Option<(DataKey, WrappedKey)>> {
+        let kid = match &self.current_key {
+            Some(kid) => kid,
+            None => return Ok(None),
+        };
+
+        let mut key = [0; KEY_LEN];
+        thread_rng().fill_bytes(&mut key);
+        let wrapped = self.wrap(kid, &key, file_id)?;
+        Ok(Some((
+            DataKey(XChaCha20Poly1305::new(&key.into())),
+            wrapped,
+        )))
+    }
+
+    /// Unwraps the data key of a file.
+    pub fn unwrap(&self, wrapped: &WrappedKey, file_id: &str) -> Result<DataKey> {
+        let key = self.unwrap_bytes(wrapped, file_id)?;
+        Ok(DataKey(XChaCha20Poly1305::new_from_slice(&key)?))
+    }
+
+    /// Wraps the data key of a file with the current master key instead.
+    /// Returns `None` if it already is.
+    pub fn rewrap(&self, wrapped: &WrappedKey, file_id: &str) -> Result<Option<WrappedKey>> {
+        let kid = self
+            .current_key
+            .as_ref()
+            .ok_or_else(|| eyre!("No current master key is configured"))?;
+        if wrapped.kid == *kid {
+            return Ok(None);
+        }
+
+        let key = self.unwrap_bytes(wrapped, file_id)?;
+        Ok(Some(self.wrap(kid, &key, file_id)?))
+    }
+
+    /// Wraps a data key with the named master key. The file ID is bound to the wrapped key,
+    /// so it can't be passed off as the data key of another file.
+    fn wrap(&self, kid: &str, key: &[u8], file_id: &str) -> Result<WrappedKey> {
+        let master_key = self.master_key(kid)?;
+        let mut nonce = XNonce::default();
+        thread_rng().fill_bytes(&mut nonce);
+        let sealed = master_key
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: key,
+                    aad: file_id.as_bytes(),
+                },
+            )
+            .map_err(|_| eyre!("Failed to wrap a data key"))?;
+
+        Ok(WrappedKey {
+            kid: kid.to_owned(),
+            nonce: STANDARD.encode(nonce),
+            key: STANDARD.encode(sealed),
+        })
+    }
+
+    /// Unwraps a data key into its bytes.
+    fn unwrap_bytes(&self, wrapped: &WrappedKey, file_id: &str) -> Result<Vec<u8>> {
+        let master_key = self.master_key(&wrapped.kid)?;
+        let nonce = STANDARD.decode(&wrapped.nonce)?;
+        ensure!(
+            nonce.len() == XNonce::default().len(),
+            "The data key of `{file_id}` is malformed"
+        );
+        master_key
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &STANDARD.decode(&wrapped.key)?,
+                    aad: file_id.as_bytes(),
+                },
+            )
+            .map_err(|_| eyre!("The data key of `{file_id}` can't be unwrapped"))
+    }
+
+    /// The named master key.
+    fn master_key(&self, kid: &str) -> Result<&XChaCha20Poly1305> {
+        self.master_keys
+            .get(kid)
+            .ok_or_else(|| eyre!("Unknown master key `{kid}`"))
+    }
+}
+
+/// The data key of a file, wrapped with a master key, as kept next to the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    /// The ID of the master key the data key is wrapped with.
+    pub kid: String,
+    nonce: String,
+    key: String,
+}
+
+/// The key a file and its attachments are encrypted with.
+pub struct DataKey(XChaCha20Poly1305);
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    /// Encrypts an object, which is the file itself for an empty `name`, or the attachment of it so named.
+    pub fn encrypt(&self, name: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        thread_rng().fill_bytes(&mut prefix);
+
+        // Even an empty object has a chunk, so cutting every chunk off is noticed.
+        let chunks = data.len().div_ceil(CHUNK_SIZE as usize).max(1);
+        let mut sealed =
+            Vec::with_capacity(HEADER_LEN as usize + data.len() + chunks * TAG_LEN as usize);
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(FORMAT_VERSION);
+        sealed.extend_from_slice(&prefix);
+
+        for index in 0..chunks {
+            let start = index * CHUNK_SIZE as usize;
+            let chunk = &data[start..data.len().min(start + CHUNK_SIZE as usize)];
+            let last = index + 1 == chunks;
+            let chunk = self
+                .0
+                .encrypt(
+                    &nonce(&prefix, index as u64),
+                    Payload {
+                        msg: chunk,
+                        aad: &chunk_aad(name, last),
+                    },
+                )
+                .map_err(|_| eyre!("Failed to encrypt `{name}`"))?;
+            sealed.extend_from_slice(&chunk);
+        }
+
+        Ok(sealed)
+    }
+
+    /// Decrypts a whole object, encrypted with [`encrypt`](Self::encrypt) under the same name.
+    pub fn decrypt(&self, name: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        ensure!(
+            sealed.len() as u64 >= HEADER_LEN,
+            "`{name}` is too short to be encrypted"
+        );
+        let (header, chunks) = sealed.split_at(HEADER_LEN as usize);
+        self.decrypt_chunks(name, header, 0, chunks, chunk_count(sealed.len() as u64))
+    }
+
+    /// Decrypts consecutive chunks of an object, starting with the chunk numbered `first`,
+    /// given the header of the object and how many chunks it has in all.
+    pub fn decrypt_chunks(
+        &self,
+        name: &str,
+        header: &[u8],
+        first: u64,
+        chunks: &[u8],
+        count: u64,
+    ) -> Result<Vec<u8>> {
+        ensure!(
+            is_encrypted(header) && header.len() as u64 >= HEADER_LEN,
+            "`{name}` isn't encrypted"
+        );
+        let prefix = &header[MAGIC.len() + 1..HEADER_LEN as usize];
+
+        let mut data = Vec::with_capacity(chunks.len());
+        let mut index = first;
+        for chunk in chunks.chunks((CHUNK_SIZE + TAG_LEN) as usize) {
+            let chunk = self
+                .0
+                .decrypt(
+                    &nonce(prefix, index),
+                    Payload {
+                        msg: chunk,
+                        aad: &chunk_aad(name, index + 1 == count),
+                    },
+                )
+                .map_err(|_| eyre!("Chunk {index} of `{name}` can't be decrypted"))?;
+            data.extend_from_slice(&chunk);
+            index += 1;
+        }
+        ensure!(index > first, "`{name}` is missing chunk {first}");
+
+        Ok(data)
+    }
+}
+
+/// Whether an object is encrypted, judging by the header it starts with.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) && data.get(MAGIC.len()) == Some(&FORMAT_VERSION)
+}
+
+/// How many chunks an encrypted object `size` bytes long holds.
+pub fn chunk_count(size: u64) -> u64 {
+    size.saturating_sub(HEADER_LEN)
+        .div_ceil(CHUNK_SIZE + TAG_LEN)
+}
+
+/// How long the contents of an encrypted object `size` bytes long are, once decrypted.
+pub fn plaintext_len(size: u64) -> u64 {
+    size.saturating_sub(HEADER_LEN)
+        .saturating_sub(chunk_count(size) * TAG_LEN)
+}
+
+/// The range of chunks holding a range of bytes of the contents of an object, which must not be empty.
+pub fn chunk_span(range: &Range<u64>) -> Range<u64> {
+    range.start / CHUNK_SIZE..(range.end - 1) / CHUNK_SIZE + 1
+}
+
+/// Where a range of chunks is found in an encrypted object.
+pub fn sealed_range(chunks: &Range<u64>) -> Range<u64> {
+    HEADER_LEN + chunks.start * (CHUNK_SIZE + TAG_LEN)
+        ..HEADER_LEN + chunks.end * (CHUNK_SIZE + TAG_LEN)
+}
+
+/// The nonce of a chunk: the random prefix of its object, then its index.
+fn nonce(prefix: &[u8], index: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+/// The associated data of a chunk: whether it is the last of its object, then the name of the object.
+fn chunk_aad(name: &str, last: bool) -> Vec<u8> {
+    [&[u8::from(last)], name.as_bytes()].concat()
+}
+
+/// Runs the `mgp-caddy rewrap` subcommand, which wraps the data key of every encrypted file
+/// with the current master key, so the master keys before it can be retired.
+pub async fn rewrap(store: Arc<FileStore>) -> Result<()> {
+    let rewrapped = store.rewrap().await?;
+    println!("{rewrapped} data keys rewrapped with the current master key");
+    Ok(())
+}
@@ -1,192 +1,1899 @@
-//! Code for saving and retrieving files from the disk.
+//! Code for saving and retrieving files from storage.
 
-use base64::{
-    alphabet,
-    engine::{self, general_purpose},
-    Engine as _,
-};
-use dashmap::DashMap;
+use crate::convert::{self, MediaInfo};
+use crate::encryption::{self, DataKey, Keyring, WrappedKey};
+use crate::id::NormalizedId;
+use crate::profile_image;
+use crate::quota::{Quota, Usage};
+use crate::storage::{ObjectReader, StorageBackend};
+use crate::storyboard;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
 use eyre::{bail, ensure, Result};
-use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashSet},
     error::Error,
     fmt::Display,
-    path::{Path, PathBuf},
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
 };
-use std::{
-    io::ErrorKind,
-    time::{SystemTime, UNIX_EPOCH},
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
 };
-use tokio::{fs::File, io::AsyncRead, sync::RwLock};
-use uuid::Uuid;
 
-/// A pure, safe interface to access files of any kind.
-/// This struct guards against path traversal and symlink abuse.
-/// It does not check the file contents or names at all though.
-#[derive(Debug)]
-pub struct FileStore {
-    base_path: PathBuf,
-    handles: DashMap<String, RwLock<()>>,
-}
+/// The attachment the metadata record of a file is kept in.
+const META_NAME: &str = "meta.json";
+
+/// The attachment the wrapped data key of an encrypted file is kept in. It is kept apart from
+/// the metadata record, since a lost record is rebuilt from the file, but a lost key can't be.
+const KEY_NAME: &str = "key.json";
+
+/// The version of the metadata records written now. Fields are only ever added to records,
+/// so those of every version stay readable, and older ones are upgraded by the scrubber.
+///
+/// Version 2 added the staging state and expiry of the file.
+const META_VERSION: u32 = 2;
+
+/// The attachment the original upload is kept under, when originals are retained.
+pub const ORIGINAL_NAME: &str = "original";
+
+/// The attachment marking a file as deleted, until the collector removes it.
+const TOMBSTONE_NAME: &str = "tombstone";
+
+/// The attachment the checksums of a file and its other attachments are recorded in.
+const CHECKSUMS_NAME: &str = "checksums.json";
+
+/// The attachment recording who uploaded a file, and what it is counted as in their usage.
+const OWNER_NAME: &str = "owner.json";
+
+/// The prefix of the keys deletions are recorded under, one per deleted ID.
+/// They are kept forever, and are what stops a deleted ID from ever being issued again.
+pub const DELETIONS_PREFIX: &str = "deletions/";
+
+/// The prefix of the keys deleted files are listed under until they are collected,
+/// one per file, so the collector can find them without walking the whole store.
+pub const UNCOLLECTED_PREFIX: &str = "uncollected/";
+
+/// The prefix of the keys objects found corrupted by the scrubber are moved under,
+/// followed by the key they were stored under.
+pub const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// The prefix of the keys the usage of each uploader is counted under.
+pub const USAGE_PREFIX: &str = "usage/";
+
+/// The prefix of the keys staged files are listed under until they are claimed,
+/// one per file, so expired ones can be found without reading every record.
+pub const STAGED_PREFIX: &str = "staged/";
+
+/// How many keys the inventory lists from the backend at a time.
+const INVENTORY_LISTING_SIZE: usize = 1000;
+
+/// A pure, safe interface to access files of any kind.
+/// The bytes themselves are kept by a [`StorageBackend`], which guards against
+/// path traversal and symlink abuse. It does not check the file contents or names at all though.
+#[derive(Debug)]
+pub struct FileStore {
+    backend: Arc<dyn StorageBackend>,
+    handles: Handles,
+    /// Every ID which has ever been deleted, as recorded under [`DELETIONS_PREFIX`].
+    deleted: DashSet<String>,
+    /// Whether files are checked against their checksums whenever they are read.
+    verify_reads: bool,
+    /// How long new files stay staged before they expire, if they are staged at all.
+    staging_ttl: Option<chrono::Duration>,
+    /// The master keys the data keys of encrypted files are wrapped with.
+    keyring: Keyring,
+}
+
+/// The lock of every file ID which is currently being read or written.
+type Handles = Arc<DashMap<String, Arc<RwLock<()>>>>;
+
+unsafe impl Send for FileStore {}
+unsafe impl Sync for FileStore {}
+
+impl FileStore {
+    /// Create a new `FileStore` instance keeping its files in the given backend.
+    pub async fn new(backend: Arc<dyn StorageBackend>) -> Result<FileStore> {
+        let deleted = DashSet::new();
+        for key in backend.list(DELETIONS_PREFIX).await? {
+            if let Some(id) = key.strip_prefix(DELETIONS_PREFIX) {
+                _ = deleted.insert(id.to_owned());
+            }
+        }
+
+        Ok(FileStore {
+            backend,
+            handles: Arc::new(DashMap::new()),
+            deleted,
+            verify_reads: false,
+            staging_ttl: None,
+            keyring: Keyring::default(),
+        })
+    }
+
+    /// Sets whether files and attachments are checked against their checksums whenever
+    /// they are read, failing with [`FSError::Corrupt`] if they don't match.
+    /// Otherwise only the scrubber checks them.
+    pub fn verify_reads(mut self, verify_reads: bool) -> FileStore {
+        self.verify_reads = verify_reads;
+        self
+    }
+
+    /// Sets how long new files stay [staged](FileState::Staged) before they expire,
+    /// unless they are [claimed](Self::claim). Without one, new files are claimed as
+    /// soon as they are written.
+    pub fn staging_ttl(mut self, ttl: Option<std::time::Duration>) -> FileStore {
+        self.staging_ttl =
+            ttl.map(|ttl| chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX));
+        self
+    }
+
+    /// Sets the master keys files are [encrypted](crate::encryption) with. New files are
+    /// encrypted if the keyring has a current master key, and files already stored can be
+    /// read as long as it still has the master key their data key is wrapped with.
+    pub fn keyring(mut self, keyring: Keyring) -> FileStore {
+        self.keyring = keyring;
+        self
+    }
+
+    /// Write a file's contents into the store.
+    /// Returns the file name the file is stored under, which is its ID.
+    ///
+    /// The file only appears once it has been written in full,
+    /// so a crash part way through never leaves a truncated file behind.
+    /// Its checksum and its metadata record, holding `media`, are stored before it is,
+    /// so it never appears without them. If a staging TTL is set, the file starts out
+    /// staged, and is deleted by [`sweep`](Self::sweep) unless claimed before it expires.
+    /// Fails with [`FSError::NameCollision`] if a file with the same ID already exists,
+    /// or ever existed and was deleted. The files derived from it, such as its storyboard,
+    /// are stored next to it as `attachments` before it appears.
+    ///
+    /// A file with an uploader is counted in their usage, along with everything stored with it,
+    /// and fails with [`QuotaExceeded`](crate::quota::QuotaExceeded) if all of that together
+    /// doesn't fit in their quota.
+    ///
+    /// If the store has a current master key, the file is encrypted with a new data key,
+    /// which its attachments are encrypted with too.
+    ///
+    /// Holds the file ID exclusively while writing, so it waits for any readers to finish.
+    pub async fn write(
+        &self,
+        normalized_id: &NormalizedId,
+        payload: &[u8],
+        media: &MediaInfo,
+        attachments: &[(String, Vec<u8>)],
+        uploader: Option<(&str, &Quota)>,
+    ) -> Result<String> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        ensure!(
+            !self.deleted.contains(&fname),
+            FSError::NameCollision(fname)
+        );
+
+        // A file which was quarantined whole still has its checksums.
+        for existing in [key.clone(), Self::attachment_key(&key, CHECKSUMS_NAME)] {
+            match self.backend.head(&existing).await {
+                Ok(_) => bail!(FSError::NameCollision(fname)),
+                Err(e) if is_not_found(&e) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        for (name, _) in attachments {
+            ensure!(
+                !name.contains(std::path::is_separator),
+                FSError::DirectoryTraversal(fname)
+            );
+        }
+
+        let now = Utc::now();
+        let expires_at = self.staging_ttl.map(|ttl| now + ttl);
+        let size = payload.len() as u64;
+        let (payload, attachments, wrapped_key) = match self.keyring.generate(&fname)? {
+            Some((data_key, wrapped_key)) => {
+                let attachments = attachments
+                    .iter()
+                    .map(|(name, data)| Ok((name, Cow::Owned(data_key.encrypt(name, data)?))))
+                    .collect::<Result<Vec<_>>>()?;
+                (
+                    Cow::Owned(data_key.encrypt("", payload)?),
+                    attachments,
+                    Some(serde_json::to_vec(&wrapped_key)?),
+                )
+            }
+            None => (
+                Cow::Borrowed(payload),
+                attachments
+                    .iter()
+                    .map(|(name, data)| (name, Cow::Borrowed(data.as_slice())))
+                    .collect(),
+                None,
+            ),
+        };
+        let record = serde_json::to_vec(&MetaRecord::new(FileMeta {
+            name: fname.clone(),
+            size,
+            date_created: now,
+            date_modified: now,
+            uploader: uploader.map(|(uploader, _)| uploader.to_owned()),
+            state: match expires_at {
+                Some(_) => FileState::Staged,
+                None => FileState::Claimed,
+            },
+            expires_at,
+            media: media.clone(),
+        }))?;
+        let mut checksums = Checksums {
+            file: Some(checksum(&payload)),
+            attachments: BTreeMap::from([(META_NAME.to_owned(), checksum(&record))]),
+        };
+        if let Some(wrapped_key) = &wrapped_key {
+            _ = checksums
+                .attachments
+                .insert(KEY_NAME.to_owned(), checksum(wrapped_key));
+        }
+        for (name, data) in &attachments {
+            _ = checksums
+                .attachments
+                .insert((*name).clone(), checksum(data));
+        }
+
+        // Admitted against everything stored for the file, and not just the file itself.
+        if let Some((uploader, quota)) = uploader {
+            let size = payload.len()
+                + record.len()
+                + wrapped_key.as_ref().map_or(0, Vec::len)
+                + attachments
+                    .iter()
+                    .map(|(_, data)| data.len())
+                    .sum::<usize>();
+            self.charge(&key, uploader, quota, size as u64).await?;
+        }
+
+        let stored = async {
+            // Listed first, so the sweeper still finds the file if the write is interrupted.
+            if let Some(expires_at) = expires_at {
+                self.write_staging(&fname, expires_at).await?;
+            }
+            self.write_checksums(&key, &checksums).await?;
+            // Stored before anything encrypted with it.
+            if let Some(wrapped_key) = &wrapped_key {
+                self.backend
+                    .put(&Self::attachment_key(&key, KEY_NAME), wrapped_key)
+                    .await?;
+            }
+            self.backend
+                .put(&Self::attachment_key(&key, META_NAME), &record)
+                .await?;
+            for (name, data) in &attachments {
+                self.backend
+                    .put(&Self::attachment_key(&key, name), data)
+                    .await?;
+            }
+            self.backend.put(&key, &payload).await
+        };
+        if let Err(e) = stored.await {
+            // What was stored before the failure would otherwise never be collected.
+            if let Err(cleanup) = self.discard(&key, &fname).await {
+                tracing::error!(key, "Failed to remove a partly written file: {cleanup}");
+            }
+            return Err(e);
+        }
+        self.settle(&key, true).await?;
+
+        Ok(fname)
+    }
+
+    /// Removes everything stored for a file whose write failed part way through,
+    /// and takes it out of its uploader's usage, so its ID can be written afresh.
+    ///
+    /// The file ID must be held exclusively.
+    async fn discard(&self, key: &str, fname: &str) -> Result<()> {
+        self.settle(key, false).await?;
+        for object in self.backend.list(key).await? {
+            if object == key || object.starts_with(&format!("{key}.")) {
+                self.backend.delete(&object).await?;
+            }
+        }
+        self.backend
+            .delete(&format!("{STAGED_PREFIX}{fname}"))
+            .await
+    }
+
+    /// Retrieves the given file from the store.
+    ///
+    /// The file ID is held shared until the returned reader is dropped,
+    /// so the file can't be written or deleted while it is being read.
+    pub async fn read(&self, normalized_id: &NormalizedId) -> Result<impl AsyncRead> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let handle = self.lock_shared(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+
+        Ok(Guarded {
+            inner: self.get_decrypted(&key, None, &fname, None).await?,
+            _handle: handle,
+        })
+    }
+
+    /// Retrieves a range of bytes of the given file, cut short at the end of the file.
+    /// Only the part of an encrypted file holding the range is read and decrypted,
+    /// unless reads are verified, which needs the whole file.
+    /// Like [`read`](Self::read), the file ID is held shared until the reader is dropped.
+    pub async fn read_range(
+        &self,
+        normalized_id: &NormalizedId,
+        range: Range<u64>,
+    ) -> Result<impl AsyncRead> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let handle = self.lock_shared(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+
+        Ok(Guarded {
+            inner: self.get_decrypted(&key, None, &fname, Some(range)).await?,
+            _handle: handle,
+        })
+    }
+
+    /// Records new media facts for a stored file in its metadata record,
+    /// so they can be served without probing the file again.
+    /// The rest of the record is kept, or taken from the file again if it can't be read.
+    /// The file itself must already have been written.
+    pub async fn write_meta(&self, normalized_id: &NormalizedId, media: &MediaInfo) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        _ = self.backend.head(&key).await?;
+
+        let record = match self.read_record(&key, &fname).await? {
+            Some(record) => MetaRecord::new(FileMeta {
+                media: media.clone(),
+                ..record.meta
+            }),
+            None => MetaRecord::new(self.meta_from_file(&key, &fname, media.clone()).await?),
+        };
+        self.put_attachment(&key, META_NAME, &serde_json::to_vec(&record)?)
+            .await?;
+        self.settle(&key, true).await
+    }
+
+    /// Retrieves the metadata record of the given file, without opening the file itself.
+    /// Fails with [`FSError::BadMeta`] if the record is missing or can't be read,
+    /// in which case it can be rebuilt with [`rebuild_meta`](Self::rebuild_meta).
+    pub async fn read_meta(&self, normalized_id: &NormalizedId) -> Result<FileMeta> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_shared(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+
+        match self.read_record(&key, &fname).await? {
+            Some(record) => Ok(record.meta),
+            None => bail!(FSError::BadMeta(fname)),
+        }
+    }
+
+    /// Rebuilds the metadata record of a file from the file itself and what is stored
+    /// with it, for when the record is missing or corrupt. What can't be found out again,
+    /// such as the format the file was converted from, is left unknown.
+    pub async fn rebuild_meta(&self, normalized_id: &NormalizedId) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+
+        self.rebuild_record(&key, &fname).await
+    }
+
+    /// Stores a file derived from the given file, such as a storyboard, next to it.
+    /// It is encrypted with the file's data key, if the file is encrypted.
+    /// The file itself must already have been written.
+    pub async fn write_attachment(
+        &self,
+        normalized_id: &NormalizedId,
+        name: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        _ = self.backend.head(&key).await?;
+        ensure!(
+            !name.contains(std::path::is_separator),
+            FSError::DirectoryTraversal(fname)
+        );
+
+        let data = match self.data_key(&key, &fname).await? {
+            Some(data_key) => Cow::Owned(data_key.encrypt(name, data)?),
+            None => Cow::Borrowed(data),
+        };
+        self.put_attachment(&key, name, &data).await?;
+        self.settle(&key, true).await
+    }
+
+    /// Retrieves a file stored with [`write_attachment`](Self::write_attachment).
+    /// Like [`read`](Self::read), the file ID is held shared until the reader is dropped.
+    pub async fn read_attachment(
+        &self,
+        normalized_id: &NormalizedId,
+        name: &str,
+    ) -> Result<impl AsyncRead> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let handle = self.lock_shared(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        ensure!(
+            !name.contains(std::path::is_separator),
+            FSError::DirectoryTraversal(fname)
+        );
+
+        Ok(Guarded {
+            inner: self.get_decrypted(&key, Some(name), &fname, None).await?,
+            _handle: handle,
+        })
+    }
+
+    /// Replaces a stored file, its media facts and its attachments with new versions.
+    ///
+    /// Each new object is only visible once it has been stored in full, so readers only
+    /// ever see the old version of each or the new one. The file itself is swapped last,
+    /// right after the new checksums are recorded, and then attachments the new version
+    /// doesn't have are removed. The retained original, if there is one, is always kept.
+    /// An encrypted file stays encrypted with the same data key, and fails with
+    /// [`FSError::KeyLost`] if the key is gone.
+    pub async fn replace(
+        &self,
+        normalized_id: &NormalizedId,
+        data: &[u8],
+        media: &MediaInfo,
+        attachments: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        _ = self.backend.head(&key).await?;
+        ensure!(
+            attachments
+                .iter()
+                .all(|(name, _)| !name.contains(std::path::is_separator)),
+            FSError::DirectoryTraversal(fname)
+        );
+
+        // Fails if the file is encrypted but its key is gone, rather than storing the new version unencrypted.
+        let data_key = self.data_key(&key, &fname).await?;
+        let seal = |name: &str, data: &[u8]| match &data_key {
+            Some(data_key) => data_key.encrypt(name, data),
+            None => Ok(data.to_vec()),
+        };
+        let sealed = seal("", data)?;
+
+        let mut checksums = Checksums {
+            file: Some(checksum(&sealed)),
+            attachments: BTreeMap::new(),
+        };
+        let old = self.read_checksums(&key).await?;
+        for kept in [ORIGINAL_NAME, KEY_NAME] {
+            if let Some(sum) = old.attachments.get(kept) {
+                _ = checksums.attachments.insert(kept.to_owned(), sum.clone());
+            }
+        }
+
+        let meta = match self.read_record(&key, &fname).await? {
+            Some(record) => FileMeta {
+                media: media.clone(),
+                ..record.meta
+            },
+            None => self.meta_from_file(&key, &fname, media.clone()).await?,
+        };
+        let meta = serde_json::to_vec(&MetaRecord::new(FileMeta {
+            size: data.len() as u64,
+            date_modified: Utc::now(),
+            ..meta
+        }))?;
+
+        for (name, attachment) in attachments {
+            let attachment = seal(name, attachment)?;
+            self.backend
+                .put(&Self::attachment_key(&key, name), &attachment)
+                .await?;
+            _ = checksums
+                .attachments
+                .insert(name.clone(), checksum(&attachment));
+        }
+        self.backend
+            .put(&Self::attachment_key(&key, META_NAME), &meta)
+            .await?;
+        _ = checksums
+            .attachments
+            .insert(META_NAME.to_owned(), checksum(&meta));
+        self.write_checksums(&key, &checksums).await?;
+        self.backend.put(&key, &sealed).await?;
+
+        let prefix = format!("{key}.");
+        for attachment_key in self.backend.list(&prefix).await? {
+            let name = &attachment_key[prefix.len()..];
+            let stale = name != META_NAME
+                && name != KEY_NAME
+                && name != ORIGINAL_NAME
+                && name != CHECKSUMS_NAME
+                && name != OWNER_NAME
+                && !attachments.iter().any(|(kept, _)| kept == name);
+            if stale {
+                self.backend.delete(&attachment_key).await?;
+            }
+        }
+
+        self.settle(&key, true).await
+    }
+
+    /// Deletes a file.
+    ///
+    /// A tombstone is stored next to the file first, so from then on it answers as
+    /// [`FSError::Gone`], and the deletion is recorded under [`DELETIONS_PREFIX`]. The file
+    /// and its attachments are only removed from the backend later, by [`collect`](Self::collect),
+    /// which finds them listed under [`UNCOLLECTED_PREFIX`].
+    /// The ID is never issued again. The file stops counting in its uploader's usage straight away.
+    pub async fn delete(&self, normalized_id: &NormalizedId) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        _ = self.backend.head(&key).await?;
+
+        self.delete_file(&key, fname).await
+    }
+
+    /// Makes a staged file permanent, so it is no longer deleted when it expires.
+    /// Claiming a file which has already been claimed does nothing.
+    /// Fails with [`FSError::Gone`] if the file has expired, even if it hasn't been swept yet.
+    pub async fn claim(&self, normalized_id: &NormalizedId) -> Result<FileMeta> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        self.ensure_live(&key, &fname).await?;
+        _ = self.backend.head(&key).await?;
+
+        let meta = match self.read_record(&key, &fname).await? {
+            Some(record) => record.meta,
+            None => bail!(FSError::BadMeta(fname)),
+        };
+        if meta.state == FileState::Claimed {
+            return Ok(meta);
+        }
+        ensure!(
+            meta.expires_at
+                .is_none_or(|expires_at| expires_at > Utc::now()),
+            FSError::Gone(fname)
+        );
+
+        let meta = FileMeta {
+            state: FileState::Claimed,
+            expires_at: None,
+            ..meta
+        };
+        self.put_attachment(
+            &key,
+            META_NAME,
+            &serde_json::to_vec(&MetaRecord::new(meta.clone()))?,
+        )
+        .await?;
+        self.settle(&key, true).await?;
+        // Only unlisted once the record says the file is claimed, so the sweeper
+        // never loses track of a file which is still staged.
+        self.backend
+            .delete(&format!("{STAGED_PREFIX}{fname}"))
+            .await?;
+
+        Ok(meta)
+    }
+
+    /// Deletes staged files which have expired without being claimed.
+    /// Meant to be run periodically in the background.
+    /// Returns how many files were deleted.
+    pub async fn sweep(&self) -> Result<usize> {
+        let mut swept = 0;
+        let now = Utc::now();
+        for key in self.backend.list(STAGED_PREFIX).await? {
+            let staging: Staging = match self.read_object(&key).await {
+                Ok(staging) => match serde_json::from_slice(&staging) {
+                    Ok(staging) => staging,
+                    // Left for an operator to look at, rather than holding up the rest.
+                    Err(e) => {
+                        tracing::warn!(key, "Skipping an unreadable staged file listing: {e}");
+                        continue;
+                    }
+                },
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            let id = match staging.id.parse() {
+                Ok(id) => id,
+                // Nothing which could be staged is stored under an invalid ID.
+                Err(_) => {
+                    self.backend.delete(&key).await?;
+                    continue;
+                }
+            };
+            if staging.expires_at <= now && self.expire(&id).await? {
+                swept += 1;
+            }
+        }
+
+        Ok(swept)
+    }
+
+    /// Deletes a staged file which has expired, and stops listing it as staged.
+    /// Returns whether the file was deleted, rather than having been claimed,
+    /// deleted or never written in full.
+    async fn expire(&self, normalized_id: &NormalizedId) -> Result<bool> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        let staged = format!("{STAGED_PREFIX}{fname}");
+
+        let live =
+            self.ensure_live(&key, &fname).await.is_ok() && self.backend.head(&key).await.is_ok();
+        // A claim interrupted after the record was written leaves the file listed.
+        let claimed = live
+            && self
+                .read_record(&key, &fname)
+                .await?
+                .is_some_and(|record| record.meta.state == FileState::Claimed);
+        let expired = live && !claimed;
+        if expired {
+            tracing::info!(id = fname, "Staged file expired without being claimed");
+            self.delete_file(&key, fname).await?;
+        }
+
+        self.backend.delete(&staged).await?;
+        Ok(expired)
+    }
+
+    /// Marks the file under `key` as deleted.
+    ///
+    /// The file ID must be held exclusively.
+    async fn delete_file(&self, key: &str, fname: String) -> Result<()> {
+        let deletion = Deletion {
+            id: fname,
+            deleted_at: Utc::now(),
+        };
+        // Listed before the tombstone is stored, so the collector never loses track of a
+        // deleted file. A listed file which has no tombstone is merely unlisted again.
+        self.list_uncollected(&deletion).await?;
+        self.backend
+            .put(
+                &Self::attachment_key(key, TOMBSTONE_NAME),
+                &serde_json::to_vec(&deletion)?,
+            )
+            .await?;
+        self.record_deletion(&deletion).await?;
+        self.settle(key, false).await
+    }
+
+    /// Removes deleted files and their attachments from the backend.
+    /// Only the files listed under [`UNCOLLECTED_PREFIX`] are looked at, and not the whole store.
+    /// Meant to be run periodically in the background.
+    /// Returns how many objects were removed, counting attachments.
+    pub async fn collect(&self) -> Result<usize> {
+        let mut removed = 0;
+        for key in self.backend.list(UNCOLLECTED_PREFIX).await? {
+            let id = key.strip_prefix(UNCOLLECTED_PREFIX).unwrap_or(&key).parse();
+            match id {
+                Ok(id) => removed += self.remove_deleted(&id).await?,
+                // Nothing which could be deleted is stored under an invalid ID.
+                Err(_) => self.backend.delete(&key).await?,
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Moves files the backend now places elsewhere, such as after a volume was added.
+    /// Meant to be run in the background while the store is in use.
+    /// Returns how many objects were moved, counting attachments.
+    pub async fn rebalance(&self) -> Result<usize> {
+        self.backend.rebalance().await
+    }
+
+    /// Wraps the data key of every encrypted file with the current master key, where it was
+    /// wrapped with another. Only the wrapped keys are written, and never the files
+    /// themselves, so rotating the master key is cheap. Once this has run, the master keys
+    /// before the current one can be retired.
+    /// Returns how many data keys were wrapped afresh.
+    pub async fn rewrap(&self) -> Result<usize> {
+        let mut rewrapped = 0;
+        for id in self.list().await? {
+            if self.rewrap_file(&id).await? {
+                rewrapped += 1;
+            }
+        }
+
+        Ok(rewrapped)
+    }
+
+    /// Wraps the data key of a file with the current master key, if it was wrapped with another.
+    /// Returns whether it was.
+    async fn rewrap_file(&self, normalized_id: &NormalizedId) -> Result<bool> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        if self.ensure_live(&key, &fname).await.is_err() {
+            return Ok(false);
+        }
+
+        let wrapped_key = match self.read_key(&key, &fname).await? {
+            Some(wrapped_key) => wrapped_key,
+            None => return Ok(false),
+        };
+        let wrapped_key = match self.keyring.rewrap(&wrapped_key, &fname)? {
+            Some(wrapped_key) => wrapped_key,
+            None => return Ok(false),
+        };
+
+        self.put_attachment(&key, KEY_NAME, &serde_json::to_vec(&wrapped_key)?)
+            .await?;
+        self.settle(&key, true).await?;
+        Ok(true)
+    }
+
+    /// Lists the files in the store in the order they are stored in, a page at a time. Files
+    /// whose IDs are of the same version are in ID order, but those of different versions
+    /// are interleaved, since each version is [chunked](Self::chunk_path) on its body.
+    ///
+    /// Only files whose IDs start with `prefix` are listed, and only those after the
+    /// file named by `cursor`, which is the `next` cursor of the page before. At most
+    /// `limit` files are listed. Deleted files aren't listed, even before they are collected.
+    /// Everything listed is read from the metadata records, and not from the files.
+    pub async fn inventory(
+        &self,
+        prefix: &str,
+        cursor: Option<&NormalizedId>,
+        limit: usize,
+    ) -> Result<InventoryPage> {
+        // The store is listed from where the cursor's file is, rather than from the start.
+        let cursor = cursor.map(|cursor| Self::chunk_path(cursor).0);
+        let mut files = Vec::new();
+        let mut last: Option<NormalizedId> = None;
+
+        for listing in Self::listing_prefixes(prefix) {
+            let mut start_after = cursor.clone();
+            loop {
+                let keys = self
+                    .backend
+                    .list_page(&listing, start_after.as_deref(), INVENTORY_LISTING_SIZE)
+                    .await?;
+                for key in &keys {
+                    let id = match Self::parse_key(key) {
+                        Some((id, None)) if id.as_str().starts_with(prefix) => id,
+                        _ => continue,
+                    };
+                    // There is a file after a full page, so the page has a next one.
+                    if files.len() >= limit {
+                        return Ok(InventoryPage {
+                            files,
+                            next: last.map(|id| id.to_string()),
+                        });
+                    }
+
+                    if let Some(record) = self.inventory_record(&id).await? {
+                        files.push(record);
+                    }
+                    last = Some(id);
+                }
+
+                match keys.len() < INVENTORY_LISTING_SIZE {
+                    true => break,
+                    false => start_after = keys.last().cloned(),
+                }
+            }
+        }
+
+        Ok(InventoryPage { files, next: None })
+    }
+
+    /// What the inventory lists about a file, or nothing if it has been deleted since
+    /// the store was listed. Files whose metadata records can't be read are listed
+    /// from the files themselves, without their kind.
+    async fn inventory_record(
+        &self,
+        normalized_id: &NormalizedId,
+    ) -> Result<Option<InventoryRecord>> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_shared(normalized_id.as_str()).await;
+        if self.ensure_live(&key, &fname).await.is_err() {
+            return Ok(None);
+        }
+
+        let meta = match self.read_record(&key, &fname).await? {
+            Some(record) => record.meta,
+            None => match self
+                .meta_from_file(&key, &fname, MediaInfo::default())
+                .await
+            {
+                Ok(meta) => meta,
+                Err(e) if is_not_found(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            },
+        };
+
+        Ok(Some(InventoryRecord {
+            name: meta.name,
+            size: meta.size,
+            kind: meta.media.kind,
+            date_created: meta.date_created,
+            date_modified: meta.date_modified,
+            state: meta.state,
+        }))
+    }
+
+    /// Checks every file and attachment in the store against its recorded checksum.
+    ///
+    /// Objects which don't match are moved under [`QUARANTINE_PREFIX`], so they are
+    /// no longer served, except for the data keys of encrypted files, which are only
+    /// reported, since their files can't be read at all without them. Objects which have a checksum but aren't stored are reported
+    /// as missing, and objects which belong to no file, or which have no checksum
+    /// though the rest of their file does, as stray. Files stored before checksums
+    /// were recorded have theirs recorded now. Deleted files are left to the collector, and
+    /// listed for it again if they aren't, such as those deleted before deleted files were listed.
+    pub async fn scrub(&self) -> Result<ScrubReport> {
+        let mut report = ScrubReport {
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            checked: 0,
+            recorded: 0,
+            corrupt: Vec::new(),
+            missing: Vec::new(),
+            stray: Vec::new(),
+            rebuilt: Vec::new(),
+        };
+
+        let mut ids = BTreeSet::new();
+        let mut tombstoned = Vec::new();
+        for key in self.backend.list("").await? {
+            let bookkeeping = [
+                DELETIONS_PREFIX,
+                UNCOLLECTED_PREFIX,
+                QUARANTINE_PREFIX,
+                USAGE_PREFIX,
+                STAGED_PREFIX,
+            ];
+            if bookkeeping.iter().any(|prefix| key.starts_with(prefix)) {
+                continue;
+            }
+            match Self::parse_key(&key) {
+                Some((id, Some(TOMBSTONE_NAME))) => tombstoned.push(id),
+                Some((id, _)) => _ = ids.insert(id),
+                None => report.stray.push(key),
+            }
+        }
+
+        for id in ids {
+            self.scrub_file(&id, &mut report).await?;
+        }
+        for id in tombstoned {
+            self.relist_uncollected(&id).await?;
+        }
+
+        report.finished_at = Utc::now();
+        Ok(report)
+    }
+
+    /// Checks a file and its attachments against their checksums, adding what is found to the report.
+    async fn scrub_file(
+        &self,
+        normalized_id: &NormalizedId,
+        report: &mut ScrubReport,
+    ) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        // Held exclusively, since corrupted objects are moved.
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        if self.ensure_live(&key, &fname).await.is_err() {
+            return Ok(());
+        }
+
+        // Listed again now the file is held, since it may have changed since the store was walked.
+        let mut stored = BTreeMap::new();
+        for object in self.backend.list(&key).await? {
+            if object == key {
+                _ = stored.insert(object, None);
+            } else if let Some(name) = object.strip_prefix(&format!("{key}.")) {
+                _ = stored.insert(object.clone(), Some(name.to_owned()));
+            }
+        }
+
+        // Ownership changes as the file is counted, so it has no checksum of its own.
+        _ = stored.remove(&Self::attachment_key(&key, OWNER_NAME));
+        let checksums_key = Self::attachment_key(&key, CHECKSUMS_NAME);
+        if stored.remove(&checksums_key).is_none() {
+            // Stored before checksums were recorded, so all there is to do is record them now.
+            let mut checksums = Checksums::default();
+            for (object, name) in &stored {
+                let sum = checksum(&self.read_object(object).await?);
+                match name {
+                    None => checksums.file = Some(sum),
+                    Some(name) => _ = checksums.attachments.insert(name.clone(), sum),
+                }
+                report.recorded += 1;
+            }
+            if !stored.is_empty() {
+                self.write_checksums(&key, &checksums).await?;
+            }
+        } else {
+            self.scrub_objects(&key, stored, report).await?;
+        }
+
+        self.scrub_record(&key, &fname, report).await
+    }
+
+    /// Checks the objects stored for the file under `key` against their checksums.
+    async fn scrub_objects(
+        &self,
+        key: &str,
+        mut stored: BTreeMap<String, Option<String>>,
+        report: &mut ScrubReport,
+    ) -> Result<()> {
+        let checksums = self.read_checksums(key).await?;
+        let expected = checksums
+            .file
+            .iter()
+            .map(|sum| (key.to_owned(), sum))
+            .chain(
+                checksums
+                    .attachments
+                    .iter()
+                    .map(|(name, sum)| (Self::attachment_key(key, name), sum)),
+            );
+        for (object, sum) in expected {
+            if stored.remove(&object).is_none() {
+                tracing::warn!(key = object, "Checksummed object is missing");
+                report.missing.push(object);
+                continue;
+            }
+
+            report.checked += 1;
+            let data = self.read_object(&object).await?;
+            if checksum(&data) != *sum && object == Self::attachment_key(key, KEY_NAME) {
+                tracing::error!(key = object, "Data key doesn't match its checksum");
+                report.corrupt.push(object);
+            } else if checksum(&data) != *sum {
+                tracing::error!(
+                    key = object,
+                    "Object doesn't match its checksum, quarantining it"
+                );
+                self.backend
+                    .put(&format!("{QUARANTINE_PREFIX}{object}"), &data)
+                    .await?;
+                self.backend.delete(&object).await?;
+                report.corrupt.push(object);
+            }
+        }
+
+        report.stray.extend(stored.into_keys());
+        Ok(())
+    }
+
+    /// Rebuilds the metadata record of the file under `key` if it is missing or corrupt,
+    /// and upgrades it if it was written by an older version.
+    async fn scrub_record(&self, key: &str, fname: &str, report: &mut ScrubReport) -> Result<()> {
+        let record = self.read_record(key, fname).await?;
+        if record
+            .as_ref()
+            .is_some_and(|record| record.version >= META_VERSION)
+        {
+            return Ok(());
+        }
+        // Nothing can be rebuilt from a file which is itself missing or was quarantined.
+        if self.backend.head(key).await.is_err() {
+            return Ok(());
+        }
+
+        match record {
+            Some(record) => {
+                let record = MetaRecord::new(record.meta);
+                self.put_attachment(key, META_NAME, &serde_json::to_vec(&record)?)
+                    .await?;
+                self.settle(key, true).await?;
+            }
+            None => {
+                tracing::warn!(key, "Metadata record is missing or corrupt, rebuilding it");
+                match self.rebuild_record(key, fname).await {
+                    // Left reported as missing or corrupt, since nothing can be done about it here.
+                    Err(e)
+                        if matches!(
+                            e.downcast_ref(),
+                            Some(FSError::KeyLost(_) | FSError::Corrupt(_))
+                        ) =>
+                    {
+                        tracing::error!(key, "{e}");
+                        return Ok(());
+                    }
+                    rebuilt => rebuilt?,
+                }
+            }
+        }
+
+        let meta_key = Self::attachment_key(key, META_NAME);
+        report.missing.retain(|object| *object != meta_key);
+        report.rebuilt.push(meta_key);
+        Ok(())
+    }
+
+    /// Removes a deleted file and everything stored next to it, leaving its tombstone until last,
+    /// and then stops listing it as uncollected.
+    /// Returns how many objects were removed.
+    async fn remove_deleted(&self, normalized_id: &NormalizedId) -> Result<usize> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        let tombstone = Self::attachment_key(&key, TOMBSTONE_NAME);
+        let uncollected = format!("{UNCOLLECTED_PREFIX}{fname}");
+
+        // A deletion interrupted before the tombstone was stored leaves the file listed,
+        // and so does a collection interrupted after it was removed.
+        match self.backend.head(&tombstone).await {
+            Ok(_) => (),
+            Err(e) if is_not_found(&e) => {
+                self.backend.delete(&uncollected).await?;
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        }
+
+        // A crash between storing the tombstone and recording the deletion leaves it unrecorded,
+        // and the ID must be recorded before the last trace of it goes.
+        if !self.deleted.contains(&fname) {
+            let deletion = match serde_json::from_slice(&self.read_object(&tombstone).await?) {
+                Ok(deletion) => deletion,
+                Err(_) => Deletion {
+                    id: fname.clone(),
+                    deleted_at: Utc::now(),
+                },
+            };
+            self.record_deletion(&deletion).await?;
+        }
+        // Likewise for taking the file out of its uploader's usage.
+        self.settle(&key, false).await?;
+
+        let mut removed = 0;
+        for object in self.backend.list(&key).await? {
+            let belongs = object == key || object.starts_with(&format!("{key}."));
+            if belongs && object != tombstone {
+                self.backend.delete(&object).await?;
+                removed += 1;
+            }
+        }
+        self.backend.delete(&tombstone).await?;
+        self.backend.delete(&uncollected).await?;
+
+        Ok(removed + 1)
+    }
+
+    /// Reads the file under `key`, or the named attachment of it, decrypting it if the file
+    /// is encrypted. Only the given range of bytes of it is read, if one is given, and as
+    /// little of it as needs decrypting to get to them.
+    async fn get_decrypted(
+        &self,
+        key: &str,
+        name: Option<&str>,
+        fname: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectReader> {
+        let data_key = match self.data_key(key, fname).await? {
+            Some(data_key) => data_key,
+            None => return self.get_verified(key, name, fname, range).await,
+        };
+        let object = match name {
+            Some(name) => Self::attachment_key(key, name),
+            None => key.to_owned(),
+        };
+        let decrypted = |e: eyre::Report| {
+            tracing::error!(key = object, "{e}");
+            FSError::Corrupt(fname.to_owned())
+        };
+
+        // Verifying a read needs the whole object anyway.
+        let range = match range {
+            Some(range) if !self.verify_reads => range,
+            range => {
+                let mut data = Vec::new();
+                _ = self
+                    .get_verified(key, name, fname, None)
+                    .await?
+                    .read_to_end(&mut data)
+                    .await?;
+                let data = data_key
+                    .decrypt(name.unwrap_or(""), &data)
+                    .map_err(decrypted)?;
+                let data = match range {
+                    Some(range) => {
+                        let range = crate::storage::clamp(range, data.len() as u64);
+                        data[range.start as usize..range.end as usize].to_vec()
+                    }
+                    None => data,
+                };
+                return Ok(Box::new(std::io::Cursor::new(data)));
+            }
+        };
+
+        let size = self.backend.head(&object).await?.size;
+        let range = crate::storage::clamp(range, encryption::plaintext_len(size));
+        if range.is_empty() {
+            return Ok(Box::new(std::io::Cursor::new(Vec::new())));
+        }
+        let chunks = encryption::chunk_span(&range);
+        let mut header = Vec::new();
+        _ = self
+            .backend
+            .get(&object, Some(0..encryption::HEADER_LEN))
+            .await?
+            .read_to_end(&mut header)
+            .await?;
+        let mut sealed = Vec::new();
+        _ = self
+            .backend
+            .get(&object, Some(encryption::sealed_range(&chunks)))
+            .await?
+            .read_to_end(&mut sealed)
+            .await?;
+
+        let data = data_key
+            .decrypt_chunks(
+                name.unwrap_or(""),
+                &header,
+                chunks.start,
+                &sealed,
+                encryption::chunk_count(size),
+            )
+            .map_err(decrypted)?;
+        let skip = range.start - chunks.start * encryption::CHUNK_SIZE;
+        let data = data[skip as usize..(skip + range.end - range.start) as usize].to_vec();
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+
+    /// The data key of the file under `key`, or nothing if it isn't encrypted.
+    /// Fails with [`FSError::KeyLost`] if it is encrypted but its data key is gone.
+    async fn data_key(&self, key: &str, fname: &str) -> Result<Option<DataKey>> {
+        match self.read_key(key, fname).await? {
+            Some(wrapped_key) => Ok(Some(self.keyring.unwrap(&wrapped_key, fname)?)),
+            // Told by the file itself, so a lost key is never taken for the file being unencrypted.
+            None => {
+                let mut header = Vec::new();
+                _ = self
+                    .backend
+                    .get(key, Some(0..encryption::HEADER_LEN))
+                    .await?
+                    .read_to_end(&mut header)
+                    .await?;
+                ensure!(
+                    !encryption::is_encrypted(&header),
+                    FSError::KeyLost(fname.to_owned())
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads the wrapped data key of the file under `key`, or nothing if it has none.
+    /// Fails with [`FSError::KeyLost`] if it can't be read.
+    async fn read_key(&self, key: &str, fname: &str) -> Result<Option<WrappedKey>> {
+        match self.read_object(&Self::attachment_key(key, KEY_NAME)).await {
+            Ok(wrapped_key) => match serde_json::from_slice(&wrapped_key) {
+                Ok(wrapped_key) => Ok(Some(wrapped_key)),
+                Err(_) => bail!(FSError::KeyLost(fname.to_owned())),
+            },
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the file under `key`, or the named attachment of it, checking it against
+    /// its recorded checksum first if reads are verified. Only the given range of bytes
+    /// of it is read, if one is given, unless the whole of it is needed to check it.
+    async fn get_verified(
+        &self,
+        key: &str,
+        name: Option<&str>,
+        fname: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectReader> {
+        let object = match name {
+            Some(name) => Self::attachment_key(key, name),
+            None => key.to_owned(),
+        };
+        if !self.verify_reads {
+            return self.backend.get(&object, range).await;
+        }
+
+        let data = self.read_object(&object).await?;
+        let checksums = self.read_checksums(key).await?;
+        let expected = match name {
+            Some(name) => checksums.attachments.get(name),
+            None => checksums.file.as_ref(),
+        };
+        // Objects stored before checksums were recorded can't be checked until the scrubber records them.
+        if expected.is_some_and(|sum| *sum != checksum(&data)) {
+            tracing::error!(key = object, "Object doesn't match its checksum");
+            bail!(FSError::Corrupt(fname.to_owned()));
+        }
+
+        let data = match range {
+            Some(range) => {
+                let range = crate::storage::clamp(range, data.len() as u64);
+                data[range.start as usize..range.end as usize].to_vec()
+            }
+            None => data,
+        };
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+
+    /// Reads the metadata record of the file under `key`, or nothing if it is missing or can't be read.
+    /// Records from before they were versioned hold only the media facts, and the rest is
+    /// filled in from the file, as version 0.
+    async fn read_record(&self, key: &str, fname: &str) -> Result<Option<MetaRecord>> {
+        let record = match self
+            .read_object(&Self::attachment_key(key, META_NAME))
+            .await
+        {
+            Ok(record) => record,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let value: serde_json::Value = match serde_json::from_slice(&record) {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        };
+        if value.get("version").is_some() {
+            return Ok(serde_json::from_value(value).ok());
+        }
+        let media = match serde_json::from_value(value) {
+            Ok(media) => media,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(MetaRecord {
+            version: 0,
+            meta: self.meta_from_file(key, fname, media).await?,
+        }))
+    }
+
+    /// Makes up the metadata record of the file under `key` from the file itself,
+    /// and what is stored about it elsewhere.
+    async fn meta_from_file(&self, key: &str, fname: &str, media: MediaInfo) -> Result<FileMeta> {
+        let object = self.backend.head(key).await?;
+        let uploader = self.read_owner(key).await?.map(|owner| owner.uploader);
+        let expires_at = match self.read_object(&format!("{STAGED_PREFIX}{fname}")).await {
+            Ok(staging) => Some(serde_json::from_slice::<Staging>(&staging)?.expires_at),
+            Err(e) if is_not_found(&e) => None,
+            Err(e) => return Err(e),
+        };
+
+        Ok(FileMeta {
+            name: fname.to_owned(),
+            size: object.size,
+            date_created: object.created.unwrap_or(object.modified),
+            date_modified: object.modified,
+            uploader,
+            state: match expires_at {
+                Some(_) => FileState::Staged,
+                None => FileState::Claimed,
+            },
+            expires_at,
+            media,
+        })
+    }
+
+    /// Rebuilds the metadata record of the file under `key` by probing the file again,
+    /// and looking at what is stored with it. An encrypted file is decrypted to be probed,
+    /// and fails with [`FSError::KeyLost`] if its data key is gone.
+    ///
+    /// The file ID must be held exclusively.
+    async fn rebuild_record(&self, key: &str, fname: &str) -> Result<()> {
+        let mut data = self.read_object(key).await?;
+        if let Some(data_key) = self.data_key(key, fname).await? {
+            data = data_key.decrypt("", &data).map_err(|e| {
+                tracing::error!(key, "{e}");
+                FSError::Corrupt(fname.to_owned())
+            })?;
+        }
+        let mut media = tokio::task::spawn_blocking(move || convert::probe(&data)).await??;
+
+        let prefix = format!("{key}.");
+        for object in self.backend.list(&prefix).await? {
+            let name = &object[prefix.len()..];
+            if name == storyboard::TRACK_NAME {
+                media.storyboard = true;
+            }
+            if let Some((purpose, width)) = profile_image::parse_rendition_name(name) {
+                media.purpose = Some(purpose);
+                media.renditions.push(width);
+            }
+        }
+        media.renditions.sort_unstable_by(|a, b| b.cmp(a));
+
+        let meta = self.meta_from_file(key, fname, media).await?;
+        self.put_attachment(key, META_NAME, &serde_json::to_vec(&MetaRecord::new(meta))?)
+            .await?;
+        self.settle(key, true).await
+    }
+
+    /// Stores an attachment of the file under `key` and records its checksum.
+    async fn put_attachment(&self, key: &str, name: &str, data: &[u8]) -> Result<()> {
+        let mut checksums = self.read_checksums(key).await?;
+        _ = checksums
+            .attachments
+            .insert(name.to_owned(), checksum(data));
+
+        self.backend
+            .put(&Self::attachment_key(key, name), data)
+            .await?;
+        self.write_checksums(key, &checksums).await
+    }
+
+    /// Reads the checksums of the file under `key`, which has none recorded
+    /// if it was stored before checksums were.
+    async fn read_checksums(&self, key: &str) -> Result<Checksums> {
+        match self
+            .read_object(&Self::attachment_key(key, CHECKSUMS_NAME))
+            .await
+        {
+            Ok(checksums) => Ok(serde_json::from_slice(&checksums)?),
+            Err(e) if is_not_found(&e) => Ok(Checksums::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the checksums of the file under `key`.
+    async fn write_checksums(&self, key: &str, checksums: &Checksums) -> Result<()> {
+        self.backend
+            .put(
+                &Self::attachment_key(key, CHECKSUMS_NAME),
+                &serde_json::to_vec(checksums)?,
+            )
+            .await
+    }
+
+    /// Retrieves how much an uploader currently has stored.
+    pub async fn usage(&self, uploader: &str) -> Result<Usage> {
+        let _handle = self.lock_shared(&format!("{USAGE_PREFIX}{uploader}")).await;
+        self.read_usage(uploader).await
+    }
+
+    /// Makes the file under `key` the uploader's, counting it as `size` bytes in their usage
+    /// if that fits in their quota. What is stored with it later is counted by [`settle`](Self::settle).
+    async fn charge(&self, key: &str, uploader: &str, quota: &Quota, size: u64) -> Result<()> {
+        let _handle = self
+            .lock_exclusive(&format!("{USAGE_PREFIX}{uploader}"))
+            .await;
+        let mut usage = self.read_usage(uploader).await?;
+        quota.admit(&usage, size)?;
+
+        // Ownership is recorded first, so a crash in between leaves the file counted
+        // for too little rather than the uploader for too much.
+        let owner = Owner {
+            uploader: uploader.to_owned(),
+            bytes: size,
+            counted: true,
+        };
+        self.backend
+            .put(
+                &Self::attachment_key(key, OWNER_NAME),
+                &serde_json::to_vec(&owner)?,
+            )
+            .await?;
+        usage.bytes += size;
+        usage.files += 1;
+        self.write_usage(uploader, &usage).await
+    }
+
+    /// Brings what the file under `key` counts as in its uploader's usage up to date with
+    /// what is stored for it, or takes it out of their usage if it isn't `live` any more.
+    /// Files without an uploader aren't counted.
+    ///
+    /// The file ID must be held exclusively.
+    async fn settle(&self, key: &str, live: bool) -> Result<()> {
+        let mut owner = match self.read_owner(key).await? {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+
+        let mut bytes = 0;
+        if live {
+            let bookkeeping = [CHECKSUMS_NAME, OWNER_NAME, TOMBSTONE_NAME]
+                .map(|name| Self::attachment_key(key, name));
+            for object in self.backend.list(key).await? {
+                let belongs = object == key || object.starts_with(&format!("{key}."));
+                if belongs && !bookkeeping.contains(&object) {
+                    bytes += self.backend.head(&object).await?.size;
+                }
+            }
+        }
+        if bytes == owner.bytes && live == owner.counted {
+            return Ok(());
+        }
+
+        let _handle = self
+            .lock_exclusive(&format!("{USAGE_PREFIX}{}", owner.uploader))
+            .await;
+        let mut usage = self.read_usage(&owner.uploader).await?;
+        usage.bytes = (usage.bytes + bytes).saturating_sub(owner.bytes);
+        match (owner.counted, live) {
+            (false, true) => usage.files += 1,
+            (true, false) => usage.files = usage.files.saturating_sub(1),
+            _ => (),
+        }
+
+        // As when charging, ownership is recorded first so a crash in between
+        // never counts anything twice.
+        owner.bytes = bytes;
+        owner.counted = live;
+        self.backend
+            .put(
+                &Self::attachment_key(key, OWNER_NAME),
+                &serde_json::to_vec(&owner)?,
+            )
+            .await?;
+        self.write_usage(&owner.uploader, &usage).await
+    }
+
+    /// Reads who uploaded the file under `key`, if anyone is recorded as having.
+    async fn read_owner(&self, key: &str) -> Result<Option<Owner>> {
+        match self
+            .read_object(&Self::attachment_key(key, OWNER_NAME))
+            .await
+        {
+            Ok(owner) => Ok(Some(serde_json::from_slice(&owner)?)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the usage of an uploader, who has none if they have never stored anything.
+    async fn read_usage(&self, uploader: &str) -> Result<Usage> {
+        match self.read_object(&format!("{USAGE_PREFIX}{uploader}")).await {
+            Ok(usage) => Ok(serde_json::from_slice(&usage)?),
+            Err(e) if is_not_found(&e) => Ok(Usage::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the usage of an uploader.
+    async fn write_usage(&self, uploader: &str, usage: &Usage) -> Result<()> {
+        self.backend
+            .put(
+                &format!("{USAGE_PREFIX}{uploader}"),
+                &serde_json::to_vec(usage)?,
+            )
+            .await
+    }
 
-unsafe impl Send for FileStore {}
-unsafe impl Sync for FileStore {}
+    /// Fails with [`FSError::Gone`] if the file has been deleted,
+    /// whether or not the collector has removed it yet.
+    async fn ensure_live(&self, key: &str, fname: &str) -> Result<()> {
+        let deleted = self.deleted.contains(fname)
+            || self
+                .backend
+                .head(&Self::attachment_key(key, TOMBSTONE_NAME))
+                .await
+                .is_ok();
+        ensure!(!deleted, FSError::Gone(fname.to_owned()));
+        Ok(())
+    }
 
-impl FileStore {
-    /// Create a new `FileStore` instance. All reads and writes
-    /// will be effectively jailed to the base path.
-    pub fn new(base_path: impl AsRef<Path>) -> Result<FileStore> {
-        let base_path = base_path.as_ref().to_owned();
-        if !base_path.is_dir() {
-            bail!("Provided base path is not a directory!");
+    /// Lists a file as staged under [`STAGED_PREFIX`], until it is claimed or expires.
+    async fn write_staging(&self, fname: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        let staging = Staging {
+            id: fname.to_owned(),
+            expires_at,
+        };
+        self.backend
+            .put(
+                &format!("{STAGED_PREFIX}{fname}"),
+                &serde_json::to_vec(&staging)?,
+            )
+            .await
+    }
+
+    /// Lists a deleted file under [`UNCOLLECTED_PREFIX`], until it is collected.
+    async fn list_uncollected(&self, deletion: &Deletion) -> Result<()> {
+        self.backend
+            .put(
+                &format!("{UNCOLLECTED_PREFIX}{}", deletion.id),
+                &serde_json::to_vec(deletion)?,
+            )
+            .await
+    }
+
+    /// Lists a deleted file as uncollected, unless it already is,
+    /// or has been collected since the store was walked.
+    async fn relist_uncollected(&self, normalized_id: &NormalizedId) -> Result<()> {
+        let (key, fname) = Self::chunk_path(normalized_id);
+        let _handle = self.lock_exclusive(normalized_id.as_str()).await;
+        match self
+            .backend
+            .head(&format!("{UNCOLLECTED_PREFIX}{fname}"))
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) if is_not_found(&e) => (),
+            Err(e) => return Err(e),
         }
-        Ok(FileStore {
-            base_path,
-            handles: DashMap::new(),
-        })
+
+        let deletion = match self
+            .read_object(&Self::attachment_key(&key, TOMBSTONE_NAME))
+            .await
+        {
+            Ok(deletion) => serde_json::from_slice(&deletion).unwrap_or(Deletion {
+                id: fname,
+                deleted_at: Utc::now(),
+            }),
+            Err(e) if is_not_found(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.list_uncollected(&deletion).await
     }
 
-    /// Write a file's contents into the filesystem.
-    /// Returns the serialized filename made with [`gen_file_name`](Self::gen_file_name).
-    pub async fn write(
-        &mut self,
-        normalized_id: &str,
-        mut payload: impl AsyncRead + Unpin,
-    ) -> Result<String> {
-        let (rel_path, fname) = Self::chunk_path(normalized_id);
-        let path = self.safe_canonicalize(&rel_path)?;
+    /// Records a deletion under [`DELETIONS_PREFIX`], where it is kept for good.
+    async fn record_deletion(&self, deletion: &Deletion) -> Result<()> {
+        self.backend
+            .put(
+                &format!("{DELETIONS_PREFIX}{}", deletion.id),
+                &serde_json::to_vec(deletion)?,
+            )
+            .await?;
+        _ = self.deleted.insert(deletion.id.clone());
 
-        ensure!(path.exists(), FSError::NameCollision(fname));
+        tracing::info!(
+            id = %deletion.id,
+            deleted_at = %deletion.deleted_at,
+            "File deleted"
+        );
+        Ok(())
+    }
 
-        // Check to see if the file ID is already being accessed
-        if self.handles.contains_key(normalized_id) {
+    /// Reads a whole object from the backend.
+    async fn read_object(&self, key: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        _ = self
+            .backend
+            .get(key, None)
+            .await?
+            .read_to_end(&mut data)
+            .await?;
+        Ok(data)
+    }
 
+    /// Waits until nothing is writing the given file ID, then holds it shared
+    /// until the returned handle is dropped.
+    async fn lock_shared(&self, normalized_id: &str) -> Handle {
+        let lock = self.lock(normalized_id);
+        Handle {
+            guard: Some(HandleGuard::Shared(lock.read_owned().await)),
+            id: normalized_id.to_owned(),
+            handles: self.handles.clone(),
         }
-        else {
-            // Tell the hashmap we have acquired a lock on the file.
-        }
+    }
 
-        let mut fd = tokio::fs::File::create(path).await?;
-        _ = tokio::io::copy(&mut payload, &mut fd).await?;
+    /// Waits until nothing else is reading or writing the given file ID,
+    /// then holds it exclusively until the returned handle is dropped.
+    async fn lock_exclusive(&self, normalized_id: &str) -> Handle {
+        let lock = self.lock(normalized_id);
+        Handle {
+            guard: Some(HandleGuard::Exclusive(lock.write_owned().await)),
+            id: normalized_id.to_owned(),
+            handles: self.handles.clone(),
+        }
+    }
 
-        Ok(fname)
+    /// The lock of the given file ID, added to the map if nothing holds it yet.
+    /// The map entry is released before the lock is waited on, so waiting never blocks other IDs.
+    fn lock(&self, normalized_id: &str) -> Arc<RwLock<()>> {
+        self.handles
+            .entry(normalized_id.to_owned())
+            .or_default()
+            .clone()
     }
 
-    /// Retrieves the given file from the filesystem.
-    /// Assumes that the input filename is already in
-    /// the format returned by `hash_name`.
-    pub async fn read(&self, normalized_id: &str) -> Result<impl AsyncRead> {
-        let (rel_path, fname) = Self::chunk_path(normalized_id);
-        let path = self.safe_canonicalize(&rel_path)?;
+    /// Lists the normalized IDs of every file in the store, in order.
+    /// Attachments and deleted files are left out, as is anything which isn't
+    /// where [`chunk_path`](Self::chunk_path) would place it.
+    pub async fn list(&self) -> Result<Vec<NormalizedId>> {
+        let mut ids = Vec::new();
+        let mut tombstoned = HashSet::new();
 
-        ensure!(path.exists(), FSError::NotFound(fname));
+        for key in self.backend.list("").await? {
+            match Self::parse_key(&key) {
+                Some((id, None)) => ids.push(id),
+                Some((id, Some(TOMBSTONE_NAME))) => _ = tombstoned.insert(id),
+                _ => (),
+            }
+        }
 
-        Ok(tokio::fs::File::open(path).await?)
+        ids.retain(|id| !self.deleted.contains(id.as_str()) && !tombstoned.contains(id));
+        ids.sort_unstable();
+        Ok(ids)
     }
 
-    /// Safely canonicalizes a given path relative to the base path.
-    ///
-    /// The `safe_canonicalize` function takes a `Path` as input and attempts to join it
-    /// to the base path, eliminating symbolic links and normalizing all intermediate components.
-    /// It performs additional safety checks ensuring that the destination file is present,
-    /// not a symbolic link, and actually exists within the base_path.
-    fn safe_canonicalize(&self, path: &Path) -> Result<PathBuf> {
-        let file_name = path
-            .file_name()
-            .map(|s| s.to_string_lossy().into_owned())
-            .unwrap_or_else(|| format!("Path `{}` had no valid filename!", path.display()));
-
-        let full_path = match self.base_path.join(path).canonicalize() {
-            Ok(p) => p,
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => {
-                    return Err(FSError::NotFound(file_name).into());
-                }
-                _ => return Err(e.into()),
-            },
+    /// Splits the key of a stored file or attachment into the file's normalized ID
+    /// and the attachment's name. Returns `None` for keys which aren't where
+    /// [`chunk_path`](Self::chunk_path) would place them.
+    fn parse_key(key: &str) -> Option<(NormalizedId, Option<&str>)> {
+        let name = key.rsplit('/').next()?;
+        let (id, attachment) = match name.split_once('.') {
+            Some((id, attachment)) => (id.parse::<NormalizedId>().ok()?, Some(attachment)),
+            None => (name.parse().ok()?, None),
         };
 
-        ensure!(!full_path.is_symlink(), FSError::IsSymlink(file_name));
-        ensure!(
-            full_path.starts_with(&self.base_path),
-            FSError::DirectoryTraversal(file_name)
-        );
-
-        Ok(full_path)
+        let chunked = key.len() == 12 + name.len() && key.starts_with(&Self::chunk_path(&id).0);
+        chunked.then_some((id, attachment))
     }
 
-    /// Generates a unique file name based on the given input.
-    ///
-    /// The `gen_file_name` function takes a generic `filename` as input and generates a unique file name based on it.
-    /// The generated file name is created by concatenating the little-endian byte representations of
-    ///
-    /// 1. A random UUID
-    /// 2. The current UNIX timestamp
-    /// 3. A random 32-bit unsigned integer
-    ///
-    /// and then Base64 encoding the entire byte array using a url-safe alphabet.
-    ///
-    fn generate_normal_id() -> String {
-        let fid = Uuid::new_v4();
-
-        let now = {
-            let start = SystemTime::now();
-            let since_the_epoch = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
-            since_the_epoch.as_secs().to_le_bytes()
-        };
+    /// The prefixes of the keys of every file whose ID starts with `prefix`, in order, so listing
+    /// the store can be narrowed down to them. IDs of every version are chunked on their body,
+    /// so a prefix which could be either of a version 0 ID or of a later one has a listing
+    /// prefix for each. None of them is a prefix of another, so no key is listed twice.
+    fn listing_prefixes(prefix: &str) -> Vec<String> {
+        let mut listings = vec![Self::listing_prefix(prefix, prefix)];
+        for version in 1..=NormalizedId::CURRENT_VERSION {
+            if let Some(body) = prefix.strip_prefix(&version.to_string()) {
+                listings.push(Self::listing_prefix(body, prefix));
+            }
+        }
 
-        let num = thread_rng().gen::<u32>().to_le_bytes();
+        listings.sort_unstable();
+        let mut prefixes: Vec<String> = Vec::new();
+        for listing in listings {
+            if !prefixes
+                .iter()
+                .any(|other| listing.starts_with(other.as_str()))
+            {
+                prefixes.push(listing);
+            }
+        }
+        prefixes
+    }
 
-        let bindat = [fid.as_bytes().as_slice(), &now, &num].concat();
+    /// The longest prefix every key of a file whose body starts with `body` shares,
+    /// given that its ID starts with `prefix`.
+    fn listing_prefix(body: &str, prefix: &str) -> String {
+        let mut listing = String::new();
+        for (index, c) in body.chars().take(9).enumerate() {
+            if index > 0 && index % 3 == 0 {
+                listing.push('/');
+            }
+            listing.push(c);
+        }
+        if body.chars().count() >= 9 {
+            listing.push('/');
+            listing.push_str(prefix);
+        }
+        listing
+    }
 
-        const BASE64: engine::GeneralPurpose =
-            engine::GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::NO_PAD);
-        BASE64.encode(bindat)
+    /// The key of a file attached to a stored file, such as its media facts sidecar.
+    /// Attachment names are joined to the ID with a `.`, which can never appear in a normalized ID.
+    fn attachment_key(key: &str, name: &str) -> String {
+        format!("{key}.{name}")
     }
 
-    /// The file name is split into the following path:
-    /// [First 3 chars]/[Next 3 chars]/[Next 3 chars]/[Entire Filename Unchanged]
-    ///
-    /// If the file name is "abc123def456.txt", the resulting path would be:
-    /// "abc/123/def/abc123def456.txt"
-    ///
-    /// The `chunk_path` function takes a file name as input and generates the path on-disk the file should be stored at.
-    /// The generated path is created by performing the following steps:
-    ///
-    /// 1. Calls the `gen_file_name` function to generate a unique file name based on the input.
-    /// 2. Splits the generated file name into chunks of 3 characters each.
-    /// 3. Constructs a path by joining the chunks with forward slashes ("/") in the following format:
-    ///    [First 3 chars]/[Next 3 chars]/[Next 3 chars]/[Entire Filename Unchanged]
-    ///
-    /// For example, if the file name is `abc123def456.txt`, the resulting path would be `abc/123/def/abc123def456.txt`
-    ///
-    /// This function is intended to be used exclusively in conjunction with [hash_name] and therefore will panic if fname is not long enough.
-    fn chunk_path(normalized_id: &str) -> (PathBuf, String) {
+    /// The key a file is stored under, `body[0..3]/body[3..6]/body[6..9]/id`, and its file name.
+    /// The chunks are taken from the [body](NormalizedId::body) of the ID, after the character
+    /// naming its version, which would otherwise put every ID of a version in the same directory.
+    /// For example, `1abc123def456…` is stored under `abc/123/def/1abc123def456…`.
+    fn chunk_path(normalized_id: &NormalizedId) -> (String, String) {
+        let (id, body) = (normalized_id.as_str(), normalized_id.body());
         (
-            Path::new(&format!(
-                "{}/{}/{}/{}",
-                &normalized_id[0..=2],
-                &normalized_id[3..=5],
-                &normalized_id[6..=8],
-                &normalized_id
-            ))
-            .to_owned(),
-            normalized_id.to_owned(),
+            format!("{}/{}/{}/{}", &body[0..=2], &body[3..=5], &body[6..=8], id),
+            id.to_owned(),
         )
     }
 }
 
+/// The BLAKE3 checksum of some data, in hex.
+fn checksum(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// Whether an error is [`FSError::NotFound`].
+pub(crate) fn is_not_found(e: &eyre::Report) -> bool {
+    matches!(e.downcast_ref::<FSError>(), Some(FSError::NotFound(_)))
+}
+
+/// A hold on a file ID, released when dropped. The ID's entry in the store's map
+/// is removed once nothing else holds or is waiting on it.
+#[derive(Debug)]
+struct Handle {
+    guard: Option<HandleGuard>,
+    id: String,
+    handles: Handles,
+}
+
+#[derive(Debug)]
+enum HandleGuard {
+    Shared(OwnedRwLockReadGuard<()>),
+    Exclusive(OwnedRwLockWriteGuard<()>),
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // The guard holds a reference to the lock, so it must go first.
+        drop(self.guard.take());
+        // Anyone else holding or waiting on the lock has their own reference to it.
+        _ = self
+            .handles
+            .remove_if(&self.id, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+/// A reader which keeps its file ID held until it is dropped.
+#[derive(Debug)]
+struct Guarded<R> {
+    inner: R,
+    _handle: Handle,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Guarded<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+/// The metadata of a stored file, as served by `GET /meta`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// The normalized ID of the file.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// When the file was stored.
+    pub date_created: DateTime<Utc>,
+    /// When the file was last changed.
+    pub date_modified: DateTime<Utc>,
+    /// Who uploaded the file, if the gateway said.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    /// Whether the file is still staged, or has been claimed.
+    #[serde(default)]
+    pub state: FileState,
+    /// When the file is deleted unless claimed before then, if it is still staged.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Facts about the file's content, captured when it was converted.
+    #[serde(flatten)]
+    pub media: MediaInfo,
+}
+
+/// Whether a stored file is permanent yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    /// Uploaded, but not yet used by anything, and deleted once it expires.
+    Staged,
+    /// Kept until it is deleted. Files stored before staging existed are all claimed.
+    #[default]
+    Claimed,
+}
+
+/// The metadata record kept next to a stored file.
+#[derive(Debug, Serialize, Deserialize)]
+struct MetaRecord {
+    /// The version of the record, which is [`META_VERSION`] for those written now.
+    version: u32,
+    #[serde(flatten)]
+    meta: FileMeta,
+}
+
+impl MetaRecord {
+    /// A record of the current version.
+    fn new(meta: FileMeta) -> MetaRecord {
+        MetaRecord {
+            version: META_VERSION,
+            meta,
+        }
+    }
+}
+
+/// The checksums of a stored file and its attachments, recorded as each is written.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checksums {
+    /// The checksum of the file itself.
+    file: Option<String>,
+    /// The checksums of the attachments, by name.
+    attachments: BTreeMap<String, String>,
+}
+
+/// Who uploaded a stored file, and what it is counted as in their usage.
+#[derive(Debug, Serialize, Deserialize)]
+struct Owner {
+    uploader: String,
+    /// The bytes the file and its attachments are counted as.
+    bytes: u64,
+    /// Whether the file is counted as one of the uploader's files, which it stops being once deleted.
+    counted: bool,
+}
+
+/// What a run of [`FileStore::scrub`] found.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrubReport {
+    /// When the run started.
+    pub started_at: DateTime<Utc>,
+    /// When the run finished.
+    pub finished_at: DateTime<Utc>,
+    /// How many objects were checked against their checksums.
+    pub checked: usize,
+    /// How many objects had no checksum yet, and had one recorded.
+    pub recorded: usize,
+    /// The keys of objects which didn't match their checksums, and were quarantined.
+    pub corrupt: Vec<String>,
+    /// The keys of objects which have a checksum but aren't stored.
+    pub missing: Vec<String>,
+    /// The keys of objects which belong to no file, or have no checksum.
+    pub stray: Vec<String>,
+    /// The keys of metadata records which were missing, corrupt or out of date, and were rebuilt.
+    pub rebuilt: Vec<String>,
+}
+
+impl ScrubReport {
+    /// Whether nothing was found wrong.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty() && self.stray.is_empty()
+    }
+}
+
+/// A file in the store, as listed by [`FileStore::inventory`].
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryRecord {
+    /// The normalized ID of the file.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The MIME type of the file, or empty if its metadata record can't be read.
+    pub kind: String,
+    /// When the file was stored.
+    pub date_created: DateTime<Utc>,
+    /// When the file was last changed.
+    pub date_modified: DateTime<Utc>,
+    /// Whether the file is still staged, or has been claimed.
+    pub state: FileState,
+}
+
+/// A page of the store's inventory.
+#[derive(Debug, Serialize)]
+pub struct InventoryPage {
+    /// The files on the page, in the order they are stored in.
+    pub files: Vec<InventoryRecord>,
+    /// The cursor of the next page, or `None` if this is the last.
+    pub next: Option<String>,
+}
+
+/// A staged file, as listed under [`STAGED_PREFIX`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Staging {
+    id: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// A record of a deleted file, kept in its tombstone and under [`DELETIONS_PREFIX`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Deletion {
+    id: String,
+    deleted_at: DateTime<Utc>,
+}
+
 /// Errors that can be returned by the FileStore.
 #[derive(Debug)]
 pub enum FSError {
-    /// Indicates the [FileStore::hash_name] method has
-    /// failed and produced a non-unique path name.
+    /// Indicates a file with the same ID is already stored, or being written.
     NameCollision(String),
     /// Indicates the requested file does not exist on disk.
     NotFound(String),
+    /// Indicates the requested file existed but has been deleted.
+    Gone(String),
+    /// Indicates the requested file doesn't match its recorded checksum.
+    Corrupt(String),
+    /// Indicates the metadata record of the requested file is missing or can't be read.
+    BadMeta(String),
+    /// Indicates the requested file is encrypted, but its data key is lost.
+    KeyLost(String),
     /// Indicates the requested file would result in traversal outside
     /// the base path for file storage.
     DirectoryTraversal(String),
@@ -201,6 +1908,22 @@ impl Display for FSError {
                 write!(f, "The file identifier `{}` is already in use", name)
             }
             Self::NotFound(name) => write!(f, "The file identifier `{}` was not found", name),
+            Self::Gone(name) => write!(f, "The file identifier `{}` has been deleted", name),
+            Self::Corrupt(name) => write!(
+                f,
+                "The file identifier `{}` doesn't match its checksum",
+                name
+            ),
+            Self::BadMeta(name) => write!(
+                f,
+                "The file identifier `{}` has no readable metadata record",
+                name
+            ),
+            Self::KeyLost(name) => write!(
+                f,
+                "The file identifier `{}` is encrypted, but its data key is lost",
+                name
+            ),
             Self::DirectoryTraversal(name) => write!(
                 f,
                 "The file identifier `{}` points to a file located outside the base path",
@@ -216,3 +1939,885 @@ impl Display for FSError {
 }
 
 impl Error for FSError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encryption::EncryptionConfig, quota::QuotaExceeded, storage::MemoryBackend};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use encryption::CHUNK_SIZE;
+
+    const V0: &str = "abc123def456ghi789jkl012mno345pqr678st";
+
+    /// A lossless WebP image of a single pixel, which can be probed again.
+    const PIXEL: &str = "UklGRhoAAABXRUJQVlA4TA0AAAAvAAAAEAcQERGIiP4HAA==";
+
+    async fn store() -> FileStore {
+        FileStore::new(Arc::new(MemoryBackend::default()))
+            .await
+            .unwrap()
+    }
+
+    /// A store in `backend` with the given master keys, each 32 copies of a byte,
+    /// which encrypts new files with the `current` one.
+    async fn encrypted(
+        backend: &Arc<MemoryBackend>,
+        keys: &[(&str, u8)],
+        current: &str,
+    ) -> FileStore {
+        let keyring = Keyring::new(&EncryptionConfig {
+            master_keys: keys
+                .iter()
+                .map(|(kid, byte)| (kid.to_string(), STANDARD.encode([*byte; 32])))
+                .collect(),
+            current_key: Some(current.to_owned()),
+        })
+        .unwrap();
+        FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .keyring(keyring)
+    }
+
+    /// A version 0 ID ending in `n`.
+    fn id(n: usize) -> NormalizedId {
+        format!("{}{n:04}", &V0[..V0.len() - 4]).parse().unwrap()
+    }
+
+    /// Contents which differ from chunk to chunk.
+    fn contents(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Reads a whole file, or the given range of it.
+    async fn read(
+        store: &FileStore,
+        id: &NormalizedId,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        _ = match range {
+            Some(range) => {
+                store
+                    .read_range(id, range)
+                    .await?
+                    .read_to_end(&mut data)
+                    .await?
+            }
+            None => store.read(id).await?.read_to_end(&mut data).await?,
+        };
+        Ok(data)
+    }
+
+    /// Whether a read failed with the given kind of error.
+    fn failed<T>(result: Result<T>, kind: fn(&FSError) -> bool) -> bool {
+        result.is_err_and(|e| e.downcast_ref().is_some_and(kind))
+    }
+
+    /// Lists the whole inventory a page of `limit` files at a time, by name.
+    async fn inventory(store: &FileStore, prefix: &str, limit: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut cursor: Option<NormalizedId> = None;
+        loop {
+            let page = store
+                .inventory(prefix, cursor.as_ref(), limit)
+                .await
+                .unwrap();
+            assert!(page.files.len() <= limit);
+            names.extend(page.files.into_iter().map(|file| file.name));
+            match page.next {
+                Some(next) => cursor = Some(next.parse().unwrap()),
+                None => return names,
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_path() {
+        let v0: NormalizedId = V0.parse().unwrap();
+        let v1: NormalizedId = format!("1{V0}").parse().unwrap();
+        assert_eq!(FileStore::chunk_path(&v0).0, format!("abc/123/def/{V0}"));
+        // Chunked on the body, rather than on the version every such ID starts with.
+        assert_eq!(FileStore::chunk_path(&v1).0, format!("abc/123/def/1{V0}"));
+
+        for id in [v0, v1] {
+            let key = FileStore::chunk_path(&id).0;
+            assert_eq!(FileStore::parse_key(&key), Some((id.clone(), None)));
+            assert_eq!(
+                FileStore::parse_key(&FileStore::attachment_key(&key, META_NAME)),
+                Some((id, Some(META_NAME)))
+            );
+        }
+        assert_eq!(FileStore::parse_key(&format!("1ab/c12/3de/1{V0}")), None);
+        assert_eq!(
+            FileStore::parse_key(&format!("{DELETIONS_PREFIX}{V0}")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_listing_prefixes() {
+        assert_eq!(FileStore::listing_prefixes(""), [""]);
+        assert_eq!(FileStore::listing_prefixes("abc1"), ["abc/1"]);
+        assert_eq!(
+            FileStore::listing_prefixes("abc123def4"),
+            ["abc/123/def/abc123def4"]
+        );
+        // Either a version 0 ID starting with `1`, or a version 1 ID.
+        assert_eq!(FileStore::listing_prefixes("1"), [""]);
+        assert_eq!(FileStore::listing_prefixes("1abc1"), ["1ab/c1", "abc/1"]);
+        assert_eq!(
+            FileStore::listing_prefixes("1abc123def"),
+            ["1ab/c12/3de/1abc123def", "abc/123/def/1abc123def"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inventory_across_versions() {
+        let store = store().await;
+        let ids = [
+            format!("1{}", &V0[1..]),
+            format!("1{V0}"),
+            V0.to_owned(),
+            format!("1zzz{}", &V0[3..]),
+        ];
+        for id in &ids {
+            _ = store
+                .write(&id.parse().unwrap(), b"x", &MediaInfo::default(), &[], None)
+                .await
+                .unwrap();
+        }
+
+        // In the order the files are stored in, whatever the page size.
+        for limit in [1, 2, 3, 10] {
+            assert_eq!(inventory(&store, "", limit).await, ids);
+        }
+        assert_eq!(
+            inventory(&store, "1", 1).await,
+            [ids[0].as_str(), &ids[1], &ids[3]]
+        );
+        assert_eq!(inventory(&store, "1a", 1).await, [ids[1].as_str()]);
+        assert_eq!(inventory(&store, "1b", 1).await, [ids[0].as_str()]);
+        assert_eq!(inventory(&store, "abc", 1).await, [ids[2].as_str()]);
+        assert!(inventory(&store, "2", 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_round_trips() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = encrypted(&backend, &[("a", 1)], "a").await;
+        for (n, len) in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1].into_iter().enumerate() {
+            let id = id(n);
+            let data = contents(len);
+            let attachment = contents(len + 7);
+            _ = store
+                .write(
+                    &id,
+                    &data,
+                    &MediaInfo::default(),
+                    &[("thumb".to_owned(), attachment.clone())],
+                    None,
+                )
+                .await
+                .unwrap();
+
+            let key = FileStore::chunk_path(&id).0;
+            let stored = store.read_object(&key).await.unwrap();
+            assert!(encryption::is_encrypted(&stored));
+            assert_eq!(encryption::plaintext_len(stored.len() as u64), len);
+            assert_eq!(read(&store, &id, None).await.unwrap(), data);
+
+            let mut read_attachment = Vec::new();
+            _ = store
+                .read_attachment(&id, "thumb")
+                .await
+                .unwrap()
+                .read_to_end(&mut read_attachment)
+                .await
+                .unwrap();
+            assert_eq!(read_attachment, attachment);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_ranges() {
+        let backend = Arc::new(MemoryBackend::default());
+        let len = 3 * CHUNK_SIZE + 5;
+        let data = contents(len);
+        _ = encrypted(&backend, &[("a", 1)], "a")
+            .await
+            .write(&id(0), &data, &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+
+        // Whether just the chunks spanned are decrypted, or the whole file to verify it.
+        for verify_reads in [false, true] {
+            let store = encrypted(&backend, &[("a", 1)], "a")
+                .await
+                .verify_reads(verify_reads);
+            for range in [
+                0..1,
+                CHUNK_SIZE - 1..CHUNK_SIZE + 1,
+                CHUNK_SIZE..2 * CHUNK_SIZE,
+                10..2 * CHUNK_SIZE + 10,
+                3 * CHUNK_SIZE..len,
+                0..len,
+            ] {
+                let part = read(&store, &id(0), Some(range.clone())).await.unwrap();
+                assert_eq!(part, data[range.start as usize..range.end as usize]);
+            }
+            // Cut short at the end of the file.
+            let tail = read(&store, &id(0), Some(len - 2..len + CHUNK_SIZE)).await;
+            assert_eq!(tail.unwrap(), data[len as usize - 2..]);
+            let past = read(&store, &id(0), Some(len..len + 10)).await;
+            assert!(past.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_truncation() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = encrypted(&backend, &[("a", 1)], "a").await;
+        let data = contents(2 * CHUNK_SIZE + 10);
+        _ = store
+            .write(&id(0), &data, &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+
+        // Cut off right after a chunk, so what is left still looks like a whole file.
+        let key = FileStore::chunk_path(&id(0)).0;
+        let stored = store.read_object(&key).await.unwrap();
+        let cut = encryption::sealed_range(&(0..2)).end as usize;
+        backend.put(&key, &stored[..cut]).await.unwrap();
+
+        let corrupt = |e: &FSError| matches!(e, FSError::Corrupt(_));
+        assert!(failed(read(&store, &id(0), None).await, corrupt));
+        let last = CHUNK_SIZE + 5..CHUNK_SIZE + 10;
+        assert!(failed(read(&store, &id(0), Some(last)).await, corrupt));
+        // The chunks before the cut are still whole.
+        let first = read(&store, &id(0), Some(0..10)).await.unwrap();
+        assert_eq!(first, data[..10]);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_swapped_chunks() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = encrypted(&backend, &[("a", 1)], "a").await;
+        let data = contents(2 * CHUNK_SIZE + 10);
+        for n in [0, 1] {
+            _ = store
+                .write(&id(n), &data, &MediaInfo::default(), &[], None)
+                .await
+                .unwrap();
+        }
+
+        let (a, b) = (
+            FileStore::chunk_path(&id(0)).0,
+            FileStore::chunk_path(&id(1)).0,
+        );
+        let from = store.read_object(&a).await.unwrap();
+        let mut to = store.read_object(&b).await.unwrap();
+        let chunk = encryption::sealed_range(&(1..2));
+        let chunk = chunk.start as usize..chunk.end as usize;
+        to[chunk.clone()].copy_from_slice(&from[chunk]);
+        backend.put(&b, &to).await.unwrap();
+
+        let corrupt = |e: &FSError| matches!(e, FSError::Corrupt(_));
+        assert!(failed(read(&store, &id(1), None).await, corrupt));
+        let swapped = CHUNK_SIZE..CHUNK_SIZE + 10;
+        assert!(failed(read(&store, &id(1), Some(swapped)).await, corrupt));
+        assert_eq!(read(&store, &id(0), None).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_rewrap() {
+        let backend = Arc::new(MemoryBackend::default());
+        let data = contents(CHUNK_SIZE + 1);
+        _ = encrypted(&backend, &[("a", 1)], "a")
+            .await
+            .write(&id(0), &data, &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        let key = FileStore::chunk_path(&id(0)).0;
+
+        let both = encrypted(&backend, &[("a", 1), ("b", 2)], "b").await;
+        let stored = both.read_object(&key).await.unwrap();
+        assert_eq!(both.rewrap().await.unwrap(), 1);
+        assert_eq!(both.rewrap().await.unwrap(), 0);
+        // Only the wrapped key was written, and never the file.
+        assert_eq!(both.read_object(&key).await.unwrap(), stored);
+
+        let retired = encrypted(&backend, &[("b", 2)], "b").await;
+        assert_eq!(read(&retired, &id(0), None).await.unwrap(), data);
+        let old = encrypted(&backend, &[("a", 1)], "a").await;
+        assert!(read(&old, &id(0), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lost_key() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = encrypted(&backend, &[("a", 1)], "a").await;
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        let key = FileStore::chunk_path(&id(0)).0;
+        let stored = store.read_object(&key).await.unwrap();
+        backend
+            .delete(&FileStore::attachment_key(&key, KEY_NAME))
+            .await
+            .unwrap();
+
+        // Never served encrypted, even once the record is written again.
+        let lost = |e: &FSError| matches!(e, FSError::KeyLost(_));
+        assert!(failed(read(&store, &id(0), None).await, lost));
+        store
+            .write_meta(&id(0), &MediaInfo::default())
+            .await
+            .unwrap();
+        assert!(failed(read(&store, &id(0), None).await, lost));
+        // Nor replaced with something unencrypted.
+        let replaced = store
+            .replace(&id(0), b"other", &MediaInfo::default(), &[])
+            .await;
+        assert!(failed(replaced, lost));
+        assert_eq!(store.read_object(&key).await.unwrap(), stored);
+
+        // Nor is the lost key made up when the record is rebuilt.
+        backend
+            .put(&FileStore::attachment_key(&key, META_NAME), b"{")
+            .await
+            .unwrap();
+        let report = store.scrub().await.unwrap();
+        assert!(report.rebuilt.is_empty());
+        assert!(failed(read(&store, &id(0), None).await, lost));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_key_kept() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = encrypted(&backend, &[("a", 1)], "a").await;
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        let key_key = FileStore::attachment_key(&FileStore::chunk_path(&id(0)).0, KEY_NAME);
+        let wrapped_key = store.read_object(&key_key).await.unwrap();
+        backend
+            .put(&key_key, &[&wrapped_key[..], b" "].concat())
+            .await
+            .unwrap();
+
+        // Reported, but never quarantined, since the file can't be read without it.
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.corrupt, [key_key.as_str()]);
+        assert!(store.read_object(&key_key).await.is_ok());
+        assert_eq!(read(&store, &id(0), None).await.unwrap(), b"media");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        let attachments = [("storyboard.vtt".to_owned(), b"WEBVTT".to_vec())];
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &attachments, None)
+            .await
+            .unwrap();
+        _ = store
+            .write(&id(1), b"kept", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        store.delete(&id(0)).await.unwrap();
+
+        let gone = |e: &FSError| matches!(e, FSError::Gone(_));
+        let collision = |e: &FSError| matches!(e, FSError::NameCollision(_));
+        assert!(failed(read(&store, &id(0), None).await, gone));
+        assert!(failed(store.read_meta(&id(0)).await, gone));
+        assert!(failed(
+            store.read_attachment(&id(0), "storyboard.vtt").await,
+            gone
+        ));
+        assert!(failed(store.delete(&id(0)).await, gone));
+        let again = store
+            .write(&id(0), b"again", &MediaInfo::default(), &[], None)
+            .await;
+        assert!(failed(again, collision));
+
+        // The file, its record, checksums and attachment, and then its tombstone.
+        assert_eq!(store.collect().await.unwrap(), 5);
+        assert_eq!(store.collect().await.unwrap(), 0);
+        let key = FileStore::chunk_path(&id(0)).0;
+        assert!(backend.list(&key).await.unwrap().is_empty());
+        assert!(backend.list(UNCOLLECTED_PREFIX).await.unwrap().is_empty());
+
+        // Never issued again, even by a store which only has the deletions to go on.
+        let reopened = FileStore::new(backend.clone()).await.unwrap();
+        assert!(failed(read(&reopened, &id(0), None).await, gone));
+        let again = reopened
+            .write(&id(0), b"again", &MediaInfo::default(), &[], None)
+            .await;
+        assert!(failed(again, collision));
+        assert_eq!(read(&reopened, &id(1), None).await.unwrap(), b"kept");
+    }
+
+    #[tokio::test]
+    async fn test_collect_listing() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        for n in [0, 1] {
+            _ = store
+                .write(&id(n), b"media", &MediaInfo::default(), &[], None)
+                .await
+                .unwrap();
+        }
+
+        // Listed by a deletion interrupted before the tombstone was stored, so merely unlisted.
+        let listed = format!("{UNCOLLECTED_PREFIX}{}", id(0));
+        backend.put(&listed, b"{}").await.unwrap();
+        assert_eq!(store.collect().await.unwrap(), 0);
+        assert!(backend.head(&listed).await.is_err());
+        assert_eq!(read(&store, &id(0), None).await.unwrap(), b"media");
+
+        // Deleted without being listed, so only collected once the scrubber lists it.
+        store.delete(&id(1)).await.unwrap();
+        backend
+            .delete(&format!("{UNCOLLECTED_PREFIX}{}", id(1)))
+            .await
+            .unwrap();
+        assert_eq!(store.collect().await.unwrap(), 0);
+        _ = store.scrub().await.unwrap();
+        assert_eq!(store.collect().await.unwrap(), 4);
+        let key = FileStore::chunk_path(&id(1)).0;
+        assert!(backend.list(&key).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scrub() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        let attachments = [("storyboard.vtt".to_owned(), b"WEBVTT".to_vec())];
+        for n in [0, 1] {
+            _ = store
+                .write(&id(n), b"media", &MediaInfo::default(), &attachments, None)
+                .await
+                .unwrap();
+        }
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.checked, 6);
+        assert!(report.corrupt.is_empty() && report.missing.is_empty());
+        assert!(report.stray.is_empty() && report.recorded == 0);
+
+        let (a, b) = (
+            FileStore::chunk_path(&id(0)).0,
+            FileStore::chunk_path(&id(1)).0,
+        );
+        backend.put(&a, b"tampered").await.unwrap();
+        let verified = FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .verify_reads(true);
+        let corrupt = |e: &FSError| matches!(e, FSError::Corrupt(_));
+        assert!(failed(read(&verified, &id(0), None).await, corrupt));
+
+        let track = FileStore::attachment_key(&b, "storyboard.vtt");
+        backend.delete(&track).await.unwrap();
+        let stray = FileStore::attachment_key(&b, "storyboard.0.webp");
+        backend.put(&stray, b"sprites").await.unwrap();
+
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.corrupt, [a.as_str()]);
+        assert_eq!(report.missing, [track.as_str()]);
+        assert_eq!(report.stray, [stray.as_str()]);
+        // Moved out of the way, but kept for an operator to look at.
+        let quarantined = store
+            .read_object(&format!("{QUARANTINE_PREFIX}{a}"))
+            .await
+            .unwrap();
+        assert_eq!(quarantined, b"tampered");
+        assert!(backend.head(&a).await.is_err());
+        assert!(read(&store, &id(0), None).await.is_err());
+        assert_eq!(read(&store, &id(1), None).await.unwrap(), b"media");
+    }
+
+    #[tokio::test]
+    async fn test_scrub_records_checksums() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        let key = FileStore::chunk_path(&id(0)).0;
+        let checksums = FileStore::attachment_key(&key, CHECKSUMS_NAME);
+        let recorded = store.read_object(&checksums).await.unwrap();
+
+        // As if stored before checksums were, so the file and its record are taken as they are.
+        backend.delete(&checksums).await.unwrap();
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.recorded, 2);
+        assert!(report.corrupt.is_empty());
+        assert_eq!(store.read_object(&checksums).await.unwrap(), recorded);
+        assert_eq!(store.scrub().await.unwrap().checked, 2);
+    }
+
+    /// The bytes stored for the file with the given ID that count in its uploader's usage.
+    async fn stored_bytes(store: &FileStore, normalized_id: &NormalizedId) -> u64 {
+        let key = FileStore::chunk_path(normalized_id).0;
+        let mut bytes = 0;
+        for object in store.backend.list(&key).await.unwrap() {
+            if !object.ends_with(CHECKSUMS_NAME) && !object.ends_with(OWNER_NAME) {
+                bytes += store.backend.head(&object).await.unwrap().size;
+            }
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let store = store().await;
+        let quota = Quota {
+            bytes: Some(4096),
+            files: Some(2),
+        };
+        let alice = Some(("alice", &quota));
+
+        _ = store
+            .write(&id(0), &contents(100), &MediaInfo::default(), &[], alice)
+            .await
+            .unwrap();
+        let first = stored_bytes(&store, &id(0)).await;
+        // Counted along with its record, and not just the file itself.
+        assert!(first > 100);
+        let usage = Usage {
+            bytes: first,
+            files: 1,
+        };
+        assert_eq!(store.usage("alice").await.unwrap(), usage);
+
+        // What is stored with the file later is counted too.
+        store
+            .write_attachment(&id(0), "storyboard.vtt", b"WEBVTT")
+            .await
+            .unwrap();
+        let first = stored_bytes(&store, &id(0)).await;
+        assert_eq!(store.usage("alice").await.unwrap().bytes, first);
+
+        let refused = |result: Result<String>| {
+            result
+                .err()
+                .and_then(|e| e.downcast::<QuotaExceeded>().ok())
+        };
+        let too_large = store
+            .write(&id(1), &contents(5000), &MediaInfo::default(), &[], alice)
+            .await;
+        assert!(matches!(
+            refused(too_large),
+            Some(QuotaExceeded::FileTooLarge)
+        ));
+        let over_bytes = store
+            .write(&id(1), &contents(3600), &MediaInfo::default(), &[], alice)
+            .await;
+        assert!(matches!(refused(over_bytes), Some(QuotaExceeded::Full)));
+        // Nothing is stored for a refused file, nor counted.
+        assert!(store
+            .backend
+            .head(&FileStore::chunk_path(&id(1)).0)
+            .await
+            .is_err());
+        assert_eq!(store.usage("alice").await.unwrap().bytes, first);
+
+        _ = store
+            .write(&id(1), &contents(10), &MediaInfo::default(), &[], alice)
+            .await
+            .unwrap();
+        let second = stored_bytes(&store, &id(1)).await;
+        let over_files = store
+            .write(&id(2), &contents(10), &MediaInfo::default(), &[], alice)
+            .await;
+        assert!(matches!(refused(over_files), Some(QuotaExceeded::Full)));
+        // Counted separately for each uploader.
+        let bob = Some(("bob", &quota));
+        _ = store
+            .write(&id(2), &contents(10), &MediaInfo::default(), &[], bob)
+            .await
+            .unwrap();
+        assert_eq!(store.usage("alice").await.unwrap().files, 2);
+
+        // Uncounted as soon as the file is deleted, and not again once it is collected.
+        store.delete(&id(0)).await.unwrap();
+        let usage = Usage {
+            bytes: second,
+            files: 1,
+        };
+        assert_eq!(store.usage("alice").await.unwrap(), usage);
+        _ = store.collect().await.unwrap();
+        assert_eq!(store.usage("alice").await.unwrap(), usage);
+        _ = store
+            .write(&id(3), &contents(10), &MediaInfo::default(), &[], alice)
+            .await
+            .unwrap();
+        assert_eq!(store.usage("alice").await.unwrap().files, 2);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_record() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        let media = MediaInfo {
+            kind: "image/webp".to_owned(),
+            source_format: "image/png".to_owned(),
+            width: Some(1),
+            height: Some(1),
+            purpose: Some(profile_image::Purpose::Avatar),
+            renditions: vec![400, 200],
+            ..Default::default()
+        };
+        let renditions = [400, 200].map(|width| {
+            let name = profile_image::rendition_name(profile_image::Purpose::Avatar, width);
+            (name, STANDARD.decode(PIXEL).unwrap())
+        });
+        _ = store
+            .write(
+                &id(0),
+                &STANDARD.decode(PIXEL).unwrap(),
+                &media,
+                &renditions,
+                Some(("alice", &Quota::default())),
+            )
+            .await
+            .unwrap();
+        let written = store.read_meta(&id(0)).await.unwrap();
+        assert_eq!(written.media, media);
+
+        let key = FileStore::chunk_path(&id(0)).0;
+        let record = FileStore::attachment_key(&key, META_NAME);
+        backend.put(&record, b"{").await.unwrap();
+        let bad_meta = |e: &FSError| matches!(e, FSError::BadMeta(_));
+        assert!(failed(store.read_meta(&id(0)).await, bad_meta));
+
+        // Everything but what the file was converted from is found again.
+        store.rebuild_meta(&id(0)).await.unwrap();
+        let rebuilt = store.read_meta(&id(0)).await.unwrap();
+        assert!(rebuilt.media.source_format.is_empty());
+        assert_eq!(rebuilt.media.kind, media.kind);
+        assert_eq!(rebuilt.media.width, media.width);
+        assert_eq!(rebuilt.media.renditions, media.renditions);
+        assert_eq!(rebuilt.media.purpose, media.purpose);
+        assert_eq!(rebuilt.name, written.name);
+        assert_eq!(rebuilt.size, written.size);
+        assert_eq!(rebuilt.uploader, written.uploader);
+        assert_eq!(rebuilt.state, FileState::Claimed);
+
+        // Likewise by the scrubber, for a record which went missing.
+        backend.delete(&record).await.unwrap();
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.rebuilt, [record.as_str()]);
+        assert!(report.missing.is_empty());
+        assert_eq!(store.read_meta(&id(0)).await.unwrap().media, rebuilt.media);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_record() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone()).await.unwrap();
+        let media = MediaInfo {
+            kind: "image/webp".to_owned(),
+            width: Some(1),
+            height: Some(1),
+            ..Default::default()
+        };
+        _ = store
+            .write(&id(0), b"media", &media, &[], None)
+            .await
+            .unwrap();
+
+        // Written before records were versioned, when they held only the media facts.
+        let (key, fname) = FileStore::chunk_path(&id(0));
+        store
+            .put_attachment(&key, META_NAME, &serde_json::to_vec(&media).unwrap())
+            .await
+            .unwrap();
+        let meta = store.read_meta(&id(0)).await.unwrap();
+        assert_eq!(meta.media, media);
+        assert_eq!(meta.size, 5);
+        assert_eq!(meta.state, FileState::Claimed);
+        assert_eq!(
+            store
+                .read_record(&key, &fname)
+                .await
+                .unwrap()
+                .unwrap()
+                .version,
+            0
+        );
+
+        let report = store.scrub().await.unwrap();
+        assert_eq!(report.rebuilt.len(), 1);
+        let record = store.read_record(&key, &fname).await.unwrap().unwrap();
+        assert_eq!(record.version, META_VERSION);
+        assert_eq!(record.meta.media, media);
+    }
+
+    #[tokio::test]
+    async fn test_staging() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .staging_ttl(Some(std::time::Duration::from_secs(3600)));
+        for n in [0, 1] {
+            _ = store
+                .write(&id(n), b"media", &MediaInfo::default(), &[], None)
+                .await
+                .unwrap();
+        }
+        let meta = store.read_meta(&id(0)).await.unwrap();
+        assert_eq!(meta.state, FileState::Staged);
+        assert!(meta
+            .expires_at
+            .is_some_and(|expires_at| expires_at > Utc::now()));
+        let listed = format!("{STAGED_PREFIX}{}", id(0));
+        assert!(backend.head(&listed).await.is_ok());
+
+        let claimed = store.claim(&id(0)).await.unwrap();
+        assert_eq!(claimed.state, FileState::Claimed);
+        assert_eq!(claimed.expires_at, None);
+        assert_eq!(
+            store.read_meta(&id(0)).await.unwrap().state,
+            FileState::Claimed
+        );
+        assert!(backend.head(&listed).await.is_err());
+        assert_eq!(store.claim(&id(0)).await.unwrap().state, FileState::Claimed);
+        // Nothing has expired yet.
+        assert_eq!(store.sweep().await.unwrap(), 0);
+
+        // Expired as soon as it is written.
+        let expiring = FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .staging_ttl(Some(std::time::Duration::ZERO));
+        _ = expiring
+            .write(&id(2), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        let gone = |e: &FSError| matches!(e, FSError::Gone(_));
+        assert!(failed(expiring.claim(&id(2)).await, gone));
+        assert_eq!(expiring.sweep().await.unwrap(), 1);
+        assert!(failed(read(&expiring, &id(2), None).await, gone));
+        // Only the file which is still staged is still listed.
+        let staged = backend.list(STAGED_PREFIX).await.unwrap();
+        assert_eq!(staged, [format!("{STAGED_PREFIX}{}", id(1))]);
+        assert_eq!(read(&store, &id(0), None).await.unwrap(), b"media");
+        assert_eq!(read(&store, &id(1), None).await.unwrap(), b"media");
+        assert_eq!(store.collect().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_leftovers() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .staging_ttl(Some(std::time::Duration::from_secs(3600)));
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+        _ = store.claim(&id(0)).await.unwrap();
+
+        // Left listed by a claim interrupted after the record was written, so merely unlisted.
+        let staging = |n| Staging {
+            id: id(n).to_string(),
+            expires_at: Utc::now() - chrono::Duration::seconds(1),
+        };
+        let listed = format!("{STAGED_PREFIX}{}", id(0));
+        let listing = serde_json::to_vec(&staging(0)).unwrap();
+        backend.put(&listed, &listing).await.unwrap();
+        // Left listed by a write which never finished, so there is nothing to delete.
+        let unwritten = format!("{STAGED_PREFIX}{}", id(1));
+        let listing = serde_json::to_vec(&staging(1)).unwrap();
+        backend.put(&unwritten, &listing).await.unwrap();
+        // Left for an operator to look at.
+        let unreadable = format!("{STAGED_PREFIX}{}", id(2));
+        backend.put(&unreadable, b"{").await.unwrap();
+
+        assert_eq!(store.sweep().await.unwrap(), 0);
+        assert_eq!(
+            backend.list(STAGED_PREFIX).await.unwrap(),
+            [unreadable.as_str()]
+        );
+        assert_eq!(read(&store, &id(0), None).await.unwrap(), b"media");
+        assert!(backend.list(UNCOLLECTED_PREFIX).await.unwrap().is_empty());
+    }
+
+    /// A backend which fails to store anything under keys ending in `failing`.
+    #[derive(Debug)]
+    struct FailingBackend {
+        inner: MemoryBackend,
+        failing: &'static str,
+    }
+
+    #[async_trait::async_trait]
+    impl StorageBackend for FailingBackend {
+        async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+            ensure!(!key.ends_with(self.failing), "Failed to store `{key}`");
+            self.inner.put(key, data).await
+        }
+
+        async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<ObjectReader> {
+            self.inner.get(key, range).await
+        }
+
+        async fn head(&self, key: &str) -> Result<crate::storage::ObjectMeta> {
+            self.inner.head(key).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.inner.delete(key).await
+        }
+
+        async fn list_page(
+            &self,
+            prefix: &str,
+            start_after: Option<&str>,
+            limit: usize,
+        ) -> Result<Vec<String>> {
+            self.inner.list_page(prefix, start_after, limit).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_write() {
+        let backend = Arc::new(FailingBackend {
+            inner: MemoryBackend::default(),
+            failing: "storyboard.vtt",
+        });
+        let store = FileStore::new(backend.clone())
+            .await
+            .unwrap()
+            .staging_ttl(Some(std::time::Duration::from_secs(3600)));
+        let quota = Quota::default();
+        let attachments = [("storyboard.vtt".to_owned(), b"WEBVTT".to_vec())];
+        let written = store
+            .write(
+                &id(0),
+                b"media",
+                &MediaInfo::default(),
+                &attachments,
+                Some(("alice", &quota)),
+            )
+            .await;
+        assert!(written.is_err());
+
+        // Nothing is left behind, nor counted, and the ID can be written again.
+        for prefix in [FileStore::chunk_path(&id(0)).0.as_str(), STAGED_PREFIX] {
+            assert!(backend.list(prefix).await.unwrap().is_empty());
+        }
+        assert_eq!(store.usage("alice").await.unwrap(), Usage::default());
+        _ = store
+            .write(&id(0), b"media", &MediaInfo::default(), &[], None)
+            .await
+            .unwrap();
+    }
+}
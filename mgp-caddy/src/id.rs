@@ -0,0 +1,223 @@
+//! Normalized IDs, which every stored file is named by.
+//!
+//! An ID is URL-safe base64 without padding. IDs issued before they were versioned are
+//! 38 characters long and are version 0. Every later ID starts with one character naming
+//! its version, followed by a body laid out as that version says, so IDs of different
+//! versions can never be mistaken for each other. Version 1 has the same body as version 0.
+//! A later version can switch to a different layout, such as a time-sortable one, without
+//! touching the IDs already issued.
+
+use base64::{alphabet, engine, engine::general_purpose, Engine};
+use rand::{thread_rng, Rng};
+use std::{
+    error::Error,
+    fmt::Display,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// The length of the body of version 0 and 1 IDs: 28 bytes, in base64.
+const RANDOM_BODY_LEN: usize = 38;
+
+/// The extensions a stored file may be named with, one for each format files are stored in.
+const EXTENSIONS: [&str; 2] = ["webp", "webm"];
+
+/// The normalized ID of a stored file, checked to be well formed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NormalizedId {
+    id: String,
+    version: u8,
+}
+
+impl NormalizedId {
+    /// The version of the IDs issued now.
+    pub const CURRENT_VERSION: u8 = 1;
+
+    /// Generates a new, unique ID of the [current version](Self::CURRENT_VERSION).
+    ///
+    /// The body is made by concatenating the little-endian byte representations of
+    ///
+    /// 1. A random UUID
+    /// 2. The current UNIX timestamp
+    /// 3. A random 32-bit unsigned integer
+    ///
+    /// and then Base64 encoding the entire byte array using a url-safe alphabet.
+    pub fn generate() -> NormalizedId {
+        let fid = Uuid::new_v4();
+
+        let now = {
+            let start = SystemTime::now();
+            let since_the_epoch = start
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards");
+            since_the_epoch.as_secs().to_le_bytes()
+        };
+
+        let num = thread_rng().gen::<u32>().to_le_bytes();
+
+        let bindat = [fid.as_bytes().as_slice(), &now, &num].concat();
+
+        const BASE64: engine::GeneralPurpose =
+            engine::GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::NO_PAD);
+        NormalizedId {
+            id: format!("{}{}", Self::CURRENT_VERSION, BASE64.encode(bindat)),
+            version: Self::CURRENT_VERSION,
+        }
+    }
+
+    /// Parses the name a file is requested by, which is its ID, optionally followed by
+    /// the extension of the format it is stored in. Returns the ID and the extension.
+    pub fn from_file_name(name: &str) -> Result<(NormalizedId, Option<&str>), InvalidId> {
+        let (id, extension) = match name.split_once('.') {
+            Some((id, extension)) => (id, Some(extension)),
+            None => (name, None),
+        };
+        if extension.is_some_and(|extension| !EXTENSIONS.contains(&extension)) {
+            return Err(InvalidId(name.to_owned()));
+        }
+
+        Ok((id.parse()?, extension))
+    }
+
+    /// The version of the ID.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The ID as it is written.
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+
+    /// The body of the ID, after the character naming its version.
+    /// Version 0 IDs have no such character, so they are all body.
+    pub fn body(&self) -> &str {
+        match self.version {
+            0 => &self.id,
+            _ => &self.id[1..],
+        }
+    }
+}
+
+impl FromStr for NormalizedId {
+    type Err = InvalidId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidId(id.to_owned());
+        if !id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        {
+            return Err(invalid());
+        }
+
+        let version = match id.len() {
+            RANDOM_BODY_LEN => 0,
+            _ => match id.as_bytes().first() {
+                Some(b'1') if id.len() == 1 + RANDOM_BODY_LEN => 1,
+                _ => return Err(invalid()),
+            },
+        };
+
+        Ok(NormalizedId {
+            id: id.to_owned(),
+            version,
+        })
+    }
+}
+
+impl Display for NormalizedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// Indicates a file was requested by a name which isn't a well formed normalized ID.
+#[derive(Debug)]
+pub struct InvalidId(String);
+
+impl Display for InvalidId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` is not a valid file identifier", self.0)
+    }
+}
+
+impl Error for InvalidId {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0: &str = "abc123def456ghi789jkl012mno345pqr678st";
+
+    #[test]
+    fn test_parse_versions() {
+        let v0: NormalizedId = V0.parse().unwrap();
+        assert_eq!(v0.version(), 0);
+        assert_eq!(v0.body(), V0);
+
+        let v1: NormalizedId = format!("1{V0}").parse().unwrap();
+        assert_eq!(v1.version(), 1);
+        assert_eq!(v1.body(), V0);
+        assert_eq!(v1.as_str(), format!("1{V0}"));
+
+        // A version 0 ID can start with any character, `1` included.
+        let v0: NormalizedId = format!("1{}", &V0[1..]).parse().unwrap();
+        assert_eq!(v0.version(), 0);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for id in [
+            "",
+            "abc",
+            &V0[1..],
+            &format!("{V0}a"),
+            // An unknown version.
+            &format!("2{V0}"),
+            &format!("11{V0}"),
+            // The right length in bytes, but not ASCII.
+            &"é".repeat(19),
+            &format!("é{}", &V0[2..]),
+            &format!("{}.", &V0[1..]),
+            &format!("{}/", &V0[1..]),
+        ] {
+            assert!(id.parse::<NormalizedId>().is_err(), "{id}");
+        }
+    }
+
+    #[test]
+    fn test_from_file_name() {
+        let (id, extension) = NormalizedId::from_file_name(V0).unwrap();
+        assert_eq!((id.as_str(), extension), (V0, None));
+
+        for extension in EXTENSIONS {
+            let name = format!("1{V0}.{extension}");
+            let (id, parsed) = NormalizedId::from_file_name(&name).unwrap();
+            assert_eq!(id.version(), 1);
+            assert_eq!(parsed, Some(extension));
+        }
+
+        for name in [
+            format!("{V0}.png"),
+            format!("{V0}.webp.webp"),
+            format!("{V0}."),
+            format!("{V0}.WEBP"),
+            format!("{}.webp", &V0[1..]),
+            format!("2{V0}.webm"),
+            ".webp".to_owned(),
+        ] {
+            assert!(NormalizedId::from_file_name(&name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_generate() {
+        let id = NormalizedId::generate();
+        assert_eq!(id.version(), NormalizedId::CURRENT_VERSION);
+        assert_eq!(id.body().len(), RANDOM_BODY_LEN);
+        assert_eq!(id.as_str().parse::<NormalizedId>().unwrap(), id);
+        assert_ne!(NormalizedId::generate(), id);
+    }
+}
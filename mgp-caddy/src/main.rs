@@ -29,63 +29,194 @@
 #![allow(dead_code)]
 #![allow(unused)]
 
-use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
 
+use futures_util::TryStreamExt;
+use tokio::io::AsyncReadExt;
+use warp::http::{HeaderMap, HeaderValue};
+use warp::hyper::body::Buf;
 use warp::hyper::StatusCode;
 use warp::multipart::{FormData, Part};
+use warp::path::FullPath;
+use warp::reply::Response;
 use warp::{Filter, Rejection, Reply};
 
+use fs::{FSError, FileStore, ScrubReport};
+use id::NormalizedId;
+use pipeline::Pipeline;
+use profile_image::{Crop, InvalidProfileImage, Purpose};
+use quota::{Quota, QuotaConfig, QuotaExceeded};
+use signing::{SignatureError, Signer};
+use transcode::UnsupportedFormat;
+
+pub mod background;
+pub mod collector;
+pub mod config;
 pub mod convert;
+pub mod encryption;
 pub mod fs;
-
-static SAMPLE_FILE: &[u8] = include_bytes!(r#"sample.mp4"#);
+pub mod id;
+pub mod pipeline;
+pub mod profile_image;
+pub mod quality;
+pub mod quota;
+pub mod rebalancer;
+pub mod reprocess;
+pub mod scrubber;
+pub mod signing;
+pub mod storage;
+pub mod storyboard;
+pub mod sweeper;
+pub mod transcode;
 
 const MAX_UPLOAD_SIZE: u64 = 5_000_000; // 5mb;
-const FILE_STORE_PATH: &str = "./fileStore";
-static FILE_STORE: fs::FileStore = fs::FileStore::new(FILE_STORE_PATH).unwrap();
+
+/// How many files a page of `GET /inventory` lists unless asked for fewer, and the most it may list.
+const INVENTORY_PAGE_SIZE: usize = 1000;
+const MAX_INVENTORY_PAGE_SIZE: usize = 10_000;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let input = std::io::BufReader::new(SAMPLE_FILE);
-    println!("{:?}", convert::convert_to_webm(input));
+    let config = config::Config::load()?;
+    ffmpeg_next::log::forward_to_tracing();
+    let store = Arc::new(
+        FileStore::new(storage::open(&config.storage)?)
+            .await?
+            .verify_reads(config.verify_reads)
+            .staging_ttl(config.staging.ttl())
+            .keyring(encryption::Keyring::new(&config.encryption)?),
+    );
+    let pipeline = Arc::new(Pipeline::new(&config)?);
+    let signer = Arc::new(Signer::new(&config.signing)?);
 
-    serve().await?;
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => serve(store, pipeline, signer, &config).await?,
+        Some("reprocess") => {
+            let options = reprocess::Options::parse(args)?;
+            reprocess::run(store, pipeline, options).await?;
+        }
+        Some("scrub") => scrubber::run(store).await?,
+        Some("rewrap") => encryption::rewrap(store).await?,
+        Some(command) => eyre::bail!("Unknown subcommand `{command}`"),
+    }
 
     Ok(())
 }
 
-async fn serve() -> eyre::Result<()> {
+async fn serve(
+    store: Arc<FileStore>,
+    pipeline: Arc<Pipeline>,
+    signer: Arc<Signer>,
+    config: &config::Config,
+) -> eyre::Result<()> {
+    _ = collector::spawn(store.clone(), &config.collector);
+    _ = rebalancer::spawn(store.clone(), &config.rebalancer);
+    _ = sweeper::spawn(store.clone(), &config.staging);
+    let scrubbed = scrubber::spawn(store.clone(), &config.scrubber);
+
+    let store = warp::any().map(move || store.clone());
+    let pipeline = warp::any().map(move || pipeline.clone());
+    let quotas = Arc::new(config.quotas.clone());
+    let quotas = warp::any().map(move || quotas.clone());
+    let signer = warp::any().map(move || signer.clone());
+    // Reads carry the path and query string they were signed for along, to check them against.
+    let signed = warp::path::full()
+        .and(warp::query::<HashMap<String, String>>())
+        .and(signer.clone());
+
     let getmeta = warp::path("meta")
         .and(warp::path::param::<String>())
         .and(warp::get())
-        .map(|name| format!("meta, {}!", name));
+        .and(signed.clone())
+        .and(store.clone())
+        .and_then(getmeta);
+
+    let getattachment = warp::path!("file" / String / String)
+        .and(warp::get())
+        .and(signed.clone())
+        .and(store.clone())
+        .and_then(getattachment);
 
     let getfile = warp::path("file")
         .and(warp::path::param::<String>())
+        .and(warp::path::end())
         .and(warp::get())
-        .map(|name| format!("getfile, {}!", name));
+        .and(signed.clone())
+        .and(warp::header::optional::<String>("range"))
+        .and(store.clone())
+        .and_then(getfile);
 
     let putfile = warp::path("file")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::headers_cloned())
         .and(warp::multipart::form().max_length(MAX_UPLOAD_SIZE))
+        .and(store.clone())
+        .and(pipeline.clone())
+        .and(quotas.clone())
+        .and_then(putfile);
+
+    let claimfile = warp::path!("file" / String / "claim")
         .and(warp::post())
-        .map(|name| format!("putfile, {:?}!", name));
+        .and(store.clone())
+        .and_then(claimfile);
 
     let delfile = warp::path("file")
         .and(warp::path::param::<String>())
+        .and(warp::path::end())
         .and(warp::delete())
-        .map(|name| format!("delfile, {}!", name));
+        .and(store.clone())
+        .and_then(delfile);
+
+    let getusage = warp::path!("usage" / String)
+        .and(warp::get())
+        .and(warp::header::headers_cloned())
+        .and(store.clone())
+        .and(quotas.clone())
+        .and_then(getusage);
+
+    let getinventory = warp::path("inventory")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(store.clone())
+        .and_then(getinventory);
+
+    let exportinventory = warp::path!("inventory" / "export")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(store.clone())
+        .and_then(exportinventory);
 
-    let invalidendpoint = warp::any().map(|| {
-        Ok(warp::reply::with_status(
-            "METHOD_NOT_ALLOWED",
-            StatusCode::METHOD_NOT_ALLOWED,
-        ))
-    });
+    let signurl = warp::path("sign")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(signer.clone())
+        .map(signurl);
+
+    let gethealth = warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || health(&scrubbed.borrow()));
+
+    let invalidendpoint = warp::any()
+        .map(|| warp::reply::with_status("METHOD_NOT_ALLOWED", StatusCode::METHOD_NOT_ALLOWED));
 
     let routes = getmeta
+        .or(getattachment)
         .or(getfile)
         .or(putfile)
+        .or(claimfile)
         .or(delfile)
+        .or(getusage)
+        .or(getinventory)
+        .or(exportinventory)
+        .or(signurl)
+        .or(gethealth)
         .or(invalidendpoint);
 
     warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
@@ -93,18 +224,539 @@ async fn serve() -> eyre::Result<()> {
     Ok(())
 }
 
-async fn getmeta(file_id: String) -> Result<impl Reply, Rejection> {
-    let file_id = Path::new(&file_id).to_owned();
-    if file_id.components().count() > 1 {
-        return Ok(warp::reply::with_status("INVALID_FILE_ID", StatusCode::BAD_REQUEST));
+async fn getmeta(
+    file_name: String,
+    path: FullPath,
+    query: HashMap<String, String>,
+    signer: Arc<Signer>,
+    store: Arc<FileStore>,
+) -> Result<Response, Rejection> {
+    let file_id = match file_id(&file_name) {
+        Some(file_id) => file_id,
+        None => return Ok(invalid_file_id()),
+    };
+    if let Err(e) = signer.check(path.as_str(), &query) {
+        return Ok(error_reply(e.into()));
+    }
+
+    let meta = match store.read_meta(&file_id).await {
+        // A file whose record was lost still has everything the record is made from.
+        Err(e) if matches!(e.downcast_ref(), Some(FSError::BadMeta(_))) => {
+            tracing::warn!("{e}, rebuilding it");
+            match store.rebuild_meta(&file_id).await {
+                Ok(()) => store.read_meta(&file_id).await,
+                Err(e) => Err(e),
+            }
+        }
+        meta => meta,
+    };
+    match meta {
+        Ok(meta) => Ok(warp::reply::json(&fs::FileMeta {
+            name: file_name,
+            ..meta
+        })
+        .into_response()),
+        Err(e) => Ok(error_reply(e)),
+    }
+}
+
+async fn getfile(
+    file_name: String,
+    path: FullPath,
+    query: HashMap<String, String>,
+    signer: Arc<Signer>,
+    range: Option<String>,
+    store: Arc<FileStore>,
+) -> Result<Response, Rejection> {
+    let (file_id, extension) = match NormalizedId::from_file_name(&file_name) {
+        Ok(file_name) => file_name,
+        Err(_) => return Ok(invalid_file_id()),
+    };
+    if let Err(e) = signer.check(path.as_str(), &query) {
+        return Ok(error_reply(e.into()));
+    }
+
+    // The size is taken from the record, and without one the whole file is sent instead.
+    let range = match range.as_deref().and_then(byte_range) {
+        Some(range) => match store.read_meta(&file_id).await {
+            Ok(meta) => Some((range, meta.size)),
+            Err(e) if matches!(e.downcast_ref(), Some(FSError::BadMeta(_))) => None,
+            Err(e) => return Ok(error_reply(e)),
+        },
+        None => None,
+    };
+    if let Some((range, size)) = &range {
+        if range.start >= *size {
+            return Ok(warp::reply::with_header(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "Content-Range",
+                format!("bytes */{size}"),
+            )
+            .into_response());
+        }
     }
-    
-    let file_id = match file_id.file_stem() {
-        None => return Ok(warp::reply::with_status("INVALID_FILE_ID", StatusCode::BAD_REQUEST)),
-        Some(file_id) => file_id.to_string_lossy(),
+
+    let file: std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>> = match &range {
+        Some((range, _)) => match store.read_range(&file_id, range.clone()).await {
+            Ok(file) => Box::pin(file),
+            Err(e) => return Ok(error_reply(e)),
+        },
+        None => match store.read(&file_id).await {
+            Ok(file) => Box::pin(file),
+            Err(e) => return Ok(error_reply(e)),
+        },
     };
 
-    // At this point there should be no path traversal elements in file_id
+    // Streamed, so the file stays held for reading until the whole of it is sent.
+    let data = tokio_util::io::ReaderStream::new(file)
+        .inspect_err(|e| tracing::error!("Reading a file failed: {e:?}"));
+    let content_type = match extension {
+        Some("webp") => "image/webp",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    };
+    let mut response = Response::new(warp::hyper::Body::wrap_stream(data));
+    let headers = response.headers_mut();
+    _ = headers.insert("Content-Type", HeaderValue::from_static(content_type));
+    _ = headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    if let Some((range, size)) = range {
+        let end = range.end.min(size);
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        _ = response.headers_mut().insert(
+            "Content-Range",
+            format!("bytes {}-{}/{size}", range.start, end - 1)
+                .parse()
+                .unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+/// The bytes asked for by a `Range` header, if it asks for a single range starting at a
+/// given offset. Any other, such as a suffix or several ranges, is answered with the whole file.
+fn byte_range(header: &str) -> Option<std::ops::Range<u64>> {
+    let (start, end) = header.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse::<u64>().ok()?.checked_add(1)?,
+    };
+    (start < end).then_some(start..end)
+}
+
+async fn getattachment(
+    file_name: String,
+    part: String,
+    path: FullPath,
+    query: HashMap<String, String>,
+    signer: Arc<Signer>,
+    store: Arc<FileStore>,
+) -> Result<Response, Rejection> {
+    let is_attachment =
+        storyboard::is_storyboard_name(&part) || profile_image::is_rendition_name(&part);
+    let file_id = match file_id(&file_name) {
+        Some(file_id) if is_attachment => file_id,
+        _ => return Ok(invalid_file_id()),
+    };
+    if let Err(e) = signer.check(path.as_str(), &query) {
+        return Ok(error_reply(e.into()));
+    }
+
+    let mut data = Vec::new();
+    let read = async {
+        let mut attachment = Box::pin(store.read_attachment(&file_id, &part).await?);
+        _ = attachment.read_to_end(&mut data).await?;
+        eyre::Ok(())
+    };
+    if let Err(e) = read.await {
+        return Ok(error_reply(e));
+    }
+
+    let content_type = match part == storyboard::TRACK_NAME {
+        true => "text/vtt",
+        false => "image/webp",
+    };
+    Ok(warp::reply::with_header(data, "Content-Type", content_type).into_response())
+}
+
+async fn delfile(file_name: String, store: Arc<FileStore>) -> Result<Response, Rejection> {
+    let file_id = match file_id(&file_name) {
+        Some(file_id) => file_id,
+        None => return Ok(invalid_file_id()),
+    };
+
+    match store.delete(&file_id).await {
+        Ok(()) => Ok(StatusCode::OK.into_response()),
+        Err(e) => Ok(error_reply(e)),
+    }
+}
+
+async fn claimfile(file_name: String, store: Arc<FileStore>) -> Result<Response, Rejection> {
+    let file_id = match file_id(&file_name) {
+        Some(file_id) => file_id,
+        None => return Ok(invalid_file_id()),
+    };
+
+    match store.claim(&file_id).await {
+        Ok(meta) => Ok(warp::reply::json(&fs::FileMeta {
+            name: file_name,
+            ..meta
+        })
+        .into_response()),
+        Err(e) => Ok(error_reply(e)),
+    }
+}
+
+async fn putfile(
+    query: HashMap<String, String>,
+    headers: HeaderMap,
+    form: FormData,
+    store: Arc<FileStore>,
+    pipeline: Arc<Pipeline>,
+    quotas: Arc<QuotaConfig>,
+) -> Result<Response, Rejection> {
+    let uploader = match uploader(&headers, &quotas) {
+        Ok(uploader) => uploader,
+        Err(_) => return Ok(invalid_uploader()),
+    };
+
+    let purpose = match upload_purpose(&query) {
+        Ok(purpose) => purpose,
+        Err(_) => {
+            return Ok(
+                warp::reply::with_status("INVALID_PURPOSE", StatusCode::BAD_REQUEST)
+                    .into_response(),
+            )
+        }
+    };
+
+    let payload = match read_upload(form).await {
+        Ok(Some(payload)) => payload,
+        Ok(None) | Err(_) => {
+            return Ok(
+                warp::reply::with_status("INVALID_UPLOAD", StatusCode::BAD_REQUEST).into_response(),
+            )
+        }
+    };
 
-    Ok("success")
+    let normalized_id = NormalizedId::generate();
+
+    // Transcoding is CPU-bound, so keep it off the async workers.
+    let job = normalized_id.to_string();
+    let converted = tokio::task::spawn_blocking(move || {
+        // Ties anything FFMPEG logs during the conversion to this upload.
+        let _job = ffmpeg_next::log::enter_job(job);
+        pipeline.convert(&payload, purpose)
+    })
+    .await;
+    let converted = match converted {
+        Ok(Ok(converted)) => converted,
+        Ok(Err(e)) => return Ok(error_reply(e)),
+        Err(e) => return Ok(error_reply(e.into())),
+    };
+
+    let stored = async {
+        let uploader = uploader
+            .as_ref()
+            .map(|(uploader, quota)| (uploader.as_str(), *quota));
+        let name = store
+            .write(
+                &normalized_id,
+                converted.data.as_slice(),
+                &converted.media,
+                &converted.attachments,
+                uploader,
+            )
+            .await?;
+        eyre::Ok(name)
+    };
+    let name = match stored.await {
+        Ok(name) => name,
+        Err(e) => return Ok(error_reply(e)),
+    };
+
+    Ok(warp::reply::json(
+        &serde_json::json!({ "name": format!("{name}.{}", converted.format.extension()) }),
+    )
+    .into_response())
+}
+
+/// Reports how much an uploader has stored, and how much their tier allows.
+async fn getusage(
+    uploader: String,
+    headers: HeaderMap,
+    store: Arc<FileStore>,
+    quotas: Arc<QuotaConfig>,
+) -> Result<Response, Rejection> {
+    if !quota::valid_uploader(&uploader) {
+        return Ok(invalid_uploader());
+    }
+    let tier = match headers.get(&quotas.tier_header).map(|tier| tier.to_str()) {
+        Some(Err(_)) => return Ok(invalid_uploader()),
+        tier => tier.and_then(Result::ok),
+    };
+
+    match store.usage(&uploader).await {
+        Ok(usage) => Ok(warp::reply::json(&serde_json::json!({
+            "uploader": uploader,
+            "bytes": usage.bytes,
+            "files": usage.files,
+            "quota": quotas.quota(tier),
+        }))
+        .into_response()),
+        Err(e) => Ok(error_reply(e)),
+    }
+}
+
+async fn getinventory(
+    query: HashMap<String, String>,
+    store: Arc<FileStore>,
+) -> Result<Response, Rejection> {
+    let (prefix, cursor) = match inventory_query(&query) {
+        Some(query) => query,
+        None => return Ok(invalid_file_id()),
+    };
+    let limit = match query.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => INVENTORY_PAGE_SIZE,
+        Some(Ok(limit)) if (1..=MAX_INVENTORY_PAGE_SIZE).contains(&limit) => limit,
+        Some(_) => {
+            return Ok(
+                warp::reply::with_status("INVALID_LIMIT", StatusCode::BAD_REQUEST).into_response(),
+            )
+        }
+    };
+
+    match store.inventory(prefix, cursor.as_ref(), limit).await {
+        Ok(page) => Ok(warp::reply::json(&page).into_response()),
+        Err(e) => Ok(error_reply(e)),
+    }
+}
+
+async fn exportinventory(
+    query: HashMap<String, String>,
+    store: Arc<FileStore>,
+) -> Result<Response, Rejection> {
+    let prefix = match inventory_query(&query) {
+        Some((prefix, None)) => prefix.to_owned(),
+        _ => return Ok(invalid_file_id()),
+    };
+
+    // Read a page at a time as the response is sent, so the whole inventory is never held at once.
+    let lines =
+        futures_util::stream::try_unfold(Some(None), move |cursor: Option<Option<String>>| {
+            let store = store.clone();
+            let prefix = prefix.clone();
+            async move {
+                let cursor = match cursor {
+                    Some(cursor) => cursor,
+                    None => return Ok(None),
+                };
+                let cursor = cursor
+                    .map(|cursor| cursor.parse::<NormalizedId>())
+                    .transpose()?;
+                let page = store
+                    .inventory(&prefix, cursor.as_ref(), MAX_INVENTORY_PAGE_SIZE)
+                    .await?;
+
+                let mut lines = Vec::new();
+                for file in &page.files {
+                    serde_json::to_writer(&mut lines, file)?;
+                    lines.push(b'\n');
+                }
+                eyre::Ok(Some((lines, page.next.map(Some))))
+            }
+        })
+        .inspect_err(|e| tracing::error!("Exporting the inventory failed: {e:?}"));
+
+    let mut response = Response::new(warp::hyper::Body::wrap_stream(lines));
+    _ = response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    Ok(response)
+}
+
+/// A request for a signed URL, as posted to `POST /sign`.
+#[derive(Debug, serde::Deserialize)]
+struct SignRequest {
+    /// The path to sign, such as `/file/<id>.webp`.
+    path: String,
+    /// How many seconds the URL stays valid for, if not the configured default.
+    ttl: Option<u64>,
+}
+
+/// Signs a URL for reading a file, its metadata or one of its attachments.
+fn signurl(request: SignRequest, signer: Arc<Signer>) -> Response {
+    let mut segments = request.path.strip_prefix('/').unwrap_or("").split('/');
+    let readable = match (segments.next(), segments.next(), segments.next()) {
+        (Some("meta"), Some(name), None) | (Some("file"), Some(name), _) => {
+            file_id(name).is_some() && segments.next().is_none()
+        }
+        _ => false,
+    };
+    let ttl = request.ttl.unwrap_or(signer.ttl);
+    let expires_at = i64::try_from(ttl)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl));
+    let expires_at = match expires_at {
+        Some(expires_at) if readable => expires_at,
+        _ => {
+            return warp::reply::with_status("INVALID_SIGN_REQUEST", StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    match signer.sign(&request.path, expires_at) {
+        Ok(url) => warp::reply::json(&serde_json::json!({
+            "url": url,
+            "expires_at": expires_at,
+        }))
+        .into_response(),
+        Err(e) => error_reply(e),
+    }
+}
+
+/// Reads the ID prefix and cursor of an inventory listing from its query string.
+/// Returns `None` if the prefix could never be part of a normalized ID,
+/// or the cursor isn't one.
+fn inventory_query(query: &HashMap<String, String>) -> Option<(&str, Option<NormalizedId>)> {
+    let prefix = query.get("prefix").map_or("", String::as_str);
+    let is_id_part = prefix
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+    let cursor = match query.get("cursor") {
+        Some(cursor) => Some(cursor.parse().ok()?),
+        None => None,
+    };
+
+    is_id_part.then_some((prefix, cursor))
+}
+
+/// Reads who an upload is from, and the quota of their tier, from the headers the gateway adds
+/// to `POST /file`. Returns `None` if the gateway didn't name an uploader, and fails if it
+/// named one which isn't usable.
+fn uploader<'a>(
+    headers: &HeaderMap,
+    quotas: &'a QuotaConfig,
+) -> eyre::Result<Option<(String, &'a Quota)>> {
+    let uploader = match headers.get(&quotas.uploader_header) {
+        Some(uploader) => uploader.to_str()?,
+        None => return Ok(None),
+    };
+    eyre::ensure!(quota::valid_uploader(uploader), "Invalid uploader ID");
+    let tier = headers
+        .get(&quotas.tier_header)
+        .map(|tier| tier.to_str())
+        .transpose()?;
+
+    Ok(Some((uploader.to_owned(), quotas.quota(tier))))
+}
+
+/// Reads what an upload is for from the query string of `POST /file`.
+/// Returns `None` for ordinary post media, which may not be cropped.
+fn upload_purpose(
+    query: &HashMap<String, String>,
+) -> eyre::Result<Option<(Purpose, Option<Crop>)>> {
+    let crop = match (query.get("crop"), query.get("focus")) {
+        (Some(_), Some(_)) => {
+            eyre::bail!("Only one of a crop rectangle and a focal point may be given")
+        }
+        (Some(rect), None) => Some(Crop::parse_rect(rect)?),
+        (None, Some(focus)) => Some(Crop::parse_focus(focus)?),
+        (None, None) => None,
+    };
+
+    match query.get("purpose").map(String::as_str) {
+        None | Some("post") if crop.is_none() => Ok(None),
+        None | Some("post") => eyre::bail!("Only avatars and cover banners may be cropped"),
+        Some(purpose) => Ok(Some((purpose.parse()?, crop))),
+    }
+}
+
+/// Extracts the normalized ID from a requested file name, which may have an extension.
+/// Returns `None` if the name isn't that of a file the store could hold, so the request
+/// can be refused without touching the store.
+fn file_id(file_name: &str) -> Option<NormalizedId> {
+    NormalizedId::from_file_name(file_name)
+        .ok()
+        .map(|(file_id, _)| file_id)
+}
+
+fn invalid_file_id() -> Response {
+    warp::reply::with_status("INVALID_FILE_ID", StatusCode::BAD_REQUEST).into_response()
+}
+
+/// The response to a request naming an uploader ID which isn't usable.
+fn invalid_uploader() -> Response {
+    warp::reply::with_status("INVALID_UPLOADER", StatusCode::BAD_REQUEST).into_response()
+}
+
+/// Reads the `file` field of a multipart upload into memory.
+async fn read_upload(mut form: FormData) -> Result<Option<Vec<u8>>, warp::Error> {
+    while let Some(part) = form.try_next().await? {
+        if part.name() != "file" {
+            continue;
+        }
+
+        let mut payload = Vec::new();
+        let mut data = part.stream();
+        while let Some(chunk) = data.try_next().await? {
+            payload.extend_from_slice(chunk.chunk());
+        }
+        return Ok(Some(payload));
+    }
+
+    Ok(None)
+}
+
+/// Reports whether the caddy is healthy, along with what the latest scrub of the store found.
+fn health(scrubbed: &Option<ScrubReport>) -> Response {
+    let status = match scrubbed {
+        Some(report) if !report.is_clean() => "DEGRADED",
+        _ => "OK",
+    };
+    warp::reply::json(&serde_json::json!({
+        "status": status,
+        "scrub": scrubbed,
+    }))
+    .into_response()
+}
+
+/// Turns an error raised while handling a request into the response the API specifies for it.
+fn error_reply(e: eyre::Report) -> Response {
+    let (body, status) = match e.downcast_ref::<FSError>() {
+        Some(FSError::NotFound(_)) => ("NOT_FOUND", StatusCode::NOT_FOUND),
+        Some(FSError::Gone(_)) => ("GONE", StatusCode::GONE),
+        Some(FSError::Corrupt(_)) => {
+            tracing::error!("{e:?}");
+            ("CORRUPT", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Some(FSError::DirectoryTraversal(_) | FSError::IsSymlink(_)) => {
+            ("INVALID_FILE_ID", StatusCode::BAD_REQUEST)
+        }
+        _ if matches!(e.downcast_ref(), Some(SignatureError::Expired)) => {
+            ("EXPIRED_URL", StatusCode::FORBIDDEN)
+        }
+        _ if e.downcast_ref::<SignatureError>().is_some() => {
+            ("INVALID_SIGNATURE", StatusCode::FORBIDDEN)
+        }
+        _ if matches!(e.downcast_ref(), Some(QuotaExceeded::FileTooLarge)) => {
+            ("FILE_TOO_LARGE", StatusCode::PAYLOAD_TOO_LARGE)
+        }
+        _ if e.downcast_ref::<QuotaExceeded>().is_some() => {
+            ("QUOTA_EXCEEDED", StatusCode::INSUFFICIENT_STORAGE)
+        }
+        _ if e.downcast_ref::<InvalidProfileImage>().is_some() => {
+            ("INVALID_PROFILE_IMAGE", StatusCode::UNPROCESSABLE_ENTITY)
+        }
+        _ if e.downcast_ref::<UnsupportedFormat>().is_some() => {
+            ("UNSUPPORTED_MEDIA_TYPE", StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+        _ => {
+            tracing::error!("{e:?}");
+            ("INTERNAL_SERVER_ERROR", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    warp::reply::with_status(body, status).into_response()
 }
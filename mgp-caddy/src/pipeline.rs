@@ -0,0 +1,140 @@
+//! The full conversion an upload goes through before it is stored:
+//! transcoding, storyboards, and the cropping of profile images.
+
+use eyre::Result;
+use file_format::FileFormat;
+use std::io::Cursor;
+
+use crate::config::Config;
+use crate::convert::MediaInfo;
+use crate::fs::ORIGINAL_NAME;
+use crate::profile_image::{self, Crop, ProfileImageConfig, Purpose};
+use crate::storyboard::{self, StoryboardConfig};
+use crate::transcode::Registry;
+
+/// A converted upload, ready to be stored.
+#[derive(Debug)]
+pub struct Converted {
+    /// The file itself.
+    pub data: Vec<u8>,
+    /// The format of the file.
+    pub format: FileFormat,
+    /// Facts about the file, for its sidecar.
+    pub media: MediaInfo,
+    /// Named files to store alongside it.
+    pub attachments: Vec<(String, Vec<u8>)>,
+}
+
+/// Everything needed to convert uploads, built once from the configuration.
+#[derive(Debug)]
+pub struct Pipeline {
+    registry: Registry,
+    storyboard: StoryboardConfig,
+    profile_images: ProfileImageConfig,
+    retain_originals: bool,
+}
+
+impl Pipeline {
+    /// Builds the pipeline described by the configuration.
+    pub fn new(config: &Config) -> Result<Pipeline> {
+        config.storyboard.validate()?;
+
+        Ok(Pipeline {
+            registry: Registry::new(&config.transcoders, &config.transcode)?,
+            storyboard: config.storyboard.clone(),
+            profile_images: config.profile_images.clone(),
+            retain_originals: config.retain_originals,
+        })
+    }
+
+    /// Converts an upload. Ordinary post media goes through the transcoder registry,
+    /// while avatars and cover banners are cropped for their purpose.
+    ///
+    /// This is CPU-bound, and should be kept off the async workers.
+    pub fn convert(
+        &self,
+        payload: &[u8],
+        purpose: Option<(Purpose, Option<Crop>)>,
+    ) -> Result<Converted> {
+        match purpose {
+            Some((purpose, crop)) => self.convert_profile_image(payload, purpose, crop),
+            None => {
+                let mut converted = self.convert_media(payload, false)?;
+                // Profile images aren't retained, since the crop they were made with isn't kept.
+                if self.retain_originals {
+                    converted
+                        .attachments
+                        .push((ORIGINAL_NAME.to_owned(), payload.to_owned()));
+                }
+                Ok(converted)
+            }
+        }
+    }
+
+    /// Converts a gifv which was already stored, such as when reprocessing one whose original
+    /// wasn't retained. It is a WebM video by then, so it is marked as a gifv again rather
+    /// than being converted as an ordinary video, and gets no storyboard.
+    pub fn convert_gifv(&self, payload: &[u8]) -> Result<Converted> {
+        self.convert_media(payload, true)
+    }
+
+    /// Converts an ordinary upload with the transcoder registry, generating a storyboard for videos.
+    /// The upload is known to be a gifv if `gifv` is set.
+    fn convert_media(&self, payload: &[u8], gifv: bool) -> Result<Converted> {
+        let mut out = Cursor::new(Vec::new());
+        let (format, mut media) = self.registry.convert(&mut Cursor::new(payload), &mut out)?;
+        media.gifv |= gifv;
+
+        // Looping animations are too short to need scrubbing through.
+        let storyboard = match (format.kind(), media.duration) {
+            (file_format::Kind::Video, Some(duration)) if !media.gifv => {
+                out.set_position(0);
+                storyboard::generate(&mut out, duration, &self.storyboard)?
+            }
+            _ => None,
+        };
+        media.storyboard = storyboard.is_some();
+
+        let mut attachments = Vec::new();
+        if let Some(storyboard) = storyboard {
+            for (index, sheet) in storyboard.sheets.into_iter().enumerate() {
+                attachments.push((storyboard::sheet_name(index), sheet));
+            }
+            attachments.push((
+                storyboard::TRACK_NAME.to_owned(),
+                storyboard.track.into_bytes(),
+            ));
+        }
+
+        Ok(Converted {
+            data: out.into_inner(),
+            format,
+            media,
+            attachments,
+        })
+    }
+
+    /// Crops an avatar or cover banner. The widest rendition is stored as the file itself,
+    /// and every rendition is stored as an attachment named for its width.
+    fn convert_profile_image(
+        &self,
+        payload: &[u8],
+        purpose: Purpose,
+        crop: Option<Crop>,
+    ) -> Result<Converted> {
+        let renditions = profile_image::process(payload, purpose, crop, &self.profile_images)?;
+        let data = renditions.images[0].1.clone();
+        let attachments = renditions
+            .images
+            .into_iter()
+            .map(|(width, image)| (profile_image::rendition_name(purpose, width), image))
+            .collect();
+
+        Ok(Converted {
+            data,
+            format: FileFormat::Webp,
+            media: renditions.media,
+            attachments,
+        })
+    }
+}
@@ -0,0 +1,391 @@
+//! Processing of avatars and cover banners, the images referenced by a user's
+//! `profile_image` and `cover_image`.
+//!
+//! Rather than being stored as uploaded, these are cropped to a fixed aspect ratio,
+//! either to a rectangle chosen by the uploader or around a focal point, and stored
+//! at each of a set of widths so clients can pick the one closest to the size shown.
+//! They are held to stricter rules than ordinary post media: they must be still images
+//! within size limits, and the crop must be at least as wide as the smallest rendition.
+
+use eyre::{bail, Result};
+use file_format::FileFormat;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    imageops::{self, FilterType},
+    AnimationDecoder, ImageFormat, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt::Display, io::Cursor, str::FromStr};
+
+use crate::convert::{self, MediaInfo};
+
+/// What a profile image upload will be used as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Purpose {
+    /// A square picture shown next to the user's name.
+    Avatar,
+    /// A wide banner shown across the top of the user's profile.
+    Cover,
+}
+
+impl Purpose {
+    fn name(self) -> &'static str {
+        match self {
+            Purpose::Avatar => "avatar",
+            Purpose::Cover => "cover",
+        }
+    }
+}
+
+impl FromStr for Purpose {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "avatar" => Ok(Purpose::Avatar),
+            "cover" => Ok(Purpose::Cover),
+            _ => bail!("Unknown upload purpose `{s}`"),
+        }
+    }
+}
+
+/// Which part of the uploaded image to keep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crop {
+    /// A rectangle in source pixels. The largest area of the right aspect ratio
+    /// centered within it is kept.
+    Rect {
+        /// Left edge.
+        x: u32,
+        /// Top edge.
+        y: u32,
+        /// Width.
+        width: u32,
+        /// Height.
+        height: u32,
+    },
+    /// A point given as fractions of the source's width and height, from 0 to 1.
+    /// The largest area of the right aspect ratio is kept, as close to centered
+    /// on the point as the edges of the image allow.
+    Focus {
+        /// Horizontal position.
+        x: f64,
+        /// Vertical position.
+        y: f64,
+    },
+}
+
+impl Crop {
+    /// Parses a crop rectangle given as `x,y,width,height`.
+    pub fn parse_rect(s: &str) -> Result<Crop> {
+        match parse_list::<u32>(s)?.as_slice() {
+            &[x, y, width, height] if width > 0 && height > 0 => Ok(Crop::Rect {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => bail!("Crop rectangle `{s}` is not four numbers with a nonzero size"),
+        }
+    }
+
+    /// Parses a focal point given as `x,y`.
+    pub fn parse_focus(s: &str) -> Result<Crop> {
+        match parse_list::<f64>(s)?.as_slice() {
+            &[x, y] if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) => {
+                Ok(Crop::Focus { x, y })
+            }
+            _ => bail!("Focal point `{s}` is not two numbers from 0 to 1"),
+        }
+    }
+}
+
+fn parse_list<T: FromStr>(s: &str) -> Result<Vec<T>>
+where
+    T::Err: Error + Send + Sync + 'static,
+{
+    Ok(s.split(',')
+        .map(|part| part.trim().parse())
+        .collect::<Result<_, _>>()?)
+}
+
+/// Settings for avatars and cover banners.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProfileImageConfig {
+    /// The shape and sizes of avatars.
+    pub avatar: RenditionSet,
+    /// The shape and sizes of cover banners.
+    pub cover: RenditionSet,
+    /// The largest width or height in pixels an uploaded image may have.
+    pub max_dimension: u32,
+    /// Whether animated uploads are accepted, keeping only their first frame.
+    /// If not, they are refused.
+    pub flatten_animation: bool,
+    /// WebP quality of the renditions, from 0 to 100.
+    pub quality: u8,
+}
+
+impl Default for ProfileImageConfig {
+    fn default() -> Self {
+        ProfileImageConfig {
+            avatar: RenditionSet {
+                aspect_width: 1,
+                aspect_height: 1,
+                widths: vec![512, 256, 128, 64],
+            },
+            cover: RenditionSet {
+                aspect_width: 3,
+                aspect_height: 1,
+                widths: vec![1500, 960, 480],
+            },
+            max_dimension: 8192,
+            flatten_animation: false,
+            quality: 85,
+        }
+    }
+}
+
+impl ProfileImageConfig {
+    fn renditions(&self, purpose: Purpose) -> &RenditionSet {
+        match purpose {
+            Purpose::Avatar => &self.avatar,
+            Purpose::Cover => &self.cover,
+        }
+    }
+}
+
+/// The aspect ratio of a kind of profile image and the widths it is stored at.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenditionSet {
+    /// Horizontal part of the aspect ratio.
+    pub aspect_width: u32,
+    /// Vertical part of the aspect ratio.
+    pub aspect_height: u32,
+    /// Widths in pixels to store the image at. Crops narrower than the smallest are refused,
+    /// and images are never scaled up, so a small crop may not be stored at every width.
+    pub widths: Vec<u32>,
+}
+
+/// A cropped profile image, encoded to WebP at each width it could be stored at.
+#[derive(Debug, Clone)]
+pub struct Renditions {
+    /// The encoded images and their widths, widest first.
+    pub images: Vec<(u32, Vec<u8>)>,
+    /// Facts about the widest rendition, which is stored as the file itself.
+    pub media: MediaInfo,
+}
+
+/// The name the rendition of the given purpose and width is stored under.
+pub fn rendition_name(purpose: Purpose, width: u32) -> String {
+    format!("{}.{width}.webp", purpose.name())
+}
+
+/// The purpose and width of a profile image rendition, from the name it is stored under.
+pub fn parse_rendition_name(name: &str) -> Option<(Purpose, u32)> {
+    let (purpose, rest) = name.split_once('.')?;
+    let width = rest.strip_suffix(".webp")?;
+    if width.is_empty() || !width.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((purpose.parse().ok()?, width.parse().ok()?))
+}
+
+/// Whether the given name is that of a profile image rendition.
+pub fn is_rendition_name(name: &str) -> bool {
+    name.strip_prefix("avatar.")
+        .or_else(|| name.strip_prefix("cover."))
+        .and_then(|rest| rest.strip_suffix(".webp"))
+        .is_some_and(|width| !width.is_empty() && width.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Checks an uploaded image against the rules for profile images, crops it
+/// for the given purpose and encodes it at each configured width.
+///
+/// Returns an [`UnsupportedFormat`](crate::transcode::UnsupportedFormat) error if the data
+/// is not an image, and an [`InvalidProfileImage`] error if it breaks any other rule.
+pub fn process(
+    data: &[u8],
+    purpose: Purpose,
+    crop: Option<Crop>,
+    config: &ProfileImageConfig,
+) -> Result<Renditions> {
+    let format = FileFormat::from_bytes(data);
+    let image_format = match format {
+        FileFormat::JointPhotographicExpertsGroup => ImageFormat::Jpeg,
+        FileFormat::PortableNetworkGraphics => ImageFormat::Png,
+        FileFormat::GraphicsInterchangeFormat => ImageFormat::Gif,
+        FileFormat::Webp => ImageFormat::WebP,
+        _ => bail!(crate::transcode::UnsupportedFormat(format)),
+    };
+
+    // Check the size before decoding, so huge images are refused without allocating for them.
+    let reader = image::io::Reader::with_format(Cursor::new(data), image_format);
+    let (width, height) = reader.into_dimensions()?;
+    if width > config.max_dimension || height > config.max_dimension {
+        bail!(InvalidProfileImage("larger than the maximum dimensions"));
+    }
+    if !config.flatten_animation && is_animated(data, image_format)? {
+        bail!(InvalidProfileImage("animated"));
+    }
+
+    // Only the first frame of an animation is decoded.
+    let image = image::load_from_memory_with_format(data, image_format)?.to_rgba8();
+    let set = config.renditions(purpose);
+    let (x, y, crop_width, crop_height) = crop_area(&image, crop, set)?;
+    let cropped = imageops::crop_imm(&image, x, y, crop_width, crop_height).to_image();
+
+    let mut widths: Vec<u32> = set
+        .widths
+        .iter()
+        .copied()
+        .filter(|&width| width <= crop_width)
+        .collect();
+    widths.sort_unstable_by(|a, b| b.cmp(a));
+    widths.dedup();
+    if widths.is_empty() {
+        bail!(InvalidProfileImage(
+            "cropped smaller than the smallest size"
+        ));
+    }
+
+    let mut images = Vec::with_capacity(widths.len());
+    for &width in &widths {
+        let height = scaled_height(width, set);
+        let resized = match (width, height) == (crop_width, crop_height) {
+            true => cropped.clone(),
+            false => imageops::resize(&cropped, width, height, FilterType::Lanczos3),
+        };
+        images.push((width, convert::encode_webp(&resized, config.quality)?));
+    }
+
+    let widest = widths[0];
+    Ok(Renditions {
+        images,
+        media: MediaInfo {
+            kind: FileFormat::Webp.media_type().to_owned(),
+            source_format: format.media_type().to_owned(),
+            width: Some(widest),
+            height: Some(scaled_height(widest, set)),
+            frame_count: Some(1),
+            video_codec: Some("webp".to_owned()),
+            purpose: Some(purpose),
+            renditions: widths,
+            ..Default::default()
+        },
+    })
+}
+
+/// The height of a rendition of the given width.
+fn scaled_height(width: u32, set: &RenditionSet) -> u32 {
+    ((width as u64 * set.aspect_height as u64 + set.aspect_width as u64 / 2)
+        / set.aspect_width as u64)
+        .max(1) as u32
+}
+
+/// Finds the area of the image to keep, as `(x, y, width, height)`.
+fn crop_area(
+    image: &RgbaImage,
+    crop: Option<Crop>,
+    set: &RenditionSet,
+) -> Result<(u32, u32, u32, u32)> {
+    if set.aspect_width == 0 || set.aspect_height == 0 {
+        bail!("Profile image aspect ratios must not be zero");
+    }
+
+    // The region to fit the crop within, and the point to center it on.
+    let (region, center) = match crop {
+        Some(Crop::Rect {
+            x,
+            y,
+            width,
+            height,
+        }) => {
+            let fits = x
+                .checked_add(width)
+                .is_some_and(|right| right <= image.width())
+                && y.checked_add(height)
+                    .is_some_and(|bottom| bottom <= image.height());
+            if !fits {
+                bail!(InvalidProfileImage("crop rectangle outside the image"));
+            }
+            (
+                (x, y, width, height),
+                (
+                    x as f64 + width as f64 / 2.0,
+                    y as f64 + height as f64 / 2.0,
+                ),
+            )
+        }
+        Some(Crop::Focus { x, y }) => (
+            (0, 0, image.width(), image.height()),
+            (x * image.width() as f64, y * image.height() as f64),
+        ),
+        None => (
+            (0, 0, image.width(), image.height()),
+            (image.width() as f64 / 2.0, image.height() as f64 / 2.0),
+        ),
+    };
+    let (region_x, region_y, region_width, region_height) = region;
+
+    // The largest area of the right aspect ratio which fits in the region.
+    let (width, height) = match region_width as u64 * set.aspect_height as u64
+        > region_height as u64 * set.aspect_width as u64
+    {
+        true => (
+            (region_height as u64 * set.aspect_width as u64 / set.aspect_height as u64) as u32,
+            region_height,
+        ),
+        false => (
+            region_width,
+            (region_width as u64 * set.aspect_height as u64 / set.aspect_width as u64) as u32,
+        ),
+    };
+    if width == 0 || height == 0 {
+        bail!(InvalidProfileImage(
+            "cropped smaller than the smallest size"
+        ));
+    }
+
+    let place = |center: f64, length: u32, start: u32, extent: u32| {
+        let max = (start + extent - length) as f64;
+        (center - length as f64 / 2.0)
+            .round()
+            .clamp(start as f64, max) as u32
+    };
+    Ok((
+        place(center.0, width, region_x, region_width),
+        place(center.1, height, region_y, region_height),
+        width,
+        height,
+    ))
+}
+
+/// Whether an image has more than one frame.
+fn is_animated(data: &[u8], format: ImageFormat) -> Result<bool> {
+    Ok(match format {
+        ImageFormat::Gif => {
+            GifDecoder::new(Cursor::new(data))?
+                .into_frames()
+                .take(2)
+                .count()
+                > 1
+        }
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data))?.is_apng(),
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(data))?.has_animation(),
+        _ => false,
+    })
+}
+
+/// The error returned when an uploaded profile image breaks the rules for them.
+#[derive(Debug)]
+pub struct InvalidProfileImage(pub &'static str);
+
+impl Display for InvalidProfileImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Profile image refused: {}", self.0)
+    }
+}
+
+impl Error for InvalidProfileImage {}
@@ -0,0 +1,198 @@
+//! Automatic tuning of encoder quality settings against an objective quality metric.
+//!
+//! Rather than encoding everything at one fixed quality, an upload can be encoded a few
+//! times while binary searching for the cheapest setting whose output still scores
+//! at least a target SSIM or PSNR against the source.
+
+use eyre::{ensure, Result};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// The PSNR reported for identical images, which would otherwise be infinite.
+pub const PSNR_IDENTICAL: f64 = 100.0;
+
+/// An objective measure of how closely an encoding resembles its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// Structural similarity of the luma plane, from 0 to 1.
+    Ssim,
+    /// Peak signal-to-noise ratio, in decibels.
+    Psnr,
+}
+
+impl Metric {
+    /// The score encodings must reach under this metric, unless configured otherwise.
+    pub fn default_target(self) -> f64 {
+        match self {
+            Metric::Ssim => 0.95,
+            Metric::Psnr => 40.0,
+        }
+    }
+}
+
+/// Settings for automatic quality tuning.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QualityTuning {
+    /// Whether encodings are tuned at all. If not, the profile's fixed settings are used.
+    pub enabled: bool,
+    /// The metric encodings are scored with.
+    pub metric: Metric,
+    /// The score an encoding must reach, or the [default](Metric::default_target) of the metric.
+    pub target: Option<f64>,
+    /// The most encodings to try for a single upload.
+    pub max_attempts: u32,
+}
+
+impl Default for QualityTuning {
+    fn default() -> Self {
+        QualityTuning {
+            enabled: false,
+            metric: Metric::Ssim,
+            target: None,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl QualityTuning {
+    /// The score an encoding must reach.
+    pub fn target(&self) -> f64 {
+        self.target.unwrap_or_else(|| self.metric.default_target())
+    }
+}
+
+/// The outcome of tuning an encoding, as recorded in the file's metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tuning {
+    /// The metric the encoding was scored with.
+    pub metric: Metric,
+    /// The chosen setting: WebP quality for images, VP9 CRF for videos.
+    pub quality: u8,
+    /// The score the chosen encoding achieved.
+    pub score: f64,
+}
+
+/// Binary searches `levels` for the lowest level whose encoding scores at least the target,
+/// where higher levels mean higher quality. `attempt` encodes at a level and scores the result.
+///
+/// If no level tried reaches the target, the highest one tried is chosen.
+/// Returns the chosen encoding along with its level and score.
+pub fn search<T>(
+    levels: RangeInclusive<u8>,
+    config: &QualityTuning,
+    mut attempt: impl FnMut(u8) -> Result<(T, f64)>,
+) -> Result<(T, u8, f64)> {
+    ensure!(
+        config.max_attempts > 0,
+        "Quality tuning needs at least one attempt"
+    );
+
+    let target = config.target();
+    let (mut low, mut high) = (*levels.start(), *levels.end());
+    let mut passing = None;
+    let mut failing = None;
+
+    for _ in 0..config.max_attempts {
+        if low > high {
+            break;
+        }
+
+        let level = low + (high - low) / 2;
+        let (encoded, score) = attempt(level)?;
+        tracing::debug!("Quality level {level} scored {score:.4}");
+
+        if score >= target {
+            passing = Some((encoded, level, score));
+            match level.checked_sub(1) {
+                Some(level) => high = level,
+                None => break,
+            }
+        } else {
+            // Every level tried after a failure is higher, so this is the best failure so far.
+            failing = Some((encoded, level, score));
+            low = level + 1;
+        }
+    }
+
+    Ok(passing
+        .or(failing)
+        .expect("At least one level is always tried"))
+}
+
+/// Scores how closely `encoded` resembles `source`, which must have the same dimensions.
+pub fn score_images(metric: Metric, source: &RgbaImage, encoded: &RgbaImage) -> f64 {
+    match metric {
+        Metric::Ssim => ssim(source, encoded),
+        Metric::Psnr => psnr(source, encoded),
+    }
+}
+
+/// The mean SSIM of the luma of two images over 8x8 windows.
+fn ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let luma = |image: &RgbaImage, x: u32, y: u32| {
+        let [r, g, b, _] = image.get_pixel(x, y).0;
+        0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+    };
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for wy in (0..a.height()).step_by(WINDOW as usize) {
+        for wx in (0..a.width()).step_by(WINDOW as usize) {
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+            let mut n = 0.0;
+            for y in wy..(wy + WINDOW).min(a.height()) {
+                for x in wx..(wx + WINDOW).min(a.width()) {
+                    let (la, lb) = (luma(a, x, y), luma(b, x, y));
+                    sum_a += la;
+                    sum_b += lb;
+                    sum_aa += la * la;
+                    sum_bb += lb * lb;
+                    sum_ab += la * lb;
+                    n += 1.0;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    match windows {
+        0 => 1.0,
+        _ => total / windows as f64,
+    }
+}
+
+/// The PSNR of the color channels of two images.
+fn psnr(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let mut squared_error = 0.0;
+    let mut samples = 0;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        for channel in 0..3 {
+            let difference = pa.0[channel] as f64 - pb.0[channel] as f64;
+            squared_error += difference * difference;
+            samples += 1;
+        }
+    }
+
+    if samples == 0 || squared_error == 0.0 {
+        return PSNR_IDENTICAL;
+    }
+
+    let mse = squared_error / samples as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(PSNR_IDENTICAL)
+}
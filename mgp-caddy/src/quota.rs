@@ -0,0 +1,109 @@
+//! Storage allowances of uploaders, and accounting of what they use.
+//!
+//! The gateway in front of the caddy names the uploader of each upload, and the tier they
+//! are on, in request headers. Each uploader's usage is counted as their files are stored
+//! and deleted, and an upload which would take them past their tier's allowance is refused.
+//! Files are never deduplicated, so every file is counted in full against its own uploader.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+/// Settings for uploader quotas.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// The request header the gateway names the uploader in.
+    pub uploader_header: String,
+    /// The request header the gateway names the uploader's tier in.
+    pub tier_header: String,
+    /// The allowance of uploaders on no tier, or on one which isn't listed.
+    pub default: Quota,
+    /// The allowance of each tier, by name.
+    pub tiers: HashMap<String, Quota>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            uploader_header: "x-uploader-id".to_owned(),
+            tier_header: "x-uploader-tier".to_owned(),
+            default: Quota::default(),
+            tiers: HashMap::new(),
+        }
+    }
+}
+
+impl QuotaConfig {
+    /// The allowance of the given tier.
+    pub fn quota(&self, tier: Option<&str>) -> &Quota {
+        tier.and_then(|tier| self.tiers.get(tier))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// How much an uploader may store. Anything left unset is unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    /// The most bytes the uploader's files may take up altogether.
+    pub bytes: Option<u64>,
+    /// The most files the uploader may have stored.
+    pub files: Option<u64>,
+}
+
+impl Quota {
+    /// Checks that a file taking up `size` bytes along with everything stored with it
+    /// can be added to what an uploader already uses.
+    pub fn admit(&self, usage: &Usage, size: u64) -> Result<(), QuotaExceeded> {
+        if self.bytes.is_some_and(|bytes| size > bytes) {
+            return Err(QuotaExceeded::FileTooLarge);
+        }
+
+        let over_bytes = self
+            .bytes
+            .is_some_and(|bytes| usage.bytes.saturating_add(size) > bytes);
+        let over_files = self.files.is_some_and(|files| usage.files >= files);
+        match over_bytes || over_files {
+            true => Err(QuotaExceeded::Full),
+            false => Ok(()),
+        }
+    }
+}
+
+/// How much an uploader currently has stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// The bytes taken up by the uploader's files and everything stored alongside them.
+    pub bytes: u64,
+    /// How many files the uploader has stored.
+    pub files: u64,
+}
+
+/// Makes sure an uploader ID named by the gateway is usable: between 1 and 128 ASCII
+/// letters, digits, `-` and `_`.
+pub fn valid_uploader(uploader: &str) -> bool {
+    (1..=128).contains(&uploader.len())
+        && uploader
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+/// Indicates an upload was refused because of its uploader's quota.
+#[derive(Debug)]
+pub enum QuotaExceeded {
+    /// The file is larger than the uploader's whole allowance, so it could never be stored.
+    FileTooLarge,
+    /// Storing the file would take the uploader past their allowance.
+    Full,
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileTooLarge => write!(f, "The file is larger than the uploader's quota"),
+            Self::Full => write!(f, "The uploader's quota is used up"),
+        }
+    }
+}
+
+impl Error for QuotaExceeded {}
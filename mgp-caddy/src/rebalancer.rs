@@ -0,0 +1,39 @@
+//! The background rebalancer, which moves files between storage volumes.
+//!
+//! Adding a volume to a sharded store changes where some files belong, so the rebalancer
+//! moves them there while the store stays in use. Other backends never need files moved.
+
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::background;
+use crate::fs::FileStore;
+
+/// Settings for the background rebalancer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RebalancerConfig {
+    /// Seconds between rebalancing runs.
+    pub interval: u64,
+}
+
+impl Default for RebalancerConfig {
+    fn default() -> Self {
+        RebalancerConfig { interval: 3600 }
+    }
+}
+
+/// Starts rebalancing in the background, once right away and then every interval.
+pub fn spawn(store: Arc<FileStore>, config: &RebalancerConfig) -> JoinHandle<()> {
+    background::every(config.interval, move || {
+        let store = store.clone();
+        async move {
+            match store.rebalance().await {
+                Ok(0) => (),
+                Ok(moved) => tracing::info!(moved, "Moved files between volumes"),
+                Err(e) => tracing::error!("Rebalancing volumes failed: {e:?}"),
+            }
+        }
+    })
+}
@@ -0,0 +1,231 @@
+//! The `mgp-caddy reprocess` subcommand, which runs everything already in the store
+//! through the current conversion pipeline, so that changed encoding settings and
+//! conversion fixes reach files uploaded before them.
+//!
+//! Files are converted from their retained original where there is one, and otherwise
+//! from the stored file itself. Avatars and cover banners are always converted from
+//! their stored widest rendition, since the crop they were uploaded with isn't kept.
+
+use eyre::{bail, ensure, eyre, Result};
+use futures_util::StreamExt;
+use std::{collections::HashSet, io::ErrorKind, path::Path, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::fs::{FileMeta, FileStore, ORIGINAL_NAME};
+use crate::id::NormalizedId;
+use crate::pipeline::Pipeline;
+use crate::profile_image::Purpose;
+
+/// The file in the working directory recording which files a run has finished,
+/// one normalized ID per line.
+pub const JOURNAL_NAME: &str = "reprocess.journal";
+
+/// Command line options of the `reprocess` subcommand.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Only list what would be reprocessed, without converting or changing anything.
+    pub dry_run: bool,
+    /// Skip files an earlier run already finished, as recorded in its journal.
+    pub resume: bool,
+    /// The most files to convert at once.
+    pub jobs: usize,
+}
+
+impl Options {
+    /// Parses the arguments following `reprocess` on the command line:
+    /// `[--dry-run] [--resume] [--jobs N]`.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut options = Options {
+            dry_run: false,
+            resume: false,
+            jobs: std::thread::available_parallelism().map_or(1, usize::from),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => options.dry_run = true,
+                "--resume" => options.resume = true,
+                "--jobs" => {
+                    let jobs = args
+                        .next()
+                        .ok_or_else(|| eyre!("`--jobs` needs a number of jobs"))?;
+                    options.jobs = jobs.parse()?;
+                    ensure!(options.jobs > 0, "`--jobs` must be at least 1");
+                }
+                _ => bail!("Unknown option `{arg}`"),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// Where a file is converted from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// The upload as it was received, kept when originals are retained.
+    Original,
+    /// The converted file in the store.
+    Stored,
+}
+
+/// Reprocesses every file in the store, or in a dry run lists what would be.
+///
+/// Each finished file is recorded in the journal, so an interrupted run can be picked up
+/// again with `--resume`. Files which fail are reported and left as they were, and
+/// are retried by the next resumed run.
+pub async fn run(store: Arc<FileStore>, pipeline: Arc<Pipeline>, options: Options) -> Result<()> {
+    let journal_path = Path::new(JOURNAL_NAME);
+    let done: HashSet<String> = match options.resume {
+        true => match tokio::fs::read_to_string(journal_path).await {
+            Ok(journal) => journal.lines().map(str::to_owned).collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        },
+        false => HashSet::new(),
+    };
+
+    let ids: Vec<NormalizedId> = store
+        .list()
+        .await?
+        .into_iter()
+        .filter(|id| !done.contains(id.as_str()))
+        .collect();
+    println!(
+        "{} files to reprocess, {} already done",
+        ids.len(),
+        done.len()
+    );
+
+    if options.dry_run {
+        for id in &ids {
+            let meta = store.read_meta(id).await.ok();
+            match plan(&store, id, meta.as_ref()).await {
+                Ok((Source::Original, _)) => println!("{id}: from its retained original"),
+                Ok((Source::Stored, _)) => println!("{id}: from the stored file"),
+                Err(e) => println!("{id}: cannot be reprocessed: {e}"),
+            }
+        }
+        return Ok(());
+    }
+
+    // A fresh run starts a fresh journal, while a resumed one carries on with its own.
+    let mut journal = match options.resume {
+        true => {
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(journal_path)
+                .await?
+        }
+        false => tokio::fs::File::create(journal_path).await?,
+    };
+
+    let mut results = futures_util::stream::iter(ids)
+        .map(|id| {
+            let store = store.clone();
+            let pipeline = pipeline.clone();
+            async move {
+                let result = reprocess(&store, pipeline, &id).await;
+                (id, result)
+            }
+        })
+        .buffer_unordered(options.jobs);
+
+    let (mut finished, mut failed) = (0, 0);
+    while let Some((id, result)) = results.next().await {
+        match result {
+            Ok(()) => {
+                journal.write_all(format!("{id}\n").as_bytes()).await?;
+                journal.flush().await?;
+                finished += 1;
+                println!("{id}: reprocessed");
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{id}: failed: {e:?}");
+            }
+        }
+    }
+
+    println!("{finished} files reprocessed, {failed} failed");
+    ensure!(
+        failed == 0,
+        "{failed} files failed to reprocess, and can be retried with `--resume`"
+    );
+
+    Ok(())
+}
+
+/// Decides where a file is converted from, given its metadata, and what it was uploaded as.
+async fn plan(
+    store: &FileStore,
+    id: &NormalizedId,
+    meta: Option<&FileMeta>,
+) -> Result<(Source, Option<Purpose>)> {
+    // Files stored before their media facts were recorded have no sidecar,
+    // and can only have been ordinary post media.
+    let purpose = meta.and_then(|meta| meta.media.purpose);
+    if purpose.is_some() {
+        return Ok((Source::Stored, purpose));
+    }
+
+    match store.read_attachment(id, ORIGINAL_NAME).await {
+        Ok(_) => Ok((Source::Original, None)),
+        Err(_) => {
+            // Still make sure the file itself can be read.
+            _ = store.read(id).await?;
+            Ok((Source::Stored, None))
+        }
+    }
+}
+
+/// Converts one file afresh and swaps the result in for the stored version.
+async fn reprocess(store: &FileStore, pipeline: Arc<Pipeline>, id: &NormalizedId) -> Result<()> {
+    let meta = store.read_meta(id).await.ok();
+    let (source, purpose) = plan(store, id, meta.as_ref()).await?;
+    // A stored gifv is already a WebM video, and would otherwise be converted as an ordinary one.
+    let gifv = source == Source::Stored && meta.as_ref().is_some_and(|meta| meta.media.gifv);
+
+    let mut data = Vec::new();
+    match source {
+        Source::Original => {
+            let mut original = Box::pin(store.read_attachment(id, ORIGINAL_NAME).await?);
+            _ = original.read_to_end(&mut data).await?;
+        }
+        Source::Stored => {
+            let mut stored = Box::pin(store.read(id).await?);
+            _ = stored.read_to_end(&mut data).await?;
+        }
+    }
+
+    let job = id.to_string();
+    let mut converted = tokio::task::spawn_blocking(move || {
+        let _job = ffmpeg_next::log::enter_job(job);
+        match gifv {
+            true => pipeline.convert_gifv(&data),
+            false => pipeline.convert(&data, purpose.map(|purpose| (purpose, None))),
+        }
+    })
+    .await??;
+
+    // The retained original is left as it is.
+    converted
+        .attachments
+        .retain(|(name, _)| name != ORIGINAL_NAME);
+    // Converting the stored file would otherwise record its own format as the source.
+    if source == Source::Stored {
+        if let Some(meta) = meta {
+            converted.media.source_format = meta.media.source_format;
+        }
+    }
+
+    store
+        .replace(
+            id,
+            &converted.data,
+            &converted.media,
+            &converted.attachments,
+        )
+        .await
+}
@@ -0,0 +1,92 @@
+//! The background scrubber, which checks stored files against their checksums.
+//!
+//! Every file and attachment has a checksum recorded when it is written, so bit rot
+//! and partial writes can be found later. The scrubber walks the whole store now and
+//! then, quarantining what it finds corrupted, and its latest findings are served
+//! by the health endpoint. Metadata records which are missing, corrupt or out of date
+//! are rebuilt on the way. The same check can be run once with `mgp-caddy scrub`.
+
+use eyre::{ensure, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::background;
+use crate::fs::{FileStore, ScrubReport};
+
+/// Settings for the background scrubber.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScrubberConfig {
+    /// Seconds between scrubbing runs.
+    pub interval: u64,
+}
+
+impl Default for ScrubberConfig {
+    fn default() -> Self {
+        ScrubberConfig { interval: 86400 }
+    }
+}
+
+/// Starts scrubbing in the background, once right away and then every interval.
+/// Returns a receiver of the report of the latest finished run.
+pub fn spawn(
+    store: Arc<FileStore>,
+    config: &ScrubberConfig,
+) -> watch::Receiver<Option<ScrubReport>> {
+    let (sender, receiver) = watch::channel(None);
+
+    _ = background::every(config.interval, move || {
+        let (store, sender) = (store.clone(), sender.clone());
+        async move {
+            match store.scrub().await {
+                Ok(report) => {
+                    tracing::info!(
+                        checked = report.checked,
+                        recorded = report.recorded,
+                        corrupt = report.corrupt.len(),
+                        missing = report.missing.len(),
+                        stray = report.stray.len(),
+                        rebuilt = report.rebuilt.len(),
+                        "Scrubbed the store"
+                    );
+                    _ = sender.send_replace(Some(report));
+                }
+                Err(e) => tracing::error!("Scrubbing the store failed: {e:?}"),
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Scrubs the store once, listing everything found wrong.
+/// Fails if anything was.
+pub async fn run(store: Arc<FileStore>) -> Result<()> {
+    let report = store.scrub().await?;
+
+    for key in &report.corrupt {
+        println!("{key}: corrupt, quarantined");
+    }
+    for key in &report.missing {
+        println!("{key}: missing");
+    }
+    for key in &report.stray {
+        println!("{key}: stray");
+    }
+    for key in &report.rebuilt {
+        println!("{key}: rebuilt");
+    }
+    println!(
+        "{} objects checked, {} checksums recorded, {} corrupt, {} missing, {} stray, {} metadata records rebuilt",
+        report.checked,
+        report.recorded,
+        report.corrupt.len(),
+        report.missing.len(),
+        report.stray.len(),
+        report.rebuilt.len()
+    );
+
+    ensure!(report.is_clean(), "The store has problems");
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! Signed, expiring URLs for reading files.
+//!
+//! Media of private accounts mustn't be fetchable by anyone who learns its ID, so the caddy
+//! can require reads to carry a signature made with a key it shares with the gateway.
+//! A signed URL has three query parameters: `exp`, when it expires as a UNIX timestamp,
+//! `kid`, the ID of the key it was signed with, and `sig`, the HMAC-SHA256 of its path,
+//! expiry and key ID in URL-safe base64. Several keys can be configured at once, so a new
+//! key can be rolled out before URLs signed with the old one stop being accepted. Keys are
+//! configured like the master keys of [encryption](crate::encryption), as 32 bytes in base64.
+
+use base64::{alphabet, engine, engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use eyre::{ensure, eyre, Result};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use crate::encryption;
+
+const BASE64: engine::GeneralPurpose =
+    engine::GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::NO_PAD);
+
+/// Settings for signed URLs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    /// Whether reads must be signed. URLs can be signed either way.
+    pub enforce: bool,
+    /// The keys URLs may be signed with, by key ID, each 32 bytes in base64.
+    pub keys: HashMap<String, String>,
+    /// The ID of the key new URLs are signed with.
+    pub current_key: Option<String>,
+    /// Seconds signed URLs stay valid for, unless asked otherwise.
+    pub ttl: u64,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            enforce: false,
+            keys: HashMap::new(),
+            current_key: None,
+            ttl: 3600,
+        }
+    }
+}
+
+/// Signs URLs, and checks the signatures of those requested.
+pub struct Signer {
+    enforce: bool,
+    keys: HashMap<String, [u8; encryption::KEY_LEN]>,
+    current_key: Option<String>,
+    /// Seconds signed URLs stay valid for, unless asked otherwise.
+    pub ttl: u64,
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Signer")
+            .field("enforce", &self.enforce)
+            .field("keys", &self.keys.keys())
+            .field("current_key", &self.current_key)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl Signer {
+    /// Makes sure the configured keys are usable.
+    pub fn new(config: &SigningConfig) -> Result<Signer> {
+        let mut keys = HashMap::new();
+        for (kid, key) in &config.keys {
+            _ = keys.insert(
+                kid.clone(),
+                encryption::decode_key("signing key", kid, key)?,
+            );
+        }
+        if let Some(current_key) = &config.current_key {
+            ensure!(
+                config.keys.contains_key(current_key),
+                "The current signing key `{current_key}` isn't one of the signing keys"
+            );
+        }
+        ensure!(
+            !config.enforce || !config.keys.is_empty(),
+            "Signed URLs can't be enforced without any signing keys"
+        );
+
+        Ok(Signer {
+            enforce: config.enforce,
+            keys,
+            current_key: config.current_key.clone(),
+            ttl: config.ttl,
+        })
+    }
+
+    /// Signs a path with the current key, so it can be read until `expires_at`.
+    /// Returns the path with the signature added as its query string.
+    pub fn sign(&self, path: &str, expires_at: DateTime<Utc>) -> Result<String> {
+        let kid = self
+            .current_key
+            .as_deref()
+            .ok_or_else(|| eyre!("No current signing key is configured"))?;
+        let exp = expires_at.timestamp();
+        let sig = BASE64.encode(self.mac(kid, path, exp)?.finalize().into_bytes());
+
+        Ok(format!("{path}?exp={exp}&kid={kid}&sig={sig}"))
+    }
+
+    /// Checks the signature of a requested path, given its query string,
+    /// if signatures are enforced.
+    pub fn check(&self, path: &str, query: &HashMap<String, String>) -> Result<(), SignatureError> {
+        match self.enforce {
+            true => self.verify(path, query),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks the signature of a requested path, given its query string.
+    pub fn verify(
+        &self,
+        path: &str,
+        query: &HashMap<String, String>,
+    ) -> Result<(), SignatureError> {
+        let (exp, kid, sig) = match (query.get("exp"), query.get("kid"), query.get("sig")) {
+            (Some(exp), Some(kid), Some(sig)) => (exp, kid, sig),
+            _ => return Err(SignatureError::Missing),
+        };
+        let exp: i64 = exp.parse().map_err(|_| SignatureError::Invalid)?;
+        let sig = BASE64.decode(sig).map_err(|_| SignatureError::Invalid)?;
+
+        // Checked before the expiry, so a forged URL is never told it has merely expired.
+        self.mac(kid, path, exp)
+            .map_err(|_| SignatureError::Invalid)?
+            .verify_slice(&sig)
+            .map_err(|_| SignatureError::Invalid)?;
+        match exp > Utc::now().timestamp() {
+            true => Ok(()),
+            false => Err(SignatureError::Expired),
+        }
+    }
+
+    /// The MAC of a path, expiry and key ID, keyed with the named key.
+    fn mac(&self, kid: &str, path: &str, exp: i64) -> Result<Hmac<Sha256>> {
+        let key = self
+            .keys
+            .get(kid)
+            .ok_or_else(|| eyre!("Unknown signing key `{kid}`"))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+        mac.update(format!("{path}\n{exp}\n{kid}").as_bytes());
+        Ok(mac)
+    }
+}
+
+/// Indicates a read was refused because its URL isn't properly signed.
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The URL has no signature.
+    Missing,
+    /// The signature is malformed, made with an unknown key, or doesn't match the URL.
+    Invalid,
+    /// The signature is good, but the URL has expired.
+    Expired,
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "The URL isn't signed"),
+            Self::Invalid => write!(f, "The URL's signature is invalid"),
+            Self::Expired => write!(f, "The URL has expired"),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use chrono::Duration;
+
+    const PATH: &str = "/file/abc123def456ghi789jkl012mno345pqr678st.webp";
+
+    fn config(keys: &[(&str, u8)], current_key: Option<&str>) -> SigningConfig {
+        SigningConfig {
+            enforce: true,
+            keys: keys
+                .iter()
+                .map(|(kid, byte)| (kid.to_string(), STANDARD.encode([*byte; 32])))
+                .collect(),
+            current_key: current_key.map(str::to_owned),
+            ttl: 3600,
+        }
+    }
+
+    /// Splits a signed URL into its path and query.
+    fn split(url: &str) -> (String, HashMap<String, String>) {
+        let (path, query) = url.split_once('?').unwrap();
+        let query = query
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap())
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        (path.to_owned(), query)
+    }
+
+    fn in_a_minute() -> DateTime<Utc> {
+        Utc::now() + Duration::minutes(1)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = Signer::new(&config(&[("a", 1)], Some("a"))).unwrap();
+        let url = signer.sign(PATH, in_a_minute()).unwrap();
+        let (path, query) = split(&url);
+        assert_eq!(path, PATH);
+        assert_eq!(query["kid"], "a");
+        signer.verify(&path, &query).unwrap();
+        signer.check(&path, &query).unwrap();
+    }
+
+    #[test]
+    fn test_tampering() {
+        let signer = Signer::new(&config(&[("a", 1), ("b", 2)], Some("a"))).unwrap();
+        let (path, query) = split(&signer.sign(PATH, in_a_minute()).unwrap());
+
+        let invalid = |path: &str, query: &HashMap<String, String>| {
+            matches!(signer.verify(path, query), Err(SignatureError::Invalid))
+        };
+        assert!(invalid(&path.replace(".webp", ".webm"), &query));
+        assert!(invalid(&format!("{path}.storyboard.json"), &query));
+
+        let with = |name: &str, value: &str| {
+            let mut query = query.clone();
+            _ = query.insert(name.to_owned(), value.to_owned());
+            query
+        };
+        let exp = query["exp"].parse::<i64>().unwrap();
+        assert!(invalid(&path, &with("exp", &(exp + 3600).to_string())));
+        assert!(invalid(&path, &with("exp", "soon")));
+        // Another key the signer knows, and one it doesn't.
+        assert!(invalid(&path, &with("kid", "b")));
+        assert!(invalid(&path, &with("kid", "c")));
+        assert!(invalid(&path, &with("sig", "not base64!")));
+        assert!(invalid(&path, &with("sig", &query["sig"][1..])));
+
+        for name in ["exp", "kid", "sig"] {
+            let mut query = query.clone();
+            _ = query.remove(name);
+            assert!(matches!(
+                signer.verify(&path, &query),
+                Err(SignatureError::Missing)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        let signer = Signer::new(&config(&[("a", 1)], Some("a"))).unwrap();
+        let (path, query) = split(
+            &signer
+                .sign(PATH, Utc::now() - Duration::seconds(1))
+                .unwrap(),
+        );
+        assert!(matches!(
+            signer.verify(&path, &query),
+            Err(SignatureError::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Signer::new(&config(&[("a", 1), ("b", 2)], Some("a"))).unwrap();
+        let url = old.sign(PATH, in_a_minute()).unwrap();
+
+        let new = Signer::new(&config(&[("a", 1), ("b", 2)], Some("b"))).unwrap();
+        let (path, query) = split(&url);
+        new.verify(&path, &query).unwrap();
+        let (path, query) = split(&new.sign(PATH, in_a_minute()).unwrap());
+        assert_eq!(query["kid"], "b");
+        old.verify(&path, &query).unwrap();
+
+        // Once the old key is removed, so are the URLs signed with it.
+        let retired = Signer::new(&config(&[("b", 2)], Some("b"))).unwrap();
+        let (path, query) = split(&url);
+        assert!(matches!(
+            retired.verify(&path, &query),
+            Err(SignatureError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_not_enforced() {
+        let signer = Signer::new(&SigningConfig {
+            enforce: false,
+            ..config(&[("a", 1)], Some("a"))
+        })
+        .unwrap();
+        signer.check(PATH, &HashMap::new()).unwrap();
+        let (path, mut query) = split(&signer.sign(PATH, in_a_minute()).unwrap());
+        _ = query.insert("kid".to_owned(), "c".to_owned());
+        signer.check(&path, &query).unwrap();
+        assert!(signer.verify(&path, &query).is_err());
+
+        // Without any keys, nothing can be signed, but everything is still read.
+        let signer = Signer::new(&SigningConfig::default()).unwrap();
+        signer.check(PATH, &HashMap::new()).unwrap();
+        assert!(signer.sign(PATH, in_a_minute()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let short = SigningConfig {
+            keys: HashMap::from([("a".to_owned(), STANDARD.encode([1; 16]))]),
+            ..config(&[], None)
+        };
+        let not_base64 = SigningConfig {
+            keys: HashMap::from([("a".to_owned(), "a".repeat(44))]),
+            ..config(&[], None)
+        };
+        for config in [
+            short,
+            not_base64,
+            config(&[("a", 1)], Some("b")),
+            config(&[], None),
+        ] {
+            assert!(Signer::new(&config).is_err(), "{config:?}");
+        }
+    }
+}
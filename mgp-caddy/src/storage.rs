@@ -0,0 +1,546 @@
+//! Where the bytes of stored files are kept.
+//!
+//! The [`FileStore`](crate::fs::FileStore) decides what is stored under which key,
+//! and hands the storing itself to a [`StorageBackend`]. Keys are relative paths
+//! separated by `/`, such as `abc/123/def/abc123def456.meta.json`. Which backend is
+//! in use is chosen by the [`StorageConfig`].
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::{ensure, Result};
+use serde::Deserialize;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+use std::{ops::Range, sync::Arc};
+use tokio::io::AsyncRead;
+
+use crate::fs::FSError;
+
+#[cfg(target_os = "linux")]
+mod jail;
+#[cfg(target_os = "linux")]
+mod local;
+mod memory;
+mod s3;
+mod sharded;
+
+#[cfg(target_os = "linux")]
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use s3::{S3Backend, S3Config};
+pub use sharded::{ShardedBackend, VolumeConfig};
+
+/// The contents of a stored object, being read.
+pub type ObjectReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Facts about a stored object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectMeta {
+    /// The size of the object in bytes.
+    pub size: u64,
+    /// When the object was last written.
+    pub modified: DateTime<Utc>,
+    /// When the object was first written, if the backend records it.
+    pub created: Option<DateTime<Utc>>,
+}
+
+/// A place objects can be stored under keys.
+///
+/// Reading or heading a key nothing is stored under fails with [`FSError::NotFound`],
+/// and a key which isn't a plain relative path fails with [`FSError::DirectoryTraversal`].
+#[async_trait]
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    /// Stores `data` under `key`, replacing anything already there.
+    /// The object is only visible once it has been stored in full.
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Reads the object under `key`, or only the given range of bytes of it.
+    /// A range running past the end of the object is cut short.
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<ObjectReader>;
+
+    /// Retrieves the facts about the object under `key`, without reading it.
+    async fn head(&self, key: &str) -> Result<ObjectMeta>;
+
+    /// Removes the object under `key`. Removing an object which doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Lists the keys of every object whose key starts with `prefix`, in order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list_page(prefix, None, usize::MAX).await
+    }
+
+    /// Lists the keys of the first `limit` objects whose key starts with `prefix`, in order,
+    /// leaving out those up to and including `start_after`, if it is given. Listing a page
+    /// after the last key of the one before walks forward through the keys without
+    /// reading those already listed.
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>>;
+
+    /// Moves objects which aren't where the backend would now place them, such as after
+    /// storage was added to it, and returns how many were moved. Most backends never move anything.
+    async fn rebalance(&self) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Which backend files are stored in, and its settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// A directory on the local disk. Only there on Linux, which it needs `openat2` from.
+    #[cfg(target_os = "linux")]
+    Local {
+        /// The directory everything is stored under, which must already exist.
+        path: PathBuf,
+    },
+    /// Memory, which is lost when the caddy exits. Only meant for tests.
+    Memory,
+    /// An S3-compatible object store.
+    S3(S3Config),
+    /// Several volumes, each a backend of its own, which files are spread across by weight.
+    Sharded {
+        /// The volumes. Volumes can be added at any time, and files are then moved
+        /// onto them in the background.
+        volumes: Vec<VolumeConfig>,
+    },
+}
+
+impl Default for StorageConfig {
+    #[cfg(target_os = "linux")]
+    fn default() -> Self {
+        StorageConfig::Local {
+            path: PathBuf::from("./fileStore"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn default() -> Self {
+        StorageConfig::Memory
+    }
+}
+
+/// Opens the backend described by the configuration.
+pub fn open(config: &StorageConfig) -> Result<Arc<dyn StorageBackend>> {
+    Ok(match config {
+        #[cfg(target_os = "linux")]
+        StorageConfig::Local { path } => Arc::new(LocalBackend::new(path)?),
+        StorageConfig::Memory => Arc::new(MemoryBackend::default()),
+        StorageConfig::S3(config) => Arc::new(S3Backend::new(config)?),
+        StorageConfig::Sharded { volumes } => Arc::new(ShardedBackend::from_config(volumes)?),
+    })
+}
+
+/// Makes sure a key is a plain relative path, which every backend stores
+/// the same way and which can't lead out of the local backend's directory.
+fn check_key(key: &str) -> Result<()> {
+    let plain = !key.is_empty()
+        && !key.contains(['\\', '\0'])
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    ensure!(plain, FSError::DirectoryTraversal(key.to_owned()));
+    Ok(())
+}
+
+/// Cuts a range of bytes short at the end of an object `size` bytes long.
+pub(crate) fn clamp(range: Range<u64>, size: u64) -> Range<u64> {
+    range.start.min(size)..range.end.clamp(range.start.min(size), size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::is_not_found;
+    use std::collections::HashMap;
+    use tokio::io::AsyncReadExt;
+    use warp::{http::Response, hyper::body::Bytes, Filter};
+
+    async fn read(backend: &dyn StorageBackend, key: &str, range: Option<Range<u64>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        _ = backend
+            .get(key, range)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    /// Runs a backend through every operation, checking it behaves as the trait describes.
+    async fn exercise(backend: &dyn StorageBackend) {
+        backend
+            .put("abc/123/def/one", b"hello world")
+            .await
+            .unwrap();
+        backend
+            .put("abc/123/def/one.meta.json", b"{}")
+            .await
+            .unwrap();
+        backend.put("xyz/two", b"2").await.unwrap();
+
+        assert_eq!(backend.head("abc/123/def/one").await.unwrap().size, 11);
+        assert_eq!(read(backend, "abc/123/def/one", None).await, b"hello world");
+        assert_eq!(
+            read(backend, "abc/123/def/one", Some(6..11)).await,
+            b"world"
+        );
+        assert_eq!(
+            read(backend, "abc/123/def/one", Some(6..100)).await,
+            b"world"
+        );
+
+        assert_eq!(
+            backend.list("abc/").await.unwrap(),
+            ["abc/123/def/one", "abc/123/def/one.meta.json"]
+        );
+        assert_eq!(
+            backend.list("abc/123/def/one.").await.unwrap(),
+            ["abc/123/def/one.meta.json"]
+        );
+        assert_eq!(backend.list("").await.unwrap().len(), 3);
+        assert!(backend.list("nothing/").await.unwrap().is_empty());
+
+        assert_eq!(
+            backend.list_page("", None, 2).await.unwrap(),
+            ["abc/123/def/one", "abc/123/def/one.meta.json"]
+        );
+        assert_eq!(
+            backend
+                .list_page("", Some("abc/123/def/one"), 2)
+                .await
+                .unwrap(),
+            ["abc/123/def/one.meta.json", "xyz/two"]
+        );
+        assert_eq!(
+            backend.list_page("", Some("abc/2"), 10).await.unwrap(),
+            ["xyz/two"]
+        );
+        assert_eq!(
+            backend.list_page("abc/", Some("a"), 1).await.unwrap(),
+            ["abc/123/def/one"]
+        );
+        assert!(backend
+            .list_page("abc/", Some("abc/123/def/one.meta.json"), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        backend.put("xyz/two", b"two").await.unwrap();
+        assert_eq!(read(backend, "xyz/two", None).await, b"two");
+
+        backend.delete("abc/123/def/one").await.unwrap();
+        backend.delete("abc/123/def/one").await.unwrap();
+        assert!(is_not_found(
+            &backend.head("abc/123/def/one").await.unwrap_err()
+        ));
+        assert!(is_not_found(
+            &backend.get("abc/123/def/one", None).await.err().unwrap()
+        ));
+        assert_eq!(
+            backend.list("abc/").await.unwrap(),
+            ["abc/123/def/one.meta.json"]
+        );
+
+        for key in ["../escape", "abc//def", "/abc", "abc/./def"] {
+            assert!(backend.put(key, b"").await.is_err(), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        exercise(&MemoryBackend::default()).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_local_backend() {
+        let dir = std::env::temp_dir().join(format!("mgp-caddy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+
+        let backend = LocalBackend::new(&dir).unwrap();
+        exercise(&backend).await;
+        // Directories emptied by deletes are pruned.
+        backend.delete("abc/123/def/one.meta.json").await.unwrap();
+        assert!(!dir.join("abc").exists());
+
+        // Writes into a directory race deletes pruning it, and still land.
+        let backend = Arc::new(backend);
+        let tasks: Vec<_> = (0..8)
+            .map(|n| {
+                let backend = backend.clone();
+                tokio::spawn(async move {
+                    let key = format!("abc/123/{n}");
+                    for _ in 0..50 {
+                        backend.put(&key, b"data").await.unwrap();
+                        backend.delete(&key).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(!dir.join("abc").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_local_backend_escapes() {
+        let root = std::env::temp_dir().join(format!("mgp-caddy-{}", uuid::Uuid::new_v4()));
+        let (dir, outside) = (root.join("store"), root.join("outside"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(outside.join("abc")).unwrap();
+        std::fs::write(outside.join("secret"), b"secret").unwrap();
+        std::fs::write(outside.join("abc/secret"), b"secret").unwrap();
+        let backend = LocalBackend::new(&dir).unwrap();
+
+        // A symbolic link to a file outside the store is never read through.
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("link")).unwrap();
+        let is_symlink = |e: eyre::Report| matches!(e.downcast_ref(), Some(FSError::IsSymlink(_)));
+        assert!(is_symlink(backend.get("link", None).await.err().unwrap()));
+        assert!(is_symlink(backend.head("link").await.unwrap_err()));
+
+        // Nor is a directory swapped for a link to one outside the store, read or written through.
+        std::os::unix::fs::symlink(outside.join("abc"), dir.join("abc")).unwrap();
+        assert!(is_symlink(
+            backend.get("abc/secret", None).await.err().unwrap()
+        ));
+        assert!(is_symlink(
+            backend.put("abc/new", b"new").await.unwrap_err()
+        ));
+        assert!(!outside.join("abc/new").exists());
+        assert!(backend.list("").await.unwrap().is_empty());
+        assert!(backend.list("abc/").await.unwrap().is_empty());
+
+        // Removing a link removes only the link.
+        backend.delete("link").await.unwrap();
+        assert!(dir.join("link").symlink_metadata().is_err());
+        assert_eq!(std::fs::read(outside.join("secret")).unwrap(), b"secret");
+
+        // Keys can't climb out, and neither can paths resolved by the jail itself.
+        assert!(backend.get("../outside/secret", None).await.is_err());
+        backend.put("real/file", b"").await.unwrap();
+        let jail = jail::Jail::open(&dir).unwrap();
+        for path in [
+            "../outside/secret",
+            "real/../../outside/secret",
+            "/etc/passwd",
+        ] {
+            let e = jail.open_file(path).unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EXDEV), "{path}");
+        }
+        assert_eq!(
+            jail.open_file("abc/secret").unwrap_err().raw_os_error(),
+            Some(libc::ELOOP)
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sharded_backend() {
+        let volumes = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let volume: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+                (name.to_owned(), 1.0, volume)
+            })
+            .collect();
+        exercise(&ShardedBackend::new(volumes).unwrap()).await;
+
+        let config: StorageConfig = serde_json::from_str(
+            r#"{"backend": "sharded", "volumes": [
+                {"name": "a", "weight": 2, "backend": "memory"},
+                {"name": "b", "backend": "memory"}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(open(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sharded_rebalance() {
+        let old: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+        let new: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+        let keys: Vec<String> = (0..100)
+            .flat_map(|i| [format!("abc/{i:09}"), format!("abc/{i:09}.meta.json")])
+            .collect();
+
+        let before = ShardedBackend::new(vec![("old".to_owned(), 1.0, old.clone())]).unwrap();
+        for key in &keys {
+            before.put(key, key.as_bytes()).await.unwrap();
+        }
+        assert_eq!(before.rebalance().await.unwrap(), 0);
+
+        // Files stay readable while they wait to be moved onto the added volume.
+        let after = ShardedBackend::new(vec![
+            ("old".to_owned(), 1.0, old.clone()),
+            ("new".to_owned(), 1.0, new.clone()),
+        ])
+        .unwrap();
+        for key in &keys {
+            assert_eq!(read(&after, key, None).await, key.as_bytes());
+        }
+        // A write before the move lands on the new volume, and isn't undone by it.
+        after
+            .put("abc/000000000.meta.json", b"newer")
+            .await
+            .unwrap();
+
+        let moved = after.rebalance().await.unwrap();
+        assert!(moved > 0 && moved < keys.len(), "{moved}");
+        assert_eq!(after.rebalance().await.unwrap(), 0);
+        assert_eq!(
+            old.list("").await.unwrap().len() + new.list("").await.unwrap().len(),
+            keys.len()
+        );
+        assert_eq!(after.list("").await.unwrap(), {
+            let mut keys = keys.clone();
+            keys.sort();
+            keys
+        });
+
+        for key in &keys {
+            // A file and its attachments are always moved together.
+            let id = key.split('.').next().unwrap();
+            assert_eq!(
+                new.head(key).await.is_ok(),
+                new.head(id).await.is_ok(),
+                "{key}"
+            );
+            if key != "abc/000000000.meta.json" {
+                assert_eq!(read(&after, key, None).await, key.as_bytes());
+            }
+        }
+        assert_eq!(
+            read(&after, "abc/000000000.meta.json", None).await,
+            b"newer"
+        );
+    }
+
+    /// A stand-in for an S3-compatible store, serving a single bucket from memory.
+    /// It doesn't check signatures beyond making sure requests are signed.
+    fn s3_stand_in() -> impl Filter<Extract = (Response<Vec<u8>>,), Error = warp::Rejection> + Clone
+    {
+        let objects = Arc::new(MemoryBackend::default());
+        warp::path("bucket")
+            .and(warp::path::tail())
+            .and(warp::method())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::bytes())
+            .and_then(
+                move |tail: warp::path::Tail,
+                      method,
+                      query: HashMap<String, String>,
+                      range: Option<String>,
+                      authorization: Option<String>,
+                      body: Bytes| {
+                    let objects = objects.clone();
+                    async move {
+                        let key = tail.as_str();
+                        let response = Response::builder();
+                        let response = match (method, authorization) {
+                            (_, None) => response.status(403).body(Vec::new()),
+                            (warp::http::Method::PUT, _) => {
+                                objects.put(key, &body).await.unwrap();
+                                response.body(Vec::new())
+                            }
+                            (warp::http::Method::DELETE, _) => {
+                                objects.delete(key).await.unwrap();
+                                response.status(204).body(Vec::new())
+                            }
+                            (warp::http::Method::HEAD, _) => match objects.head(key).await {
+                                Ok(meta) => response
+                                    .header("content-length", meta.size)
+                                    .header(
+                                        "last-modified",
+                                        meta.modified
+                                            .format("%a, %d %b %Y %H:%M:%S GMT")
+                                            .to_string(),
+                                    )
+                                    .body(Vec::new()),
+                                Err(_) => response.status(404).body(Vec::new()),
+                            },
+                            (warp::http::Method::GET, _) if key.is_empty() => {
+                                let prefix = query.get("prefix").map_or("", String::as_str);
+                                // The continuation token is the last key listed so far.
+                                let start_after = query
+                                    .get("continuation-token")
+                                    .or(query.get("start-after"))
+                                    .map(String::as_str);
+                                let max_keys = query
+                                    .get("max-keys")
+                                    .map_or(1000, |max_keys| max_keys.parse().unwrap());
+                                let keys = objects
+                                    .list_page(prefix, start_after, max_keys + 1)
+                                    .await
+                                    .unwrap();
+                                let truncated = keys.len() > max_keys;
+                                let mut xml = format!(
+                                    "<ListBucketResult><IsTruncated>{truncated}</IsTruncated>"
+                                );
+                                for key in keys.iter().take(max_keys) {
+                                    xml.push_str(&format!("<Contents><Key>{key}</Key></Contents>"));
+                                }
+                                if truncated {
+                                    xml.push_str(&format!(
+                                        "<NextContinuationToken>{}</NextContinuationToken>",
+                                        keys[max_keys - 1]
+                                    ));
+                                }
+                                xml.push_str("</ListBucketResult>");
+                                response.body(xml.into_bytes())
+                            }
+                            (warp::http::Method::GET, _) => {
+                                let range = range.map(|range| {
+                                    let (start, end) = range
+                                        .strip_prefix("bytes=")
+                                        .and_then(|range| range.split_once('-'))
+                                        .unwrap();
+                                    start.parse::<u64>().unwrap()..end.parse::<u64>().unwrap() + 1
+                                });
+                                match objects.get(key, range.clone()).await {
+                                    Ok(mut object) => {
+                                        let mut data = Vec::new();
+                                        _ = object.read_to_end(&mut data).await.unwrap();
+                                        let status = if range.is_some() { 206 } else { 200 };
+                                        response.status(status).body(data)
+                                    }
+                                    Err(_) => response.status(404).body(Vec::new()),
+                                }
+                            }
+                            _ => response.status(405).body(Vec::new()),
+                        };
+                        Ok::<_, warp::Rejection>(response.unwrap())
+                    }
+                },
+            )
+    }
+
+    #[tokio::test]
+    async fn test_s3_backend() {
+        let (address, server) = warp::serve(s3_stand_in()).bind_ephemeral(([127, 0, 0, 1], 0));
+        let server = tokio::spawn(server);
+
+        let backend = S3Backend::new(&S3Config {
+            endpoint: format!("http://{address}"),
+            bucket: "bucket".to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key_id: Some("access".to_owned()),
+            secret_access_key: Some("secret".to_owned()),
+        })
+        .unwrap();
+        exercise(&backend).await;
+
+        server.abort();
+    }
+}
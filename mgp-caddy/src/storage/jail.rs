@@ -0,0 +1,329 @@
+//! A directory files can only be reached beneath.
+//!
+//! Every path is resolved by the kernel with `openat2`, relative to a descriptor of the
+//! directory held open for as long as the jail is, and with `RESOLVE_BENEATH` and
+//! `RESOLVE_NO_SYMLINKS`. Nothing can lead a path out of the directory, not `..`, nor
+//! a symbolic link swapped in after a path was checked, since there is no separate check.
+//! Needs Linux 5.6 or later. Every call blocks.
+
+use eyre::{ensure, Result};
+use std::{
+    ffi::{CStr, CString},
+    fs::{File, Metadata},
+    io::{self, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+};
+
+/// Opens a path beneath a directory descriptor, refusing to leave the directory or follow
+/// symbolic links on the way.
+fn openat2(dir: RawFd, path: &CStr, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+    // Zeroed first, since the kernel rejects any field it doesn't know unless it is zero.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.mode = mode as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS;
+
+    let how_ptr: *const libc::open_how = &how;
+    loop {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dir,
+                path.as_ptr(),
+                how_ptr,
+                size_of::<libc::open_how>(),
+            )
+        };
+        if fd >= 0 {
+            return Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }));
+        }
+
+        let e = io::Error::last_os_error();
+        // The kernel asks for a retry when a rename races with resolving `..`.
+        if !matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN)) {
+            return Err(e);
+        }
+    }
+}
+
+/// Turns the return value of a libc call into a result.
+fn cvt(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Turns a path into the form libc takes.
+fn c_path(path: &str) -> io::Result<CString> {
+    CString::new(path).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// Splits a `/`-separated path into its directory, which is empty for the jail itself,
+/// and its name.
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// What a directory entry is, as far as the jail is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum EntryKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// Anything else, including symbolic links, which are never followed.
+    Other,
+}
+
+/// A directory on the local disk, held open, which every path is resolved beneath.
+#[derive(Debug)]
+pub(super) struct Jail {
+    root: File,
+}
+
+impl Jail {
+    /// Opens the jail on an existing directory.
+    pub(super) fn open(path: &Path) -> Result<Jail> {
+        let root = File::open(path)?;
+        ensure!(
+            root.metadata()?.is_dir(),
+            "Provided base path is not a directory!"
+        );
+
+        let jail = Jail { root };
+        // Found out now, rather than by the first request.
+        if let Err(e) = jail.open_dir("") {
+            eyre::bail!("The local storage backend needs openat2, from Linux 5.6 on: {e}");
+        }
+        Ok(jail)
+    }
+
+    /// Opens a directory beneath the jail, or the jail itself for an empty path.
+    pub(super) fn open_dir(&self, dir: &str) -> io::Result<File> {
+        let dir = match dir {
+            "" => ".",
+            dir => dir,
+        };
+        openat2(
+            self.root.as_raw_fd(),
+            &c_path(dir)?,
+            libc::O_RDONLY | libc::O_DIRECTORY,
+            0,
+        )
+    }
+
+    /// Opens a regular file beneath the jail for reading.
+    /// Anything but a regular file is treated as not being there.
+    pub(super) fn open_file(&self, path: &str) -> io::Result<File> {
+        // Not blocking, so opening a FIFO someone left in the store doesn't hang.
+        let file = openat2(
+            self.root.as_raw_fd(),
+            &c_path(path)?,
+            libc::O_RDONLY | libc::O_NONBLOCK,
+            0,
+        )?;
+        match file.metadata()?.is_file() {
+            true => Ok(file),
+            false => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    /// Retrieves the metadata of a regular file beneath the jail, without opening it for reading.
+    pub(super) fn metadata(&self, path: &str) -> io::Result<Metadata> {
+        let file = openat2(self.root.as_raw_fd(), &c_path(path)?, libc::O_PATH, 0)?;
+        let metadata = file.metadata()?;
+        match metadata.is_file() {
+            true => Ok(metadata),
+            false => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    /// Creates the directories of a path one level at a time, each beneath the one before.
+    /// Returns the innermost one, opened.
+    pub(super) fn create_dirs(&self, dir: &str) -> io::Result<File> {
+        let mut parent = self.open_dir("")?;
+        for name in dir.split('/').filter(|name| !name.is_empty()) {
+            let c_name = c_path(name)?;
+            let created =
+                match cvt(unsafe { libc::mkdirat(parent.as_raw_fd(), c_name.as_ptr(), 0o755) }) {
+                    Ok(()) => true,
+                    Err(e) if e.raw_os_error() == Some(libc::EEXIST) => false,
+                    Err(e) => return Err(e),
+                };
+            // A name already taken by a symbolic link or a file fails to open as a directory here.
+            let child = openat2(
+                parent.as_raw_fd(),
+                &c_name,
+                libc::O_RDONLY | libc::O_DIRECTORY,
+                0,
+            )?;
+            if created {
+                parent.sync_all()?;
+            }
+            parent = child;
+        }
+
+        Ok(parent)
+    }
+
+    /// Writes `data` to a temporary file named `path` followed by `partial_suffix`, flushes it
+    /// to disk, and renames it over `path`. Readers only ever see the old file or the complete new one.
+    pub(super) fn write_atomic(
+        &self,
+        path: &str,
+        data: &[u8],
+        partial_suffix: &str,
+    ) -> io::Result<()> {
+        let (dir_path, name) = split(path);
+        let c_name = c_path(name)?;
+        let c_partial = c_path(&format!("{name}{partial_suffix}"))?;
+
+        // Until the partial file is in it, a directory can be pruned by a concurrent `remove`
+        // as soon as it's created, in which case it's created again.
+        let mut retries = 3;
+        let (dir, mut file) = loop {
+            let opened = self.create_dirs(dir_path).and_then(|dir| {
+                let file = openat2(
+                    dir.as_raw_fd(),
+                    &c_partial,
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                    0o644,
+                )?;
+                Ok((dir, file))
+            });
+            match opened {
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) && retries > 0 => retries -= 1,
+                opened => break opened?,
+            }
+        };
+
+        let written = (|| {
+            file.write_all(data)?;
+            file.sync_all()?;
+            cvt(unsafe {
+                libc::renameat(
+                    dir.as_raw_fd(),
+                    c_partial.as_ptr(),
+                    dir.as_raw_fd(),
+                    c_name.as_ptr(),
+                )
+            })
+        })();
+        if let Err(e) = written {
+            _ = unsafe { libc::unlinkat(dir.as_raw_fd(), c_partial.as_ptr(), 0) };
+            return Err(e);
+        }
+
+        // The rename is only durable once the directory holding it has been flushed too.
+        dir.sync_all()
+    }
+
+    /// Removes a file beneath the jail, then the directories it was in which are left empty,
+    /// innermost first. Returns whether there was a file to remove.
+    /// Removing a symbolic link removes the link itself, never what it points to.
+    pub(super) fn remove(&self, path: &str) -> io::Result<bool> {
+        let (dir, name) = split(path);
+        let parent = match self.open_dir(dir) {
+            Ok(parent) => parent,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENOTDIR)) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        match cvt(unsafe { libc::unlinkat(parent.as_raw_fd(), c_path(name)?.as_ptr(), 0) }) {
+            Ok(()) => parent.sync_all()?,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        // Only ever removes empty directories, so it can't take anything else with it.
+        let mut dir = dir;
+        while !dir.is_empty() {
+            let (outer, name) = split(dir);
+            let outer_dir = match self.open_dir(outer) {
+                Ok(outer_dir) => outer_dir,
+                // Already pruned by a concurrent `remove`.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => break,
+                Err(e) => return Err(e),
+            };
+            let removed = unsafe {
+                libc::unlinkat(
+                    outer_dir.as_raw_fd(),
+                    c_path(name)?.as_ptr(),
+                    libc::AT_REMOVEDIR,
+                )
+            };
+            if cvt(removed).is_err() {
+                break;
+            }
+            outer_dir.sync_all()?;
+            dir = outer;
+        }
+
+        Ok(true)
+    }
+
+    /// Lists the entries of a directory beneath the jail, by name.
+    pub(super) fn entries(&self, dir: &str) -> io::Result<Vec<(String, EntryKind)>> {
+        let dir = self.open_dir(dir)?;
+        // The stream takes the descriptor over, and closes it when closed.
+        let stream = unsafe { libc::fdopendir(dir.as_raw_fd()) };
+        if stream.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = dir.as_raw_fd();
+        std::mem::forget(dir);
+
+        let mut entries = Vec::new();
+        let listed = loop {
+            // Told apart from the end of the stream by errno.
+            unsafe { *libc::__errno_location() = 0 };
+            let entry = unsafe { libc::readdir64(stream) };
+            if entry.is_null() {
+                let e = io::Error::last_os_error();
+                break match e.raw_os_error() {
+                    Some(0) => Ok(()),
+                    _ => Err(e),
+                };
+            }
+
+            let (name, d_type) =
+                unsafe { (CStr::from_ptr((*entry).d_name.as_ptr()), (*entry).d_type) };
+            if matches!(name.to_bytes(), b"." | b"..") {
+                continue;
+            }
+            let kind = match d_type {
+                libc::DT_REG => EntryKind::File,
+                libc::DT_DIR => EntryKind::Dir,
+                // Not every filesystem fills the type in.
+                libc::DT_UNKNOWN => match Self::entry_kind(fd, name) {
+                    Ok(kind) => kind,
+                    // Removed since the directory was read.
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                    // Broken out of rather than returned from, so the stream is still closed.
+                    Err(e) => break Err(e),
+                },
+                _ => EntryKind::Other,
+            };
+            if let Ok(name) = std::str::from_utf8(name.to_bytes()) {
+                entries.push((name.to_owned(), kind));
+            }
+        };
+
+        _ = unsafe { libc::closedir(stream) };
+        listed.map(|()| entries)
+    }
+
+    /// What the entry `name` of the directory open as `dir` is, without following it.
+    fn entry_kind(dir: RawFd, name: &CStr) -> io::Result<EntryKind> {
+        let mut stat: libc::stat64 = unsafe { std::mem::zeroed() };
+        cvt(unsafe { libc::fstatat64(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) })?;
+        Ok(match stat.st_mode & libc::S_IFMT {
+            libc::S_IFREG => EntryKind::File,
+            libc::S_IFDIR => EntryKind::Dir,
+            _ => EntryKind::Other,
+        })
+    }
+}
@@ -0,0 +1,205 @@
+//! Storage in a directory on the local disk.
+
+use async_trait::async_trait;
+use eyre::Result;
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::jail::{EntryKind, Jail};
+use super::{check_key, clamp, ObjectMeta, ObjectReader, StorageBackend};
+use crate::fs::FSError;
+
+/// The suffix of files being written, before they are renamed into place.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Stores each object as a file under a base directory, with the `/`-separated parts
+/// of its key as directories. Everything is jailed to the base directory: every path
+/// is resolved beneath a descriptor of it by the kernel, and symbolic links are never
+/// followed, so no key can lead outside it.
+#[derive(Debug)]
+pub struct LocalBackend {
+    jail: Arc<Jail>,
+}
+
+impl LocalBackend {
+    /// Opens the backend on an existing directory.
+    pub fn new(base_path: impl AsRef<Path>) -> Result<LocalBackend> {
+        Ok(LocalBackend {
+            jail: Arc::new(Jail::open(base_path.as_ref())?),
+        })
+    }
+
+    /// Runs blocking filesystem calls on the jail off the async runtime,
+    /// turning their errors into those the trait describes for `key`.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&Jail) -> io::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let jail = self.jail.clone();
+        match tokio::task::spawn_blocking(move || f(&jail)).await? {
+            Ok(value) => Ok(value),
+            Err(e) => Err(jail_error(e, key)),
+        }
+    }
+}
+
+/// Turns an error resolving `key` in the jail into the error the trait describes for it.
+fn jail_error(e: io::Error, key: &str) -> eyre::Report {
+    match e.raw_os_error() {
+        Some(libc::ENOENT | libc::ENOTDIR) => FSError::NotFound(key.to_owned()).into(),
+        // Refused by `RESOLVE_NO_SYMLINKS`.
+        Some(libc::ELOOP) => FSError::IsSymlink(key.to_owned()).into(),
+        // Refused by `RESOLVE_BENEATH`.
+        Some(libc::EXDEV) => FSError::DirectoryTraversal(key.to_owned()).into(),
+        _ => e.into(),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        check_key(key)?;
+
+        let (path, data) = (key.to_owned(), data.to_vec());
+        self.blocking(key, move |jail| {
+            jail.write_atomic(&path, &data, PARTIAL_SUFFIX)
+        })
+        .await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<ObjectReader> {
+        check_key(key)?;
+
+        let path = key.to_owned();
+        let file = self
+            .blocking(key, move |jail| jail.open_file(&path))
+            .await?;
+        let mut file = tokio::fs::File::from_std(file);
+
+        match range {
+            None => Ok(Box::new(file)),
+            Some(range) => {
+                let range = clamp(range, file.metadata().await?.len());
+                _ = file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(Box::new(file.take(range.end - range.start)))
+            }
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta> {
+        check_key(key)?;
+
+        let path = key.to_owned();
+        let metadata = self.blocking(key, move |jail| jail.metadata(&path)).await?;
+        Ok(ObjectMeta {
+            size: metadata.len(),
+            modified: metadata.modified()?.into(),
+            // Not every filesystem records creation times.
+            created: metadata.created().ok().map(Into::into),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        check_key(key)?;
+
+        // Directories left empty are pruned along with the file.
+        let path = key.to_owned();
+        _ = self.blocking(key, move |jail| jail.remove(&path)).await?;
+        Ok(())
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        // Only the directory the prefix ends in, and those under it, can hold matching keys.
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) => {
+                check_key(dir)?;
+                format!("{dir}/")
+            }
+            None => String::new(),
+        };
+        let prefix = prefix.to_owned();
+        let start_after = start_after.map(str::to_owned);
+
+        self.blocking(&start.clone(), move |jail| {
+            let start_after = start_after.as_deref();
+            // Whether nothing under a directory comes after `start_after`, so it needn't be read.
+            let passed = |dir_prefix: &str| {
+                start_after.is_some_and(|start_after| {
+                    dir_prefix <= start_after && !start_after.starts_with(dir_prefix)
+                })
+            };
+            let mut keys = Vec::new();
+            // Directories are walked in order, so once enough keys have been found,
+            // those under the directories left can't come before them.
+            let mut dirs = vec![start];
+            while let Some(dir_key) = dirs.pop() {
+                if keys.len() >= limit {
+                    keys.sort_unstable();
+                    keys.truncate(limit);
+                    if keys.last().is_some_and(|last| *last < dir_key) {
+                        break;
+                    }
+                }
+
+                let entries = match jail.entries(dir_key.trim_end_matches('/')) {
+                    Ok(entries) => entries,
+                    // The directory the prefix ends in may not exist, may have been pruned,
+                    // or may be a symbolic link, which is never followed.
+                    Err(e)
+                        if matches!(
+                            e.raw_os_error(),
+                            Some(libc::ENOENT | libc::ENOTDIR | libc::ELOOP)
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let mut subdirs = Vec::new();
+                for (name, kind) in entries {
+                    let key = format!("{dir_key}{name}");
+                    match kind {
+                        EntryKind::Dir => {
+                            let dir_prefix = format!("{key}/");
+                            let matching =
+                                dir_prefix.starts_with(&prefix) || prefix.starts_with(&dir_prefix);
+                            if matching && !passed(&dir_prefix) {
+                                subdirs.push(dir_prefix);
+                            }
+                        }
+                        EntryKind::File
+                            if key.starts_with(&prefix)
+                                && !name.ends_with(PARTIAL_SUFFIX)
+                                && start_after
+                                    .is_none_or(|start_after| key.as_str() > start_after) =>
+                        {
+                            keys.push(key)
+                        }
+                        // Symbolic links are never followed, since they could lead out of the store.
+                        _ => (),
+                    }
+                }
+                // Pushed last first, so they are walked first to last.
+                subdirs.sort_unstable();
+                dirs.extend(subdirs.into_iter().rev());
+            }
+
+            keys.sort_unstable();
+            keys.truncate(limit);
+            Ok(keys)
+        })
+        .await
+    }
+}
@@ -0,0 +1,108 @@
+//! Storage in memory, for tests.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::{bail, Result};
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    ops::{Bound, Range},
+    sync::Arc,
+    sync::RwLock,
+};
+
+use super::{check_key, clamp, ObjectMeta, ObjectReader, StorageBackend};
+use crate::fs::FSError;
+
+/// A stored object, along with when it was first and last written.
+#[derive(Debug)]
+struct Object {
+    data: Arc<[u8]>,
+    created: DateTime<Utc>,
+    modified: DateTime<Utc>,
+}
+
+/// Keeps every object in memory, so nothing outlives the process.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    objects: RwLock<BTreeMap<String, Object>>,
+}
+
+impl MemoryBackend {
+    /// Looks at the object under `key`, failing if there is none.
+    fn object<T>(&self, key: &str, f: impl FnOnce(&Object) -> T) -> Result<T> {
+        check_key(key)?;
+        let objects = self.objects.read().unwrap_or_else(|e| e.into_inner());
+        match objects.get(key) {
+            Some(object) => Ok(f(object)),
+            None => bail!(FSError::NotFound(key.to_owned())),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        check_key(key)?;
+
+        let now = Utc::now();
+        let mut objects = self.objects.write().unwrap_or_else(|e| e.into_inner());
+        let created = objects.get(key).map_or(now, |object| object.created);
+        _ = objects.insert(
+            key.to_owned(),
+            Object {
+                data: data.into(),
+                created,
+                modified: now,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<ObjectReader> {
+        let data = self.object(key, |object| object.data.clone())?;
+        let range = clamp(range.unwrap_or(0..u64::MAX), data.len() as u64);
+        Ok(Box::new(Cursor::new(
+            data[range.start as usize..range.end as usize].to_vec(),
+        )))
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta> {
+        self.object(key, |object| ObjectMeta {
+            size: object.data.len() as u64,
+            modified: object.modified,
+            created: Some(object.created),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        check_key(key)?;
+        _ = self
+            .objects
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+        Ok(())
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let start = match start_after {
+            Some(start_after) if start_after >= prefix => Bound::Excluded(start_after.to_owned()),
+            _ => Bound::Included(prefix.to_owned()),
+        };
+
+        let objects = self.objects.read().unwrap_or_else(|e| e.into_inner());
+        Ok(objects
+            .range((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,239 @@
+//! Storage spread across several volumes.
+//!
+//! Each file is placed on a volume by weighted rendezvous hashing of its normalized ID,
+//! so every object belonging to a file lands on the same volume, and adding a volume
+//! only moves files onto the new one. Files are moved by [`StorageBackend::rebalance`]
+//! while the store stays in use, and until a file has been moved it is still read
+//! from where it was.
+
+use async_trait::async_trait;
+use eyre::{ensure, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeSet, ops::Range, sync::Arc};
+use tokio::{io::AsyncReadExt, sync::Mutex};
+
+use super::{ObjectMeta, ObjectReader, StorageBackend, StorageConfig};
+use crate::fs::{is_not_found, FSError};
+
+/// The number of locks objects being written or moved are spread over.
+const LOCK_STRIPES: usize = 64;
+
+/// Settings for one volume of a sharded store.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VolumeConfig {
+    /// The name the volume is known by. Where files are placed depends on it,
+    /// so it must not change once the volume is in use.
+    pub name: String,
+    /// The share of files the volume takes, relative to the other volumes.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// Where the volume stores its files, usually a local directory.
+    #[serde(flatten)]
+    pub storage: StorageConfig,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// A volume files can be placed on.
+#[derive(Debug)]
+struct Volume {
+    name: String,
+    weight: f64,
+    backend: Arc<dyn StorageBackend>,
+}
+
+/// Spreads objects across several volumes, each of them a backend of its own.
+#[derive(Debug)]
+pub struct ShardedBackend {
+    volumes: Vec<Volume>,
+    /// Held while an object is written, removed or moved, picked by its normalized ID,
+    /// so a move never undoes a write which happens while it runs.
+    locks: Vec<Mutex<()>>,
+}
+
+impl ShardedBackend {
+    /// Spreads objects across the given volumes, as `(name, weight, backend)`.
+    pub fn new(volumes: Vec<(String, f64, Arc<dyn StorageBackend>)>) -> Result<ShardedBackend> {
+        ensure!(
+            !volumes.is_empty(),
+            "A sharded store needs at least one volume"
+        );
+        for (index, (name, weight, _)) in volumes.iter().enumerate() {
+            ensure!(
+                weight.is_finite() && *weight > 0.0,
+                "The weight of volume `{name}` must be a positive number"
+            );
+            ensure!(
+                volumes[..index].iter().all(|(other, _, _)| other != name),
+                "There is more than one volume named `{name}`"
+            );
+        }
+
+        Ok(ShardedBackend {
+            volumes: volumes
+                .into_iter()
+                .map(|(name, weight, backend)| Volume {
+                    name,
+                    weight,
+                    backend,
+                })
+                .collect(),
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        })
+    }
+
+    /// Opens the volumes described by the configuration.
+    pub fn from_config(volumes: &[VolumeConfig]) -> Result<ShardedBackend> {
+        ShardedBackend::new(
+            volumes
+                .iter()
+                .map(|volume| {
+                    Ok((
+                        volume.name.clone(),
+                        volume.weight,
+                        super::open(&volume.storage)?,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+        )
+    }
+
+    /// The indices of the volumes, in the order a key's object is looked for on them:
+    /// the volume it is placed on first, then the others by how close they came.
+    ///
+    /// Each volume scores `-weight / ln(h)` for a hash `h` of its name and the ID,
+    /// taken as a fraction between 0 and 1, and the highest score wins.
+    fn ranked(&self, key: &str) -> Vec<usize> {
+        let id = object_id(key);
+        let scores: Vec<f64> = self
+            .volumes
+            .iter()
+            .map(|volume| {
+                let digest = Sha256::new()
+                    .chain_update(volume.name.as_bytes())
+                    .chain_update([0])
+                    .chain_update(id.as_bytes())
+                    .finalize();
+                let hash = u64::from_le_bytes(digest[..8].try_into().unwrap_or_default());
+                // Kept strictly between 0 and 1, so the logarithm is finite and negative.
+                let fraction = (hash as f64 + 0.5) / (u64::MAX as f64 + 1.0);
+                -volume.weight / fraction.ln()
+            })
+            .collect();
+
+        let mut ranked: Vec<usize> = (0..self.volumes.len()).collect();
+        ranked.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+        ranked
+    }
+
+    /// The lock an object is written, removed and moved under.
+    fn stripe(&self, key: &str) -> &Mutex<()> {
+        let digest = Sha256::digest(object_id(key).as_bytes());
+        &self.locks[digest[0] as usize % LOCK_STRIPES]
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ShardedBackend {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let _lock = self.stripe(key).lock().await;
+        self.volumes[self.ranked(key)[0]]
+            .backend
+            .put(key, data)
+            .await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<ObjectReader> {
+        let mut last = None;
+        for index in self.ranked(key) {
+            match self.volumes[index].backend.get(key, range.clone()).await {
+                Err(e) if is_not_found(&e) => last = Some(e),
+                result => return result,
+            }
+        }
+        Err(last.unwrap_or_else(|| FSError::NotFound(key.to_owned()).into()))
+    }
+
+    async fn head(&self, key: &str) -> Result<ObjectMeta> {
+        let mut last = None;
+        for index in self.ranked(key) {
+            match self.volumes[index].backend.head(key).await {
+                Err(e) if is_not_found(&e) => last = Some(e),
+                result => return result,
+            }
+        }
+        Err(last.unwrap_or_else(|| FSError::NotFound(key.to_owned()).into()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        // Copies left on other volumes by an unfinished move must go too,
+        // or the object would be found there again.
+        let _lock = self.stripe(key).lock().await;
+        for volume in &self.volumes {
+            volume.backend.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        // An object being moved can be on two volumes at once. The first keys of the
+        // whole store are among the first keys of each volume.
+        let mut keys = BTreeSet::new();
+        for volume in &self.volumes {
+            keys.extend(volume.backend.list_page(prefix, start_after, limit).await?);
+        }
+        Ok(keys.into_iter().take(limit).collect())
+    }
+
+    /// Moves every object which isn't on the volume it is now placed on, such as after
+    /// a volume was added. Each object is copied to its new volume before it is removed from
+    /// the old one, so it can be read throughout. Returns how many objects were moved.
+    async fn rebalance(&self) -> Result<usize> {
+        let mut moved = 0;
+
+        for (index, volume) in self.volumes.iter().enumerate() {
+            for key in volume.backend.list("").await? {
+                let target = self.ranked(&key)[0];
+                if target == index {
+                    continue;
+                }
+
+                let _lock = self.stripe(&key).lock().await;
+                // A copy already on the new volume was written after the old one, so it wins.
+                match self.volumes[target].backend.head(&key).await {
+                    Ok(_) => (),
+                    Err(e) if is_not_found(&e) => {
+                        let mut data = Vec::new();
+                        match volume.backend.get(&key, None).await {
+                            Ok(mut object) => _ = object.read_to_end(&mut data).await?,
+                            // Deleted since it was listed.
+                            Err(e) if is_not_found(&e) => continue,
+                            Err(e) => return Err(e),
+                        }
+                        self.volumes[target].backend.put(&key, &data).await?;
+                    }
+                    Err(e) => return Err(e),
+                }
+                volume.backend.delete(&key).await?;
+                moved += 1;
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+/// The normalized ID an object belongs to, which decides where it is placed:
+/// the last part of its key, up to any attachment name.
+fn object_id(key: &str) -> &str {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    name.split('.').next().unwrap_or(name)
+}
@@ -0,0 +1,306 @@
+//! Storyboards: hover previews for scrubbing through videos.
+//!
+//! A storyboard is a set of WebP sprite sheets holding thumbnails of a video sampled
+//! at a fixed interval, along with a WebVTT track which maps each time range of the
+//! video to the part of a sheet showing it.
+
+use eyre::{ensure, Result};
+use ffmpeg_next::{codec, format, frame, media, software::scaling};
+use image::{
+    codecs::webp::{WebPEncoder, WebPQuality},
+    imageops, ColorType, RgbaImage,
+};
+use serde::Deserialize;
+use std::{
+    fmt::Write as _,
+    io::{BufReader, Read, Seek},
+};
+
+use crate::convert::StreamingInput;
+
+/// The name the WebVTT track of a storyboard is stored under.
+pub const TRACK_NAME: &str = "storyboard.vtt";
+
+/// Settings for the storyboards generated for uploaded videos.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoryboardConfig {
+    /// Whether storyboards are generated at all.
+    pub enabled: bool,
+    /// Videos shorter than this many seconds don't get a storyboard.
+    pub min_duration: f64,
+    /// Seconds between sampled frames.
+    pub interval: f64,
+    /// Width of each thumbnail in pixels. The height follows the video's aspect ratio.
+    pub thumbnail_width: u32,
+    /// Number of thumbnails across each sprite sheet.
+    pub columns: u32,
+    /// Number of thumbnails down each sprite sheet.
+    pub rows: u32,
+    /// WebP quality of the sprite sheets, from 0 to 100.
+    pub quality: u8,
+}
+
+impl Default for StoryboardConfig {
+    fn default() -> Self {
+        StoryboardConfig {
+            enabled: true,
+            min_duration: 10.0,
+            interval: 5.0,
+            thumbnail_width: 160,
+            columns: 5,
+            rows: 5,
+            quality: 70,
+        }
+    }
+}
+
+impl StoryboardConfig {
+    /// Makes sure the settings can make a storyboard at all.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.interval.is_finite() && self.interval > 0.0,
+            "The storyboard interval must be a positive number of seconds"
+        );
+        ensure!(
+            self.thumbnail_width > 0,
+            "Storyboard thumbnails must be at least 1 pixel wide"
+        );
+        ensure!(
+            self.columns > 0 && self.rows > 0,
+            "Storyboard sprite sheets must have at least one column and one row"
+        );
+        ensure!(
+            self.quality <= 100,
+            "The storyboard quality must be from 0 to 100"
+        );
+        Ok(())
+    }
+}
+
+/// The sprite sheets and WebVTT track making up a storyboard.
+#[derive(Debug, Clone)]
+pub struct Storyboard {
+    /// WebP-encoded sprite sheets, named by [`sheet_name`].
+    pub sheets: Vec<Vec<u8>>,
+    /// The WebVTT track, to be stored under [`TRACK_NAME`].
+    pub track: String,
+}
+
+/// The name the sprite sheet at the given index is stored under.
+/// The WebVTT track refers to sheets relative to its own location.
+pub fn sheet_name(index: usize) -> String {
+    format!("storyboard.{index}.webp")
+}
+
+/// Whether the given name is one of the files making up a storyboard.
+pub fn is_storyboard_name(name: &str) -> bool {
+    name == TRACK_NAME
+        || name
+            .strip_prefix("storyboard.")
+            .and_then(|rest| rest.strip_suffix(".webp"))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Samples a frame from the video in `source` every `config.interval` seconds and tiles
+/// them into a storyboard. `duration` is the length of the video in seconds.
+///
+/// Returns `None` if the source has no video or is too short to need a storyboard.
+pub fn generate<R: Read + Seek>(
+    source: &mut R,
+    duration: f64,
+    config: &StoryboardConfig,
+) -> Result<Option<Storyboard>> {
+    if !config.enabled || duration < config.min_duration {
+        return Ok(None);
+    }
+
+    let mut input = StreamingInput::new(BufReader::new(source))?;
+
+    let (index, time_base, mut decoder) = match input.streams().best(media::Type::Video) {
+        Some(stream) => (
+            stream.index(),
+            stream.time_base(),
+            codec::context::Context::from_parameters(stream.parameters())?
+                .decoder()
+                .video()?,
+        ),
+        None => return Ok(None),
+    };
+
+    // Thumbnail heights are kept even, since that's what most scalers are happiest with.
+    let width = config.thumbnail_width;
+    let height = ((width as f64 * decoder.height() as f64 / decoder.width() as f64 / 2.0).round()
+        as u32
+        * 2)
+    .max(2);
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        format::Pixel::RGBA,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+
+    let mut builder = Builder::new(width, height, duration, config);
+    let mut decoded = frame::Video::empty();
+    let mut scaled = frame::Video::empty();
+
+    let mut eof = false;
+    let mut packets = input.packets();
+    while !eof {
+        match packets.next() {
+            Some((stream, packet)) if stream.index() == index => decoder.send_packet(&packet)?,
+            Some(_) => continue,
+            None => {
+                decoder.send_eof()?;
+                eof = true;
+            }
+        }
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = match decoded.timestamp() {
+                Some(timestamp) => timestamp as f64 * f64::from(time_base),
+                None => continue,
+            };
+            if !builder.wants(timestamp) {
+                continue;
+            }
+
+            scaler.run(&decoded, &mut scaled)?;
+            builder.add(&scaled)?;
+        }
+    }
+
+    builder.finish().map(Some)
+}
+
+/// Tiles sampled frames into sprite sheets and writes the cues pointing at them.
+struct Builder<'a> {
+    config: &'a StoryboardConfig,
+    width: u32,
+    height: u32,
+    duration: f64,
+
+    sheets: Vec<Vec<u8>>,
+    sheet: RgbaImage,
+    /// Number of thumbnails added to the current sheet.
+    tiles: u32,
+    /// Number of thumbnails added overall.
+    samples: u32,
+    track: String,
+}
+
+impl<'a> Builder<'a> {
+    fn new(width: u32, height: u32, duration: f64, config: &'a StoryboardConfig) -> Self {
+        Builder {
+            config,
+            width,
+            height,
+            duration,
+            sheets: Vec::new(),
+            sheet: RgbaImage::new(width * config.columns, height * config.rows),
+            tiles: 0,
+            samples: 0,
+            track: "WEBVTT\n".to_owned(),
+        }
+    }
+
+    /// Whether a frame shown at the given time should be the next thumbnail.
+    fn wants(&self, timestamp: f64) -> bool {
+        let start = self.samples as f64 * self.config.interval;
+        start < self.duration && timestamp >= start
+    }
+
+    /// Adds an RGBA frame, already scaled to the thumbnail size, as the next thumbnail.
+    fn add(&mut self, thumbnail: &frame::Video) -> Result<()> {
+        let x = (self.tiles % self.config.columns) * self.width;
+        let y = (self.tiles / self.config.columns) * self.height;
+
+        // Rows of the frame may be padded past the thumbnail's width.
+        let data = thumbnail.data(0);
+        let stride = thumbnail.stride(0);
+        let row_len = self.width as usize * 4;
+        let sheet_width = self.sheet.width();
+        let sheet: &mut [u8] = &mut self.sheet;
+        for row in 0..self.height {
+            let src = &data[row as usize * stride..][..row_len];
+            let dst = ((y + row) * sheet_width + x) as usize * 4;
+            sheet[dst..][..row_len].copy_from_slice(src);
+        }
+
+        let start = self.samples as f64 * self.config.interval;
+        let end = (start + self.config.interval).min(self.duration);
+        _ = write!(
+            self.track,
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            timestamp(start),
+            timestamp(end),
+            sheet_name(self.sheets.len()),
+            x,
+            y,
+            self.width,
+            self.height
+        );
+
+        self.tiles += 1;
+        self.samples += 1;
+        if self.tiles == self.config.columns * self.config.rows {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the current sheet, cropped down to the thumbnails it holds.
+    fn flush(&mut self) -> Result<()> {
+        if self.tiles == 0 {
+            return Ok(());
+        }
+
+        let columns = self.tiles.min(self.config.columns);
+        let rows = self.tiles.div_ceil(self.config.columns);
+        let sheet = imageops::crop_imm(&self.sheet, 0, 0, columns * self.width, rows * self.height)
+            .to_image();
+
+        let mut encoded = Vec::new();
+        WebPEncoder::new_with_quality(&mut encoded, WebPQuality::lossy(self.config.quality))
+            .encode(
+                sheet.as_raw(),
+                sheet.width(),
+                sheet.height(),
+                ColorType::Rgba8,
+            )?;
+        self.sheets.push(encoded);
+
+        self.sheet = RgbaImage::new(
+            self.width * self.config.columns,
+            self.height * self.config.rows,
+        );
+        self.tiles = 0;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Storyboard> {
+        self.flush()?;
+        Ok(Storyboard {
+            sheets: self.sheets,
+            track: self.track,
+        })
+    }
+}
+
+/// Formats a time in seconds as a WebVTT timestamp, `HH:MM:SS.mmm`.
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
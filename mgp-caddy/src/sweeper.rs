@@ -0,0 +1,53 @@
+//! The background sweeper, which deletes staged files nobody claimed.
+//!
+//! Clients upload media before the post using it exists, so new files start out
+//! staged, and are only kept once they are claimed. The sweeper deletes those which
+//! expire first, leaving their removal from storage to the collector.
+
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+use crate::background;
+use crate::fs::FileStore;
+
+/// Settings for staged uploads and the background sweeper.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StagingConfig {
+    /// Seconds new files stay staged before they expire,
+    /// or `None`, the default, for new files to be claimed straight away.
+    pub ttl: Option<u64>,
+    /// Seconds between sweeping runs.
+    pub interval: u64,
+}
+
+impl Default for StagingConfig {
+    fn default() -> Self {
+        StagingConfig {
+            ttl: None,
+            interval: 600,
+        }
+    }
+}
+
+impl StagingConfig {
+    /// How long new files stay staged, if they are staged at all.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl.map(Duration::from_secs)
+    }
+}
+
+/// Starts sweeping in the background, once right away and then every interval.
+pub fn spawn(store: Arc<FileStore>, config: &StagingConfig) -> JoinHandle<()> {
+    background::every(config.interval, move || {
+        let store = store.clone();
+        async move {
+            match store.sweep().await {
+                Ok(0) => (),
+                Ok(swept) => tracing::info!(swept, "Deleted expired staged files"),
+                Err(e) => tracing::error!("Sweeping staged files failed: {e:?}"),
+            }
+        }
+    })
+}
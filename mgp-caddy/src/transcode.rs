@@ -0,0 +1,306 @@
+//! Routing of uploaded media to the code which converts it.
+//!
+//! Every way of converting media is a [`Transcoder`], and the [`Registry`] decides
+//! which one an upload goes to based on the format it turns out to be in.
+//! Which transcoders are in use, and which formats are accepted at all,
+//! is controlled by the [`RegistryConfig`].
+
+use eyre::{bail, Result};
+use file_format::FileFormat;
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{BufRead, Seek, SeekFrom, Write},
+};
+
+use crate::convert::{self, MediaInfo, TranscodeProfile};
+
+/// Uploaded data being read by a [`Transcoder`].
+pub trait Source: BufRead + Seek {}
+impl<T: BufRead + Seek> Source for T {}
+
+/// Where a [`Transcoder`] writes its output.
+pub trait Sink: Write + Seek {}
+impl<T: Write + Seek> Sink for T {}
+
+/// A way of converting media in some set of formats into a format the caddy stores.
+pub trait Transcoder: std::fmt::Debug + Send + Sync {
+    /// The name this transcoder is referred to by in the configuration.
+    fn name(&self) -> &'static str;
+
+    /// The input formats this transcoder can convert.
+    fn formats(&self) -> &[FileFormat];
+
+    /// The format of everything this transcoder produces.
+    fn output(&self) -> FileFormat;
+
+    /// Whether this transcoder wants to convert the given data, which is in one of its formats.
+    /// This lets transcoders for the same format split inputs between them.
+    fn accepts(&self, _source: &mut dyn Source) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Converts the data in `source`, writing the result to `sink`.
+    fn convert(&self, source: &mut dyn Source, sink: &mut dyn Sink) -> Result<MediaInfo>;
+}
+
+/// Configuration of which transcoders and input formats are in use.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RegistryConfig {
+    /// Names of the transcoders to use, in order of preference.
+    /// When several support the same format, the first one listed wins.
+    pub transcoders: Vec<String>,
+    /// MIME types of input formats which are refused even if a transcoder supports them.
+    pub disabled_formats: Vec<String>,
+    /// Which animated images the `ffmpeg-gifv` transcoder converts to video.
+    pub gifv: GifvPolicy,
+}
+
+/// Thresholds past which an animated image is stored as a video rather than an animated WebP.
+/// An animation reaching either threshold is converted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GifvPolicy {
+    /// The number of frames at which an animation is converted.
+    pub frame_count: u64,
+    /// The upload size in bytes at which an animation is converted.
+    pub size: u64,
+}
+
+impl Default for GifvPolicy {
+    fn default() -> Self {
+        GifvPolicy {
+            frame_count: 300,
+            size: 2_000_000,
+        }
+    }
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            transcoders: vec![
+                ImageTranscoder::NAME.to_owned(),
+                VideoTranscoder::NAME.to_owned(),
+            ],
+            disabled_formats: Vec::new(),
+            gifv: GifvPolicy::default(),
+        }
+    }
+}
+
+/// The transcoders uploads can be routed to, looked up by input format.
+#[derive(Debug, Default)]
+pub struct Registry {
+    transcoders: Vec<Box<dyn Transcoder>>,
+    disabled_formats: Vec<String>,
+}
+
+impl Registry {
+    /// Builds a registry holding the built-in transcoders named in the configuration.
+    pub fn new(config: &RegistryConfig, profile: &TranscodeProfile) -> Result<Registry> {
+        let mut registry = Registry {
+            transcoders: Vec::new(),
+            disabled_formats: config.disabled_formats.clone(),
+        };
+
+        for name in &config.transcoders {
+            match name.as_str() {
+                ImageTranscoder::NAME => registry.register(ImageTranscoder {
+                    profile: profile.clone(),
+                }),
+                VideoTranscoder::NAME => registry.register(VideoTranscoder {
+                    profile: profile.clone(),
+                }),
+                GifvTranscoder::NAME => registry.register(GifvTranscoder {
+                    policy: config.gifv.clone(),
+                    profile: profile.clone(),
+                }),
+                _ => bail!("Unknown transcoder `{name}` in configuration"),
+            }
+        }
+
+        Ok(registry)
+    }
+
+    /// Adds a transcoder, with lower preference than those already registered.
+    pub fn register(&mut self, transcoder: impl Transcoder + 'static) {
+        self.transcoders.push(Box::new(transcoder));
+    }
+
+    /// Finds the transcoder the data in `source`, which is in the given format,
+    /// should be converted with.
+    pub fn find(
+        &self,
+        format: FileFormat,
+        source: &mut dyn Source,
+    ) -> Result<Option<&dyn Transcoder>> {
+        if self
+            .disabled_formats
+            .iter()
+            .any(|disabled| disabled == format.media_type())
+        {
+            return Ok(None);
+        }
+
+        for transcoder in &self.transcoders {
+            if !transcoder.formats().contains(&format) {
+                continue;
+            }
+
+            let accepted = transcoder.accepts(source)?;
+            _ = source.seek(SeekFrom::Start(0))?;
+            if accepted {
+                return Ok(Some(transcoder.as_ref()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Detects the format of the data in `source` and converts it with the matching
+    /// transcoder, returning the format of the output along with facts about it.
+    ///
+    /// Returns an [`UnsupportedFormat`] error if no transcoder accepts the data.
+    pub fn convert(
+        &self,
+        source: &mut dyn Source,
+        sink: &mut dyn Sink,
+    ) -> Result<(FileFormat, MediaInfo)> {
+        let format = FileFormat::from_reader(&mut *source)?;
+        _ = source.seek(SeekFrom::Start(0))?;
+
+        let transcoder = match self.find(format, source)? {
+            Some(transcoder) => transcoder,
+            None => bail!(UnsupportedFormat(format)),
+        };
+
+        let mut media = transcoder.convert(source, sink)?;
+        media.source_format = format.media_type().to_owned();
+
+        Ok((transcoder.output(), media))
+    }
+}
+
+/// Converts still and animated images to WebP with the `image` crate.
+#[derive(Debug)]
+pub struct ImageTranscoder {
+    profile: TranscodeProfile,
+}
+
+impl ImageTranscoder {
+    const NAME: &'static str = "image-webp";
+}
+
+impl Transcoder for ImageTranscoder {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn formats(&self) -> &[FileFormat] {
+        use FileFormat::*;
+        &[
+            JointPhotographicExpertsGroup,
+            PortableNetworkGraphics,
+            GraphicsInterchangeFormat,
+            Webp,
+        ]
+    }
+
+    fn output(&self) -> FileFormat {
+        FileFormat::Webp
+    }
+
+    fn convert(&self, mut source: &mut dyn Source, mut sink: &mut dyn Sink) -> Result<MediaInfo> {
+        convert::convert_to_webp(&mut source, &mut sink, &self.profile)
+    }
+}
+
+/// Converts videos to VP9 and Opus WebM with FFMPEG.
+#[derive(Debug)]
+pub struct VideoTranscoder {
+    profile: TranscodeProfile,
+}
+
+impl VideoTranscoder {
+    const NAME: &'static str = "ffmpeg-webm";
+}
+
+impl Transcoder for VideoTranscoder {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn formats(&self) -> &[FileFormat] {
+        use FileFormat::*;
+        &[Webm, MatroskaVideo, Mpeg4Part14Video, AppleQuicktime]
+    }
+
+    fn output(&self) -> FileFormat {
+        FileFormat::Webm
+    }
+
+    fn convert(&self, mut source: &mut dyn Source, mut sink: &mut dyn Sink) -> Result<MediaInfo> {
+        Ok(convert::convert_to_webm(&mut source, &mut sink, &self.profile)?.media)
+    }
+}
+
+/// Converts large animated GIFs to silent VP9 WebM videos with FFMPEG, which come out
+/// far smaller than the equivalent animated WebP. Smaller GIFs are left to whichever
+/// transcoder is listed after this one.
+///
+/// WebM has no notion of looping, so the result is marked as a `gifv`
+/// for clients to autoplay and loop it.
+#[derive(Debug)]
+pub struct GifvTranscoder {
+    policy: GifvPolicy,
+    profile: TranscodeProfile,
+}
+
+impl GifvTranscoder {
+    const NAME: &'static str = "ffmpeg-gifv";
+}
+
+impl Transcoder for GifvTranscoder {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn formats(&self) -> &[FileFormat] {
+        &[FileFormat::GraphicsInterchangeFormat]
+    }
+
+    fn output(&self) -> FileFormat {
+        FileFormat::Webm
+    }
+
+    fn accepts(&self, mut source: &mut dyn Source) -> Result<bool> {
+        let size = source.seek(SeekFrom::End(0))?;
+        _ = source.seek(SeekFrom::Start(0))?;
+        let frame_count = convert::count_frames(&mut source)?;
+
+        // A single frame is a still image however large it is.
+        Ok(frame_count > 1 && (frame_count >= self.policy.frame_count || size >= self.policy.size))
+    }
+
+    fn convert(&self, mut source: &mut dyn Source, mut sink: &mut dyn Sink) -> Result<MediaInfo> {
+        // GIFs have no audio, so there is nothing to measure or keep.
+        let mut media = convert::convert_to_webm(&mut source, &mut sink, &self.profile)?.media;
+        media.gifv = true;
+        Ok(media)
+    }
+}
+
+/// The error returned when asked to convert data which no transcoder accepts.
+#[derive(Debug)]
+pub struct UnsupportedFormat(pub FileFormat);
+
+impl Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported media format `{}`", self.0.media_type())
+    }
+}
+
+impl Error for UnsupportedFormat {}